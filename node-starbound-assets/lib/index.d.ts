@@ -20,10 +20,50 @@ declare module 'node-starbound-assets' {
     content: T;
   }
 
+  /**
+   * A player whose `content` is in the lossless text dialect: floats with
+   * no fractional part are `{"$float": n}`, large integers `{"$int": "n"}`,
+   * and so on. See `starbound_assets::bson::text`.
+   */
+  export interface LosslessPlayer {
+    contents: VersionedJSON;
+  }
+
+  /**
+   * A path changed differently on both sides. Values are in the text
+   * dialect; a missing side means the value is absent there.
+   */
+  export interface Conflict {
+    path: string;
+    base?: any;
+    ours?: any;
+    theirs?: any;
+  }
+
+  export interface MergeResult {
+    player: LosslessPlayer;
+    conflicts: Conflict[];
+  }
+
+  /**
+   * Merges the edits made from `base` to `ours` with a newer save `theirs`.
+   * Conflicting paths keep our value. Players are in the lossless dialect,
+   * as from `parsePlayerLossless`.
+   */
+  export function mergePlayers(
+    base: LosslessPlayer,
+    ours: LosslessPlayer,
+    theirs: LosslessPlayer
+  ): MergeResult;
+
   export function parsePlayer(path: string, cb: Callback<Player>): void;
   export function parsePlayerAsync(path: string): Promise<Player>;
   export function savePlayer(player: Player, cb: Callback<ArrayBuffer>): void;
   export function savePlayerAsync(player: Player): Promise<ArrayBuffer>;
+  export function parsePlayerLossless(path: string, cb: Callback<LosslessPlayer>): void;
+  export function parsePlayerLosslessAsync(path: string): Promise<LosslessPlayer>;
+  export function savePlayerLossless(player: LosslessPlayer, cb: Callback<ArrayBuffer>): void;
+  export function savePlayerLosslessAsync(player: LosslessPlayer): Promise<ArrayBuffer>;
   export function parseAssets(path: string, cb: Callback<PackedAssets>): void;
   export function parseAssetsAsync(path: string): Promise<PackedAssets>;
 }
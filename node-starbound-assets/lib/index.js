@@ -1,8 +1,11 @@
 var {
   parseAssets,
+  mergePlayers,
   parsePlayer,
+  parsePlayerLossless,
   PackedAssets,
   savePlayer,
+  savePlayerLossless,
 } = require('../native');
 const { promisify } = require('util');
 
@@ -11,12 +14,19 @@ PackedAssets.prototype.getFileAsync = promisify(PackedAssets.prototype.getFile);
 
 const parsePlayerAsync = promisify(parsePlayer);
 const savePlayerAsync = promisify(savePlayer);
+const parsePlayerLosslessAsync = promisify(parsePlayerLossless);
+const savePlayerLosslessAsync = promisify(savePlayerLossless);
 
 module.exports = {
+  mergePlayers,
   parseAssets,
   parseAssetsAsync,
   parsePlayer,
   parsePlayerAsync,
+  parsePlayerLossless,
+  parsePlayerLosslessAsync,
   savePlayer,
   savePlayerAsync,
+  savePlayerLossless,
+  savePlayerLosslessAsync,
 };
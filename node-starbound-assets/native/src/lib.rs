@@ -1,6 +1,9 @@
 use neon::prelude::*;
+use serde::{Deserialize, Serialize};
+use starbound_assets::bson::text;
 use starbound_assets::{
     parse_packed, parse_player, save_versioned_json, PackedAssets as OrigPackedAssets, Player,
+    VersionedJson,
 };
 use std::cell::RefCell;
 use std::sync::{Arc, Mutex};
@@ -52,7 +55,7 @@ impl Task for FileLoader {
         lock.as_ref()
             .unwrap()
             .file(&self.1)
-            .ok_or_else(|| "could not find the file specified".to_string())
+            .map_err(|e| e.to_string())
             .map(|bytes| bytes.to_vec())
     }
 
@@ -73,7 +76,48 @@ impl Task for FileLoader {
     }
 }
 
-struct PlayerLoader(String);
+/// A player as seen from JavaScript by the lossless functions, with its
+/// content in the text dialect so that floats, large integers and nulls
+/// survive the trip.
+#[derive(Serialize, Deserialize)]
+struct JsPlayer {
+    contents: JsVersionedJson,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsVersionedJson {
+    identifier: String,
+    version: u32,
+    content: serde_json::Value,
+}
+
+impl From<&Player> for JsPlayer {
+    fn from(player: &Player) -> Self {
+        JsPlayer {
+            contents: JsVersionedJson {
+                identifier: player.contents.identifier.clone(),
+                version: player.contents.version,
+                content: text::to_json(&player.contents.content),
+            },
+        }
+    }
+}
+
+impl JsPlayer {
+    fn into_player(self) -> Result<Player, String> {
+        Ok(Player {
+            contents: VersionedJson {
+                identifier: self.contents.identifier,
+                version: self.contents.version,
+                content: text::from_json(&self.contents.content).map_err(|e| e.to_string())?,
+            },
+            source: None,
+        })
+    }
+}
+
+/// Loads a player, in the text dialect if the flag is set.
+struct PlayerLoader(String, bool);
 
 impl Task for PlayerLoader {
     type Output = Player;
@@ -91,7 +135,11 @@ impl Task for PlayerLoader {
     ) -> JsResult<Self::JsEvent> {
         let mut cx = cx;
         let result = result.or_else(|e| cx.throw_error(e))?;
-        Ok(neon_serde::to_value(&mut cx, &result)?)
+        if self.1 {
+            Ok(neon_serde::to_value(&mut cx, &JsPlayer::from(&result))?)
+        } else {
+            Ok(neon_serde::to_value(&mut cx, &result)?)
+        }
     }
 }
 
@@ -175,13 +223,21 @@ declare_types! {
     }
 }
 
-fn js_parse_player(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+fn parse_player_with(mut cx: FunctionContext, lossless: bool) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let cb = cx.argument::<JsFunction>(1)?;
-    PlayerLoader(path).schedule(cb);
+    PlayerLoader(path, lossless).schedule(cb);
     Ok(cx.undefined())
 }
 
+fn js_parse_player(cx: FunctionContext) -> JsResult<JsUndefined> {
+    parse_player_with(cx, false)
+}
+
+fn js_parse_player_lossless(cx: FunctionContext) -> JsResult<JsUndefined> {
+    parse_player_with(cx, true)
+}
+
 fn js_parse_assets(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let cb = cx.argument::<JsFunction>(1)?;
@@ -197,9 +253,36 @@ fn js_save_player(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+fn js_save_player_lossless(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let parg = cx.argument(0)?;
+    let player: JsPlayer = neon_serde::from_value(&mut cx, parg)?;
+    let player = player.into_player().or_else(|e| cx.throw_error(e))?;
+    let cb = cx.argument::<JsFunction>(1)?;
+    PlayerSaver(player.into()).schedule(cb);
+    Ok(cx.undefined())
+}
+
+fn js_merge_players(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let mut players = Vec::new();
+    for i in 0..3 {
+        let arg = cx.argument(i)?;
+        let player: JsPlayer = neon_serde::from_value(&mut cx, arg)?;
+        players.push(player.into_player().or_else(|e| cx.throw_error(e))?);
+    }
+    let (merged, conflicts) = players[1].merge(&players[0], &players[2]);
+    let result = serde_json::json!({
+        "player": JsPlayer::from(&merged),
+        "conflicts": conflicts.iter().map(|c| c.to_json()).collect::<Vec<_>>(),
+    });
+    Ok(neon_serde::to_value(&mut cx, &result)?)
+}
+
 register_module!(mut m, {
     m.export_function("parsePlayer", js_parse_player)?;
+    m.export_function("parsePlayerLossless", js_parse_player_lossless)?;
     m.export_function("savePlayer", js_save_player)?;
+    m.export_function("savePlayerLossless", js_save_player_lossless)?;
+    m.export_function("mergePlayers", js_merge_players)?;
     m.export_function("parseAssets", js_parse_assets)?;
     m.export_class::<JsPackedAssets>("PackedAssets")?;
     Ok(())
@@ -0,0 +1,348 @@
+use super::{Map, Value};
+use std::convert::TryFrom;
+use std::ops;
+
+static NULL: Value = Value::Empty;
+
+/// Something a `Value` can be indexed by: a `usize` for arrays or a string
+/// for objects.
+pub trait ValueIndex {
+    fn index_into<'v>(&self, v: &'v Value) -> Option<&'v Value>;
+    fn index_into_mut<'v>(&self, v: &'v mut Value) -> Option<&'v mut Value>;
+    /// Like `index_into_mut`, but adds the key to an object, turning a null
+    /// into one first. Panics where that isn't possible.
+    fn index_or_insert<'v>(&self, v: &'v mut Value) -> &'v mut Value;
+}
+
+impl ValueIndex for usize {
+    fn index_into<'v>(&self, v: &'v Value) -> Option<&'v Value> {
+        v.as_array()?.get(*self)
+    }
+
+    fn index_into_mut<'v>(&self, v: &'v mut Value) -> Option<&'v mut Value> {
+        v.as_array_mut()?.get_mut(*self)
+    }
+
+    fn index_or_insert<'v>(&self, v: &'v mut Value) -> &'v mut Value {
+        match v {
+            Value::Array(a) => {
+                let len = a.len();
+                a.get_mut(*self)
+                    .unwrap_or_else(|| panic!("index {} out of bounds for array of {}", self, len))
+            }
+            v => panic!("cannot index {} with a number", v.type_name()),
+        }
+    }
+}
+
+impl ValueIndex for str {
+    fn index_into<'v>(&self, v: &'v Value) -> Option<&'v Value> {
+        v.as_object()?.get(self)
+    }
+
+    fn index_into_mut<'v>(&self, v: &'v mut Value) -> Option<&'v mut Value> {
+        v.as_object_mut()?.get_mut(self)
+    }
+
+    fn index_or_insert<'v>(&self, v: &'v mut Value) -> &'v mut Value {
+        if v.is_null() {
+            *v = Value::Object(Map::new());
+        }
+        match v {
+            Value::Object(o) => o.entry(self.to_string()).or_insert(Value::Empty),
+            v => panic!("cannot index {} with a string", v.type_name()),
+        }
+    }
+}
+
+impl ValueIndex for String {
+    fn index_into<'v>(&self, v: &'v Value) -> Option<&'v Value> {
+        self.as_str().index_into(v)
+    }
+
+    fn index_into_mut<'v>(&self, v: &'v mut Value) -> Option<&'v mut Value> {
+        self.as_str().index_into_mut(v)
+    }
+
+    fn index_or_insert<'v>(&self, v: &'v mut Value) -> &'v mut Value {
+        self.as_str().index_or_insert(v)
+    }
+}
+
+impl<T: ValueIndex + ?Sized> ValueIndex for &T {
+    fn index_into<'v>(&self, v: &'v Value) -> Option<&'v Value> {
+        (**self).index_into(v)
+    }
+
+    fn index_into_mut<'v>(&self, v: &'v mut Value) -> Option<&'v mut Value> {
+        (**self).index_into_mut(v)
+    }
+
+    fn index_or_insert<'v>(&self, v: &'v mut Value) -> &'v mut Value {
+        (**self).index_or_insert(v)
+    }
+}
+
+impl Value {
+    pub fn get<I: ValueIndex>(&self, index: I) -> Option<&Value> {
+        index.index_into(self)
+    }
+
+    pub fn get_mut<I: ValueIndex>(&mut self, index: I) -> Option<&mut Value> {
+        index.index_into_mut(self)
+    }
+
+    /// Replaces the value with `Empty`, returning what was there.
+    pub fn take(&mut self) -> Value {
+        std::mem::replace(self, Value::Empty)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Empty | Value::Unset => "null",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "bool",
+            Value::Integer(_) => "int",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Empty | Value::Unset)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        self.as_i64().and_then(|i| u64::try_from(i).ok())
+    }
+
+    /// Floats, and integers converted to floats.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float(f) => Some(*f),
+            Value::Integer(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_array_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Map> {
+        match self {
+            Value::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    pub fn as_object_mut(&mut self) -> Option<&mut Map> {
+        match self {
+            Value::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+}
+
+/// Missing keys and indices, or indexing into something that isn't an
+/// object or array, give `Empty`.
+impl<I: ValueIndex> ops::Index<I> for Value {
+    type Output = Value;
+
+    fn index(&self, index: I) -> &Value {
+        index.index_into(self).unwrap_or(&NULL)
+    }
+}
+
+/// Indexing an object by a missing key adds it, as does indexing a null,
+/// which becomes an object. Indices past the end of an array panic.
+impl<I: ValueIndex> ops::IndexMut<I> for Value {
+    fn index_mut(&mut self, index: I) -> &mut Value {
+        index.index_or_insert(self)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(a: Vec<Value>) -> Self {
+        Value::Array(a)
+    }
+}
+
+impl From<Map> for Value {
+    fn from(o: Map) -> Self {
+        Value::Object(o)
+    }
+}
+
+/// Plain JSON, not the text dialect: numbers keep the type serde_json parsed
+/// them as, and integers too large for an `i64` become floats.
+impl From<serde_json::Value> for Value {
+    fn from(v: serde_json::Value) -> Self {
+        use serde_json::Value as Json;
+        match v {
+            Json::Null => Value::Empty,
+            Json::Bool(b) => Value::Boolean(b),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => Value::Integer(i),
+                None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Json::String(s) => Value::String(s),
+            Json::Array(a) => Value::Array(a.into_iter().map(Value::from).collect()),
+            Json::Object(o) => Value::Object(o.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+/// A float JSON can't hold: NaN or an infinity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NonFiniteFloat(pub f64);
+
+impl std::fmt::Display for NonFiniteFloat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} cannot be represented in JSON", self.0)
+    }
+}
+
+impl std::error::Error for NonFiniteFloat {}
+
+impl TryFrom<Value> for serde_json::Value {
+    type Error = NonFiniteFloat;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        use serde_json::Value as Json;
+        Ok(match v {
+            Value::Empty | Value::Unset => Json::Null,
+            Value::Boolean(b) => Json::Bool(b),
+            Value::Integer(i) => Json::from(i),
+            Value::Float(f) => {
+                Json::Number(serde_json::Number::from_f64(f).ok_or(NonFiniteFloat(f))?)
+            }
+            Value::String(s) => Json::String(s),
+            Value::Array(a) => Json::Array(
+                a.into_iter()
+                    .map(Json::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(o) => Json::Object(
+                o.into_iter()
+                    .map(|(k, v)| Ok((k, Json::try_from(v)?)))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_access() {
+        let mut v = Value::from(json!({
+            "identity": {"name": "Nova", "color": [1, 2.5]},
+            "level": 3,
+        }));
+        assert_eq!(v["identity"]["name"].as_str(), Some("Nova"));
+        assert_eq!(v["identity"]["color"][1].as_f64(), Some(2.5));
+        assert_eq!(v["level"].as_f64(), Some(3.0));
+        assert_eq!(v["level"].as_u64(), Some(3));
+        assert!(v["missing"][4]["deeper"].is_null());
+        assert_eq!(
+            v.get("identity")
+                .and_then(|i| i.get("color"))
+                .map(|c| c.type_name()),
+            Some("array")
+        );
+        assert_eq!(v.get(0), None);
+
+        v["identity"]["name"] = "Vera".into();
+        v["new"]["nested"] = true.into();
+        v["identity"]["color"][0] = Value::from(7i64);
+        assert_eq!(v.pointer("/new/nested"), Some(&Value::Boolean(true)));
+        assert_eq!(v.pointer("/identity/color/0"), Some(&Value::Integer(7)));
+        assert_eq!(v["identity"]["name"].take(), Value::from("Vera"));
+        assert!(v["identity"]["name"].is_null());
+
+        v["nan"] = f64::NAN.into();
+        assert!(serde_json::Value::try_from(v.clone()).is_err());
+        v.as_object_mut().unwrap().remove("nan");
+        assert_eq!(
+            serde_json::Value::try_from(v).unwrap(),
+            json!({
+                "identity": {"name": null, "color": [7, 2.5]},
+                "level": 3,
+                "new": {"nested": true},
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_index_out_of_bounds() {
+        let mut v = Value::from(json!([1]));
+        v[1] = Value::Empty;
+    }
+}
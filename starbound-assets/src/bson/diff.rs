@@ -0,0 +1,327 @@
+use super::pointer::escape;
+use super::{text, Value};
+
+/// Past this many element comparisons arrays are compared index by index
+/// instead of looking for insertions, removals and moves.
+const MAX_ARRAY_WORK: usize = 1 << 20;
+
+/// One difference between two values, located by JSON pointer. Paths into
+/// arrays use the index in the new value, except for removals, which use
+/// the index in the old one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Added {
+        path: String,
+        value: Value,
+    },
+    Removed {
+        path: String,
+        value: Value,
+    },
+    /// A different value of the same type.
+    Changed {
+        path: String,
+        from: Value,
+        to: Value,
+    },
+    /// A value of a different type, e.g. an integer that became a float.
+    TypeChanged {
+        path: String,
+        from: Value,
+        to: Value,
+    },
+    /// An array element that is unchanged but now somewhere else.
+    Moved {
+        from: String,
+        to: String,
+    },
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Changed { path, .. }
+            | Change::TypeChanged { path, .. } => path,
+            Change::Moved { to, .. } => to,
+        }
+    }
+
+    /// The change as JSON, with values in the text dialect.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        match self {
+            Change::Added { path, value } => {
+                json!({"op": "added", "path": path, "value": text::to_json(value)})
+            }
+            Change::Removed { path, value } => {
+                json!({"op": "removed", "path": path, "value": text::to_json(value)})
+            }
+            Change::Changed { path, from, to } => json!({
+                "op": "changed",
+                "path": path,
+                "from": text::to_json(from),
+                "to": text::to_json(to),
+            }),
+            Change::TypeChanged { path, from, to } => json!({
+                "op": "type-changed",
+                "path": path,
+                "from": text::to_json(from),
+                "to": text::to_json(to),
+            }),
+            Change::Moved { from, to } => json!({"op": "moved", "from": from, "to": to}),
+        }
+    }
+}
+
+fn compact(v: &Value) -> String {
+    text::to_json(v).to_string()
+}
+
+/// One line per change, prefixed `+`, `-`, `~`, `!` or `>` for added,
+/// removed, changed, type-changed and moved.
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added { path, value } => write!(f, "+ {}: {}", path, compact(value)),
+            Change::Removed { path, value } => write!(f, "- {}: {}", path, compact(value)),
+            Change::Changed { path, from, to } => {
+                write!(f, "~ {}: {} -> {}", path, compact(from), compact(to))
+            }
+            Change::TypeChanged { path, from, to } => write!(
+                f,
+                "! {}: {} {} -> {} {}",
+                path,
+                from.type_name(),
+                compact(from),
+                to.type_name(),
+                compact(to)
+            ),
+            Change::Moved { from, to } => write!(f, "> {} -> {}", from, to),
+        }
+    }
+}
+
+fn child(path: &str, token: &str) -> String {
+    format!("{}/{}", path, escape(token))
+}
+
+/// Pairs of indices of a longest common subsequence of `a` and `b`.
+fn common(a: &[Value], b: &[Value]) -> Vec<(usize, usize)> {
+    // lengths[i][j] is the LCS length of a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if same(&a[i], &b[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut pairs = Vec::new();
+    while i < a.len() && j < b.len() {
+        if same(&a[i], &b[j]) {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+fn diff_arrays(path: &str, a: &[Value], b: &[Value], out: &mut Vec<Change>) {
+    if a.len().saturating_mul(b.len()) > MAX_ARRAY_WORK {
+        for (i, (a, b)) in a.iter().zip(b).enumerate() {
+            diff_values(&child(path, &i.to_string()), a, b, out);
+        }
+        for (i, v) in a.iter().enumerate().skip(b.len()) {
+            out.push(Change::Removed {
+                path: child(path, &i.to_string()),
+                value: v.clone(),
+            });
+        }
+        for (i, v) in b.iter().enumerate().skip(a.len()) {
+            out.push(Change::Added {
+                path: child(path, &i.to_string()),
+                value: v.clone(),
+            });
+        }
+        return;
+    }
+
+    let anchors = common(a, b);
+    let mut used_a = vec![false; a.len()];
+    let mut used_b = vec![false; b.len()];
+    for &(i, j) in &anchors {
+        used_a[i] = true;
+        used_b[j] = true;
+    }
+
+    // Equal elements left over on both sides were moved
+    let mut moves = Vec::new();
+    for i in 0..a.len() {
+        if used_a[i] {
+            continue;
+        }
+        if let Some(j) = (0..b.len()).find(|&j| !used_b[j] && same(&a[i], &b[j])) {
+            used_a[i] = true;
+            used_b[j] = true;
+            moves.push((i, j));
+        }
+    }
+
+    // What's left between two anchors is paired up in order and compared;
+    // the rest was removed or added
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in anchors.into_iter().chain(Some((a.len(), b.len()))) {
+        let old: Vec<_> = (i..next_i).filter(|&i| !used_a[i]).collect();
+        let new: Vec<_> = (j..next_j).filter(|&j| !used_b[j]).collect();
+        for (&i, &j) in old.iter().zip(&new) {
+            diff_values(&child(path, &j.to_string()), &a[i], &b[j], out);
+        }
+        for &i in old.iter().skip(new.len()) {
+            out.push(Change::Removed {
+                path: child(path, &i.to_string()),
+                value: a[i].clone(),
+            });
+        }
+        for &j in new.iter().skip(old.len()) {
+            out.push(Change::Added {
+                path: child(path, &j.to_string()),
+                value: b[j].clone(),
+            });
+        }
+        i = next_i + 1;
+        j = next_j + 1;
+    }
+
+    out.extend(moves.into_iter().map(|(i, j)| Change::Moved {
+        from: child(path, &i.to_string()),
+        to: child(path, &j.to_string()),
+    }));
+}
+
+/// Equality that takes identical float bits as equal, so a NaN is the same
+/// as itself.
+pub(super) fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => x.to_bits() == y.to_bits() || x == y,
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((ka, a), (kb, b))| ka == kb && same(a, b))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn diff_values(path: &str, a: &Value, b: &Value, out: &mut Vec<Change>) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in a {
+                match b.get(k) {
+                    Some(w) => diff_values(&child(path, k), v, w, out),
+                    None => out.push(Change::Removed {
+                        path: child(path, k),
+                        value: v.clone(),
+                    }),
+                }
+            }
+            for (k, w) in b.iter().filter(|(k, _)| !a.contains_key(*k)) {
+                out.push(Change::Added {
+                    path: child(path, k),
+                    value: w.clone(),
+                });
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => diff_arrays(path, a, b, out),
+        (a, b) if same(a, b) => {}
+        (a, b) => {
+            let (path, from, to) = (path.to_string(), a.clone(), b.clone());
+            out.push(if a.type_name() == b.type_name() {
+                Change::Changed { path, from, to }
+            } else {
+                Change::TypeChanged { path, from, to }
+            });
+        }
+    }
+}
+
+impl Value {
+    /// Everything that changed going from `self` to `other`.
+    pub fn diff(&self, other: &Value) -> Vec<Change> {
+        let mut out = Vec::new();
+        diff_values("", self, other, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(a: &str, b: &str) -> Vec<String> {
+        let a = text::from_str(a).unwrap();
+        let b = text::from_str(b).unwrap();
+        a.diff(&b).iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        assert!(diff(r#"{"a": [1, {"b": 2}]}"#, r#"{"a": [1, {"b": 2}]}"#).is_empty());
+        assert_eq!(
+            diff(
+                r#"{"name": "Nova", "hp": 100, "speed": 1.5, "old": null, "a/b": 1}"#,
+                r#"{"name": "Vera", "hp": {"$float": 100.0}, "speed": 1.5, "new": [], "a/b": 2}"#
+            ),
+            vec![
+                "~ /a~1b: 1 -> 2",
+                "! /hp: int 100 -> float {\"$float\":100.0}",
+                "~ /name: \"Nova\" -> \"Vera\"",
+                "- /old: null",
+                "+ /new: []",
+            ]
+        );
+        assert_eq!(
+            diff(
+                r#"[{"item": "a"}, {"item": "b", "count": 1}, "x", "y"]"#,
+                r#"["y", {"item": "a"}, {"item": "b", "count": 2}, "z", "x"]"#
+            ),
+            vec!["~ /2/count: 1 -> 2", "+ /3: \"z\"", "> /3 -> /0"]
+        );
+        assert_eq!(diff("[1, 2, 3]", "[1, 3]"), vec!["- /1: 2"]);
+        assert_eq!(diff("[1, 3]", "[1, 2, 3]"), vec!["+ /1: 2"]);
+        assert_eq!(diff("1", "\"1\""), vec!["! : int 1 -> string \"1\""]);
+
+        // Elements holding a NaN are still equal to themselves
+        let nan = r#"{"$float": "7ff8000000000000"}"#;
+        assert_eq!(
+            diff(&format!("[[{}], 2]", nan), &format!("[[{}], 3]", nan)),
+            vec!["~ /1: 2 -> 3"]
+        );
+        assert_eq!(
+            diff(&format!("[[{}], 1]", nan), &format!("[1, [{}]]", nan)),
+            vec!["> /0 -> /1"]
+        );
+
+        let change = &text::from_str("[1]")
+            .unwrap()
+            .diff(&text::from_str("[]").unwrap())[0];
+        assert_eq!(change.path(), "/0");
+        assert_eq!(
+            change.to_json(),
+            serde_json::json!({"op": "removed", "path": "/0", "value": 1})
+        );
+    }
+}
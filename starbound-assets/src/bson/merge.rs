@@ -0,0 +1,209 @@
+use super::diff::same;
+use super::pointer::escape;
+use super::{text, Map, Value};
+use std::collections::BTreeSet;
+
+/// A path both sides changed in different ways. `None` means the value is
+/// absent on that side.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub path: String,
+    pub base: Option<Value>,
+    pub ours: Option<Value>,
+    pub theirs: Option<Value>,
+}
+
+impl Conflict {
+    /// The conflict as JSON, with values in the text dialect and absent
+    /// values left out.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut o = serde_json::Map::new();
+        o.insert("path".to_string(), self.path.clone().into());
+        for (side, v) in &[
+            ("base", &self.base),
+            ("ours", &self.ours),
+            ("theirs", &self.theirs),
+        ] {
+            if let Some(v) = v {
+                o.insert(side.to_string(), text::to_json(v));
+            }
+        }
+        serde_json::Value::Object(o)
+    }
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |v: &Option<Value>| match v {
+            Some(v) => text::to_json(v).to_string(),
+            None => "(absent)".to_string(),
+        };
+        write!(
+            f,
+            "{}: base {}, ours {}, theirs {}",
+            self.path,
+            show(&self.base),
+            show(&self.ours),
+            show(&self.theirs)
+        )
+    }
+}
+
+/// The result of a three-way merge. Conflicting paths are left as in ours.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merge {
+    pub merged: Value,
+    pub conflicts: Vec<Conflict>,
+}
+
+fn child(path: &str, token: &str) -> String {
+    format!("{}/{}", path, escape(token))
+}
+
+fn same_opt(a: Option<&Value>, b: Option<&Value>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => same(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+fn merge_values(
+    path: &str,
+    base: Option<&Value>,
+    ours: Option<&Value>,
+    theirs: Option<&Value>,
+    conflicts: &mut Vec<Conflict>,
+) -> Option<Value> {
+    if same_opt(ours, theirs) || same_opt(theirs, base) {
+        return ours.cloned();
+    }
+    if same_opt(ours, base) {
+        return theirs.cloned();
+    }
+    match (base, ours, theirs) {
+        // Objects merge key by key, even if both sides added the object
+        (base, Some(Value::Object(o)), Some(Value::Object(t)))
+            if matches!(base, None | Some(Value::Object(_))) =>
+        {
+            let empty = Map::new();
+            let b = base.and_then(Value::as_object).unwrap_or(&empty);
+            let keys: BTreeSet<_> = o.keys().chain(t.keys()).chain(b.keys()).collect();
+            let mut merged = Map::new();
+            for k in keys {
+                let v = merge_values(&child(path, k), b.get(k), o.get(k), t.get(k), conflicts);
+                if let Some(v) = v {
+                    merged.insert(k.clone(), v);
+                }
+            }
+            Some(Value::Object(merged))
+        }
+        // Arrays merge element by element while none of them changed length
+        (Some(Value::Array(b)), Some(Value::Array(o)), Some(Value::Array(t)))
+            if b.len() == o.len() && b.len() == t.len() =>
+        {
+            let merged = (0..b.len())
+                .map(|i| {
+                    let path = child(path, &i.to_string());
+                    merge_values(&path, Some(&b[i]), Some(&o[i]), Some(&t[i]), conflicts)
+                        .unwrap_or_default()
+                })
+                .collect();
+            Some(Value::Array(merged))
+        }
+        _ => {
+            conflicts.push(Conflict {
+                path: path.to_string(),
+                base: base.cloned(),
+                ours: ours.cloned(),
+                theirs: theirs.cloned(),
+            });
+            ours.cloned()
+        }
+    }
+}
+
+impl Value {
+    /// Merges the changes made from `base` to `self` with those made from
+    /// `base` to `theirs`. A path changed on only one side takes that
+    /// side's value; a path changed the same way on both is fine too.
+    /// Anything else is a conflict, which keeps our value.
+    pub fn merge3(&self, base: &Value, theirs: &Value) -> Merge {
+        let mut conflicts = Vec::new();
+        let merged = merge_values("", Some(base), Some(self), Some(theirs), &mut conflicts)
+            .unwrap_or_default();
+        Merge { merged, conflicts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (Value, Vec<String>) {
+        let v = |s| text::from_str(s).unwrap();
+        let m = v(ours).merge3(&v(base), &v(theirs));
+        (
+            m.merged,
+            m.conflicts.iter().map(|c| c.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = r#"{"name": "Nova", "hp": 100, "bags": [1, 2, 3], "log": {"deaths": 0}}"#;
+        let (merged, conflicts) = merge(
+            base,
+            r#"{"name": "Vera", "hp": 100, "bags": [1, 5, 3], "log": {"deaths": 0}, "mine": 1}"#,
+            r#"{"name": "Nova", "hp": 80, "bags": [1, 2, 4], "log": {"deaths": 1}}"#,
+        );
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            merged,
+            text::from_str(
+                r#"{"name": "Vera", "hp": 80, "bags": [1, 5, 4], "log": {"deaths": 1}, "mine": 1}"#
+            )
+            .unwrap()
+        );
+
+        let (merged, conflicts) = merge(
+            base,
+            r#"{"name": "Vera", "hp": 100, "bags": [1, 2], "log": {"deaths": 0}}"#,
+            r#"{"name": "Lyra", "bags": [1, 2, 3, 4], "log": {"deaths": 0, "kills": 2}}"#,
+        );
+        assert_eq!(
+            conflicts,
+            vec![
+                "/bags: base [1,2,3], ours [1,2], theirs [1,2,3,4]",
+                "/name: base \"Nova\", ours \"Vera\", theirs \"Lyra\"",
+            ]
+        );
+        assert_eq!(
+            merged,
+            text::from_str(r#"{"name": "Vera", "bags": [1, 2], "log": {"deaths": 0, "kills": 2}}"#)
+                .unwrap()
+        );
+
+        // Removed on one side and changed on the other
+        let (_, conflicts) = merge(r#"{"a": 1}"#, "{}", r#"{"a": 2}"#);
+        assert_eq!(conflicts, vec!["/a: base 1, ours (absent), theirs 2"]);
+        let m = text::from_str("{}").unwrap().merge3(
+            &text::from_str(r#"{"a": 1}"#).unwrap(),
+            &text::from_str(r#"{"a": 2}"#).unwrap(),
+        );
+        assert_eq!(
+            m.conflicts[0].to_json(),
+            serde_json::json!({"path": "/a", "base": 1, "theirs": 2})
+        );
+
+        // An untouched NaN is no conflict
+        let nan = |b| {
+            let mut o = Map::new();
+            o.insert("x".to_string(), Value::Float(f64::NAN));
+            o.insert("b".to_string(), Value::Boolean(b));
+            Value::Object(o)
+        };
+        let m = nan(true).merge3(&nan(false), &nan(false));
+        assert!(m.conflicts.is_empty());
+        assert!(same(&m.merged, &nan(true)));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod access;
+pub mod diff;
+pub mod merge;
+pub mod pointer;
 pub mod serializer;
+pub mod text;
 
 use nom::{
     branch::alt,
@@ -29,6 +34,9 @@ pub type Map = BTreeMap<String, Value>;
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
 pub enum Value {
     Empty,
+    /// A null stored with the 0 tag rather than the usual 1. Kept apart from
+    /// `Empty` only so that such values are written back unchanged.
+    Unset,
     Float(f64),
     Boolean(bool),
     Integer(i64),
@@ -43,11 +51,42 @@ impl Default for Value {
     }
 }
 
+impl Value {
+    /// Turns integers back into floats wherever `template` has a float in the
+    /// same place. JSON tools often write `1.0` as `1`, which the game would
+    /// then read as an integer. Array elements past the end of `template`'s
+    /// array are matched against its last element.
+    pub fn restore_floats(&mut self, template: &Value) {
+        match (self, template) {
+            (v @ Value::Integer(_), Value::Float(_)) => {
+                if let Value::Integer(i) = *v {
+                    *v = Value::Float(i as f64);
+                }
+            }
+            (Value::Array(a), Value::Array(t)) => {
+                for (i, v) in a.iter_mut().enumerate() {
+                    if let Some(t) = t.get(i).or_else(|| t.last()) {
+                        v.restore_floats(t);
+                    }
+                }
+            }
+            (Value::Object(o), Value::Object(t)) => {
+                for (k, v) in o.iter_mut() {
+                    if let Some(t) = t.get(k) {
+                        v.restore_floats(t);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 pub fn parse_bson<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
     context(
         "bson",
         alt((
-            value(Value::Empty, tag("\x00")),
+            value(Value::Unset, tag("\x00")),
             value(Value::Empty, tag("\x01")),
             preceded(tag("\x02"), cut(parse_float)),
             preceded(tag("\x03"), cut(parse_boolean)),
@@ -123,6 +162,7 @@ impl Serialize for Value {
     {
         match self {
             Value::Empty => serializer.serialize_none(),
+            Value::Unset => serializer.serialize_unit_struct(serializer::UNSET),
             Value::Float(f) => serializer.serialize_f64(*f),
             Value::Boolean(b) => serializer.serialize_bool(*b),
             Value::Integer(i) => serializer.serialize_i64(*i),
@@ -0,0 +1,296 @@
+use super::{Map, Value};
+
+/// Why a JSON pointer (RFC 6901) could not be followed or applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PointerError {
+    /// Not empty and doesn't start with `/`.
+    Invalid(String),
+    /// Some part of the pointer doesn't exist.
+    NotFound(String),
+    /// The pointer goes into something that isn't an object or array.
+    NotAContainer(String),
+    /// An array index that isn't a number or is out of range.
+    BadIndex(String),
+}
+
+impl std::fmt::Display for PointerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PointerError::Invalid(p) => write!(f, "invalid pointer {:?}", p),
+            PointerError::NotFound(p) => write!(f, "nothing at {}", p),
+            PointerError::NotAContainer(p) => write!(f, "{} is not an object or array", p),
+            PointerError::BadIndex(p) => write!(f, "bad array index in {}", p),
+        }
+    }
+}
+
+impl std::error::Error for PointerError {}
+
+/// Splits a pointer into its unescaped reference tokens.
+pub(crate) fn tokens(pointer: &str) -> Result<Vec<String>, PointerError> {
+    if pointer.is_empty() {
+        return Ok(vec![]);
+    }
+    if !pointer.starts_with('/') {
+        return Err(PointerError::Invalid(pointer.to_string()));
+    }
+    Ok(pointer[1..]
+        .split('/')
+        .map(|t| t.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+/// Escapes `key` for use as one token of a pointer.
+pub fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// An array index as RFC 6901 allows it: digits without leading zeros.
+fn index(token: &str) -> Option<usize> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return None;
+    }
+    if !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn child<'a>(v: &'a Value, token: &str) -> Option<&'a Value> {
+    match v {
+        Value::Object(o) => o.get(token),
+        Value::Array(a) => a.get(index(token)?),
+        _ => None,
+    }
+}
+
+fn child_mut<'a>(v: &'a mut Value, token: &str) -> Option<&'a mut Value> {
+    match v {
+        Value::Object(o) => o.get_mut(token),
+        Value::Array(a) => a.get_mut(index(token)?),
+        _ => None,
+    }
+}
+
+fn set_child(
+    parent: &mut Value,
+    token: String,
+    value: Value,
+    pointer: &str,
+) -> Result<Option<Value>, PointerError> {
+    match parent {
+        Value::Object(o) => Ok(o.insert(token, value)),
+        Value::Array(a) if token == "-" || index(&token) == Some(a.len()) => {
+            a.push(value);
+            Ok(None)
+        }
+        Value::Array(a) => match index(&token).and_then(|i| a.get_mut(i)) {
+            Some(slot) => Ok(Some(std::mem::replace(slot, value))),
+            None => Err(PointerError::BadIndex(pointer.to_string())),
+        },
+        _ => unreachable!(),
+    }
+}
+
+impl Value {
+    /// The value `pointer` refers to, e.g. `/inventory/bags/mainBag/0`.
+    pub fn pointer(&self, pointer: &str) -> Option<&Value> {
+        tokens(pointer)
+            .ok()?
+            .iter()
+            .try_fold(self, |v, t| child(v, t))
+    }
+
+    pub fn pointer_mut(&mut self, pointer: &str) -> Option<&mut Value> {
+        tokens(pointer)
+            .ok()?
+            .iter()
+            .try_fold(self, |v, t| child_mut(v, t))
+    }
+
+    /// Finds the container holding what `pointer` refers to, along with the
+    /// last token.
+    fn parent_mut(&mut self, pointer: &str) -> Result<(&mut Value, String), PointerError> {
+        let mut tokens = tokens(pointer)?;
+        let last = tokens
+            .pop()
+            .ok_or_else(|| PointerError::Invalid(pointer.to_string()))?;
+        let at = |n: usize| -> String {
+            tokens[..n]
+                .iter()
+                .map(|t| format!("/{}", escape(t)))
+                .collect()
+        };
+        let mut v = self;
+        for (i, t) in tokens.iter().enumerate() {
+            v = match v {
+                Value::Object(_) | Value::Array(_) => {
+                    child_mut(v, t).ok_or_else(|| PointerError::NotFound(at(i + 1)))?
+                }
+                _ => return Err(PointerError::NotAContainer(at(i))),
+            };
+        }
+        match v {
+            Value::Object(_) | Value::Array(_) => Ok((v, last)),
+            _ => Err(PointerError::NotAContainer(at(tokens.len()))),
+        }
+    }
+
+    /// Sets what `pointer` refers to, returning what was there before. The
+    /// parent must already exist. In an array, `-` or the array's length
+    /// appends. An empty pointer replaces the whole value.
+    pub fn set_pointer(
+        &mut self,
+        pointer: &str,
+        value: Value,
+    ) -> Result<Option<Value>, PointerError> {
+        if pointer.is_empty() {
+            return Ok(Some(std::mem::replace(self, value)));
+        }
+        let (parent, last) = self.parent_mut(pointer)?;
+        set_child(parent, last, value, pointer)
+    }
+
+    /// Like `set_pointer`, but creates missing parents as objects, and
+    /// turns nulls along the way into objects.
+    pub fn insert_at_path(
+        &mut self,
+        pointer: &str,
+        value: Value,
+    ) -> Result<Option<Value>, PointerError> {
+        let mut tokens = tokens(pointer)?;
+        let last = match tokens.pop() {
+            Some(last) => last,
+            None => return Ok(Some(std::mem::replace(self, value))),
+        };
+        let mut v = self;
+        for t in tokens {
+            if v.is_null() {
+                *v = Value::Object(Map::new());
+            }
+            v = match v {
+                Value::Object(o) => o.entry(t).or_insert(Value::Empty),
+                Value::Array(a) => {
+                    let i = match index(&t) {
+                        _ if t == "-" => a.len(),
+                        Some(i) if i <= a.len() => i,
+                        _ => return Err(PointerError::BadIndex(pointer.to_string())),
+                    };
+                    if i == a.len() {
+                        a.push(Value::Empty);
+                    }
+                    &mut a[i]
+                }
+                _ => return Err(PointerError::NotAContainer(pointer.to_string())),
+            };
+        }
+        if v.is_null() {
+            *v = Value::Object(Map::new());
+        }
+        match v {
+            Value::Object(_) | Value::Array(_) => set_child(v, last, value, pointer),
+            _ => Err(PointerError::NotAContainer(pointer.to_string())),
+        }
+    }
+
+    /// Removes what `pointer` refers to. Array elements after it move down.
+    pub fn remove_pointer(&mut self, pointer: &str) -> Result<Value, PointerError> {
+        let not_found = || PointerError::NotFound(pointer.to_string());
+        match self.parent_mut(pointer)? {
+            (Value::Object(o), key) => o.remove(&key).ok_or_else(not_found),
+            (Value::Array(a), t) => match index(&t) {
+                Some(i) if i < a.len() => Ok(a.remove(i)),
+                _ => Err(PointerError::BadIndex(pointer.to_string())),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bson::text;
+
+    #[test]
+    fn test_pointer() {
+        let mut v = text::from_str(
+            r#"{"bags": {"main": [{"name": "a"}, null]}, "a/b": 1, "m~n": 2, "": 3}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            v.pointer("/bags/main/0/name"),
+            Some(&Value::String("a".to_string()))
+        );
+        assert_eq!(v.pointer("/a~1b"), Some(&Value::Integer(1)));
+        assert_eq!(v.pointer("/m~0n"), Some(&Value::Integer(2)));
+        assert_eq!(v.pointer("/"), Some(&Value::Integer(3)));
+        assert_eq!(v.pointer(""), Some(&v.clone()));
+        assert_eq!(v.pointer("/bags/main/01"), None);
+        assert_eq!(v.pointer("/bags/main/2"), None);
+        assert_eq!(v.pointer("bags"), None);
+
+        *v.pointer_mut("/bags/main/1").unwrap() = Value::Boolean(true);
+        assert_eq!(v.pointer("/bags/main/1"), Some(&Value::Boolean(true)));
+
+        assert_eq!(v.set_pointer("/bags/main/-", Value::Integer(7)), Ok(None));
+        assert_eq!(v.set_pointer("/bags/main/3", Value::Integer(8)), Ok(None));
+        assert_eq!(
+            v.set_pointer("/bags/main/0", Value::Integer(6)),
+            Ok(Some(text::from_str(r#"{"name": "a"}"#).unwrap()))
+        );
+        assert_eq!(
+            v.pointer("/bags/main"),
+            Some(&text::from_str("[6, true, 7, 8]").unwrap())
+        );
+        assert_eq!(
+            v.set_pointer("/bags/main/9", Value::Empty),
+            Err(PointerError::BadIndex("/bags/main/9".to_string()))
+        );
+        assert_eq!(
+            v.set_pointer("/bags/other/x", Value::Empty),
+            Err(PointerError::NotFound("/bags/other".to_string()))
+        );
+        assert_eq!(
+            v.set_pointer("/a~1b/x", Value::Empty),
+            Err(PointerError::NotAContainer("/a~1b".to_string()))
+        );
+
+        assert_eq!(
+            v.insert_at_path("/bags/main/-/x", Value::Integer(1)),
+            Ok(None)
+        );
+        assert_eq!(v.pointer("/bags/main/4/x"), Some(&Value::Integer(1)));
+        v.remove_pointer("/bags/main/4").unwrap();
+        assert_eq!(v.insert_at_path("/new/a/b", Value::Integer(1)), Ok(None));
+        assert_eq!(
+            v.remove_pointer("/new"),
+            Ok(text::from_str(r#"{"a": {"b": 1}}"#).unwrap())
+        );
+        assert_eq!(
+            v.insert_at_path("/a~1b/c", Value::Empty),
+            Err(PointerError::NotAContainer("/a~1b/c".to_string()))
+        );
+        v.set_pointer("/empty", Value::Array(vec![])).unwrap();
+        assert_eq!(
+            v.insert_at_path("/empty/x/y", Value::Empty),
+            Err(PointerError::BadIndex("/empty/x/y".to_string()))
+        );
+        assert_eq!(v.insert_at_path("/empty/-/y", Value::Empty), Ok(None));
+        assert_eq!(
+            v.remove_pointer("/empty"),
+            Ok(text::from_str(r#"[{"y": null}]"#).unwrap())
+        );
+
+        assert_eq!(v.remove_pointer("/bags/main/1"), Ok(Value::Boolean(true)));
+        assert_eq!(v.remove_pointer("/m~0n"), Ok(Value::Integer(2)));
+        assert_eq!(
+            v.remove_pointer("/m~0n"),
+            Err(PointerError::NotFound("/m~0n".to_string()))
+        );
+        assert_eq!(
+            v,
+            text::from_str(r#"{"bags": {"main": [6, 7, 8]}, "a/b": 1, "": 3}"#).unwrap()
+        );
+    }
+}
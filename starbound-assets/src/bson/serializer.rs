@@ -7,6 +7,10 @@ use {
     std::io::Write,
 };
 
+/// Unit struct name that `Value::Unset` serializes as, written with the 0
+/// tag. Other serializers see an ordinary unit struct, i.e. null.
+pub(crate) const UNSET: &str = "$bson::Unset";
+
 pub struct Error(pub String);
 
 impl std::fmt::Display for Error {
@@ -147,7 +151,10 @@ impl<'a, W: Write> ser::Serializer for &'a mut Serializer<W> {
         self.writer.write_all(&[b'\x01']).map_err(SerError::custom)
     }
 
-    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
+        if name == UNSET {
+            return self.writer.write_all(b"\x00").map_err(SerError::custom);
+        }
         self.serialize_unit()
     }
 
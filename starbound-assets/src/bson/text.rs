@@ -0,0 +1,233 @@
+//! A JSON dialect for bson values that reads back exactly as it was written,
+//! even after passing through JavaScript, which has a single number type.
+//!
+//! - `null`, booleans, strings, arrays and objects are themselves.
+//! - A number with no fractional part is an `Integer`, any other a `Float`.
+//!   Numbers too large for an integer are floats.
+//! - Floats with no fractional part are written `{"$float": 100.0}`. Negative
+//!   zero, infinities and NaNs are written `{"$float": "<16 hex digits>"}`,
+//!   the bits of the float.
+//! - Integers beyond ±(2^53 - 1) are written `{"$int": "<decimal>"}`.
+//! - `Unset`, the 0-tagged null, is written `{"$null": 0}`.
+//! - An object whose only key is one of `$float`, `$int`, `$null` or
+//!   `$object` is written `{"$object": {..}}`.
+//!
+//! Objects are written in key order, as `Map` keeps them, so bson → text →
+//! bson re-encodes identically to the parsed value. Keys the game wrote in
+//! another order come back sorted.
+
+use super::{Map, Value};
+use serde_json::{Number, Value as Json};
+use std::error::Error;
+
+const FLOAT: &str = "$float";
+const INT: &str = "$int";
+const NULL: &str = "$null";
+const OBJECT: &str = "$object";
+const ESCAPES: [&str; 4] = [FLOAT, INT, NULL, OBJECT];
+
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+fn escaped(key: &str, v: Json) -> Json {
+    let mut o = serde_json::Map::new();
+    o.insert(key.to_string(), v);
+    Json::Object(o)
+}
+
+fn only_entry(o: &serde_json::Map<String, Json>) -> Option<(&str, &Json)> {
+    match o.len() {
+        1 => o.iter().next().map(|(k, v)| (k.as_str(), v)),
+        _ => None,
+    }
+}
+
+pub fn to_json(v: &Value) -> Json {
+    match v {
+        Value::Empty => Json::Null,
+        Value::Unset => escaped(NULL, Json::from(0)),
+        Value::Boolean(b) => Json::Bool(*b),
+        Value::String(s) => Json::String(s.clone()),
+        Value::Integer(i) if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(i) => Json::from(*i),
+        Value::Integer(i) => escaped(INT, Json::String(i.to_string())),
+        Value::Float(f) => match Number::from_f64(*f) {
+            Some(n) if f.fract() != 0.0 => Json::Number(n),
+            Some(n) if *f != 0.0 || f.is_sign_positive() => escaped(FLOAT, Json::Number(n)),
+            _ => escaped(FLOAT, Json::String(format!("{:016x}", f.to_bits()))),
+        },
+        Value::Array(a) => Json::Array(a.iter().map(to_json).collect()),
+        Value::Object(o) => {
+            let o = o
+                .iter()
+                .map(|(k, v)| (k.clone(), to_json(v)))
+                .collect::<serde_json::Map<_, _>>();
+            match only_entry(&o) {
+                Some((k, _)) if ESCAPES.contains(&k) => escaped(OBJECT, Json::Object(o)),
+                _ => Json::Object(o),
+            }
+        }
+    }
+}
+
+fn number(n: &Number) -> Value {
+    if let Some(i) = n.as_i64() {
+        return Value::Integer(i);
+    }
+    let f = n.as_f64().unwrap_or(f64::NAN);
+    let limit = 2f64.powi(63);
+    if f.fract() == 0.0 && -limit <= f && f < limit {
+        Value::Integer(f as i64)
+    } else {
+        Value::Float(f)
+    }
+}
+
+fn object(o: &serde_json::Map<String, Json>) -> Result<Value, Box<dyn Error>> {
+    let mut map = Map::new();
+    for (k, v) in o {
+        map.insert(k.clone(), from_json(v)?);
+    }
+    Ok(Value::Object(map))
+}
+
+pub fn from_json(v: &Json) -> Result<Value, Box<dyn Error>> {
+    let o = match v {
+        Json::Null => return Ok(Value::Empty),
+        Json::Bool(b) => return Ok(Value::Boolean(*b)),
+        Json::String(s) => return Ok(Value::String(s.clone())),
+        Json::Number(n) => return Ok(number(n)),
+        Json::Array(a) => {
+            return Ok(Value::Array(
+                a.iter().map(from_json).collect::<Result<_, _>>()?,
+            ))
+        }
+        Json::Object(o) => o,
+    };
+    Ok(match only_entry(o) {
+        Some((FLOAT, Json::Number(n))) => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        Some((FLOAT, Json::String(bits))) => Value::Float(f64::from_bits(
+            u64::from_str_radix(bits, 16).map_err(|e| format!("invalid {} {}: {}", FLOAT, v, e))?,
+        )),
+        Some((INT, Json::String(i))) => Value::Integer(
+            i.parse()
+                .map_err(|e| format!("invalid {} {}: {}", INT, v, e))?,
+        ),
+        Some((NULL, Json::Number(n))) if n.as_f64() == Some(0.0) => Value::Unset,
+        Some((OBJECT, Json::Object(o))) => object(o)?,
+        Some((k, _)) if ESCAPES.contains(&k) => return Err(format!("invalid {}", v).into()),
+        _ => object(o)?,
+    })
+}
+
+/// Pretty-printed text for `v`.
+pub fn to_string(v: &Value) -> String {
+    serde_json::to_string_pretty(&to_json(v)).unwrap()
+}
+
+pub fn from_str(s: &str) -> Result<Value, Box<dyn Error>> {
+    from_json(&serde_json::from_str(s)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bson::{parse_bson, serializer::to_writer};
+    use nom::error::VerboseError;
+
+    fn bytes(v: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        to_writer(&mut out, v).unwrap();
+        out
+    }
+
+    /// What `v` looks like after `JSON.parse`: every number a double.
+    fn through_js(v: Json) -> Json {
+        match v {
+            Json::Number(n) => Json::Number(Number::from_f64(n.as_f64().unwrap()).unwrap()),
+            Json::Array(a) => Json::Array(a.into_iter().map(through_js).collect()),
+            Json::Object(o) => {
+                Json::Object(o.into_iter().map(|(k, v)| (k, through_js(v))).collect())
+            }
+            v => v,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut escapes = Map::new();
+        for key in &ESCAPES {
+            escapes.insert(
+                key.to_string(),
+                Value::Object(
+                    vec![(key.to_string(), Value::Integer(0))]
+                        .into_iter()
+                        .collect(),
+                ),
+            );
+        }
+        let floats = [
+            0.1,
+            100.0,
+            -0.0,
+            1e300,
+            5e-324,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::from_bits(0x7ff8_0000_0000_1234),
+        ];
+        let value = Value::Array(vec![
+            Value::Empty,
+            Value::Unset,
+            Value::Boolean(true),
+            Value::Integer(1),
+            Value::Integer(-MAX_SAFE_INTEGER),
+            Value::Integer(i64::MIN),
+            Value::Integer(i64::MAX),
+            Value::String("$float".to_string()),
+            Value::Array(floats.iter().map(|f| Value::Float(*f)).collect()),
+            Value::Object(escapes),
+        ]);
+        let encoded = bytes(&value);
+        let (_, value) = parse_bson::<VerboseError<&[u8]>>(&encoded).unwrap();
+        assert_eq!(bytes(&value), encoded);
+
+        let text = to_string(&value);
+        assert!(text.contains(r#""$float": 100.0"#));
+        assert!(text.contains(r#""$null": 0"#));
+        assert_eq!(bytes(&from_str(&text).unwrap()), bytes(&value));
+
+        let js = through_js(serde_json::from_str(&text).unwrap());
+        assert_eq!(bytes(&from_json(&js).unwrap()), bytes(&value));
+
+        // {"b": 1, "a": 2.0} in the order the game wrote it
+        let game = b"\x07\x02\x01b\x04\x02\x01a\x02\x40\x00\x00\x00\x00\x00\x00\x00";
+        let (_, value) = parse_bson::<VerboseError<&[u8]>>(game).unwrap();
+        let text = to_string(&value);
+        assert_eq!(bytes(&from_str(&text).unwrap()), bytes(&value));
+        assert_eq!(
+            bytes(&value),
+            b"\x07\x02\x01a\x02\x40\x00\x00\x00\x00\x00\x00\x00\x01b\x04\x02"
+        );
+    }
+
+    #[test]
+    fn test_plain_json() {
+        assert!(from_str(r#"{"e": {"$int": 3}}"#).is_err());
+        let v = from_str(r#"{"a": 1, "b": 1.5, "c": 2.0, "d": null}"#).unwrap();
+        let expected = vec![
+            ("a", Value::Integer(1)),
+            ("b", Value::Float(1.5)),
+            ("c", Value::Integer(2)),
+            ("d", Value::Empty),
+        ];
+        assert_eq!(
+            v,
+            Value::Object(
+                expected
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect()
+            )
+        );
+    }
+}
@@ -0,0 +1,490 @@
+use crate::packed::render_nom_error;
+use crate::vlq::read_vlqu64;
+use memmap::Mmap;
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::map,
+    error::{context, ParseError},
+    multi::{length_data, many_m_n},
+    number::complete::{be_i64, be_u32, be_u8},
+    sequence::{pair, tuple},
+    IResult,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+
+pub mod writer;
+
+pub const HEADER_SIZE: usize = 512;
+pub const INVALID_BLOCK: u32 = u32::MAX;
+
+const MAGIC: &[u8] = b"BTreeDB5";
+const ROOT_SELECTOR: usize = 32;
+const ROOT_INFO_START: usize = 33;
+const ROOT_INFO_SIZE: usize = 17;
+
+const FREE_MAGIC: &[u8] = b"FF";
+const INDEX_MAGIC: &[u8] = b"II";
+const LEAF_MAGIC: &[u8] = b"LL";
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootInfo {
+    pub free_index_block: u32,
+    pub device_size: i64,
+    pub root_block: u32,
+    pub root_is_leaf: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub block_size: u32,
+    pub identifier: String,
+    pub key_size: u32,
+    pub using_alt_root: bool,
+    pub roots: [RootInfo; 2],
+}
+
+impl Header {
+    /// The root selected by the header's root flag.
+    pub fn root(&self) -> &RootInfo {
+        &self.roots[self.using_alt_root as usize]
+    }
+
+    /// The root a writer fills in before flipping the flag.
+    pub fn alternate_root(&self) -> &RootInfo {
+        &self.roots[!self.using_alt_root as usize]
+    }
+
+    pub fn block_offset(&self, block: u32) -> usize {
+        HEADER_SIZE + block as usize * self.block_size as usize
+    }
+}
+
+fn parse_root_info<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], RootInfo, E> {
+    context(
+        "root info",
+        map(
+            tuple((be_u32, be_i64, be_u32, be_u8)),
+            |(free_index_block, device_size, root_block, leaf)| RootInfo {
+                free_index_block,
+                device_size,
+                root_block,
+                root_is_leaf: leaf != 0,
+            },
+        ),
+    )(i)
+}
+
+pub(crate) fn parse_header<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], Header, E> {
+    let (i, _) = context("magic", tag(MAGIC))(i)?;
+    let (i, block_size) = be_u32(i)?;
+    let (i, identifier) = context("identifier", take(16usize))(i)?;
+    let (i, key_size) = be_u32(i)?;
+    let (i, selector) = be_u8(i)?;
+    let (i, first) = parse_root_info(i)?;
+    let (i, second) = parse_root_info(i)?;
+    let identifier = String::from_utf8_lossy(identifier)
+        .trim_end_matches('\0')
+        .to_string();
+    Ok((
+        i,
+        Header {
+            block_size,
+            identifier,
+            key_size,
+            using_alt_root: selector != 0,
+            roots: [first, second],
+        },
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexNode {
+    pub level: u8,
+    pub begin: u32,
+    pub children: Vec<(Vec<u8>, u32)>,
+}
+
+impl IndexNode {
+    /// The child block that may contain `key`.
+    pub fn child_for(&self, key: &[u8]) -> u32 {
+        let i = self.children.partition_point(|(k, _)| k.as_slice() <= key);
+        match i {
+            0 => self.begin,
+            i => self.children[i - 1].1,
+        }
+    }
+
+    pub fn pointers(&self) -> impl Iterator<Item = u32> + '_ {
+        std::iter::once(self.begin).chain(self.children.iter().map(|(_, p)| *p))
+    }
+}
+
+pub(crate) fn parse_index_node<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+    key_size: usize,
+) -> IResult<&'a [u8], IndexNode, E> {
+    let (i, _) = context("index magic", tag(INDEX_MAGIC))(i)?;
+    let (i, level) = be_u8(i)?;
+    let (i, n) = be_u32(i)?;
+    let (i, begin) = be_u32(i)?;
+    let child = pair(map(take(key_size), |k: &[u8]| k.to_vec()), be_u32);
+    let (i, children) = context("index children", many_m_n(n as usize, n as usize, child))(i)?;
+    Ok((
+        i,
+        IndexNode {
+            level,
+            begin,
+            children,
+        },
+    ))
+}
+
+pub(crate) fn parse_leaf_entries<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+    key_size: usize,
+) -> IResult<&'a [u8], Vec<Entry>, E> {
+    let (i, n) = be_u32(i)?;
+    let data = length_data(map(read_vlqu64, |v| v as usize));
+    let entry = pair(
+        map(take(key_size), |k: &[u8]| k.to_vec()),
+        map(data, |d: &[u8]| d.to_vec()),
+    );
+    context("leaf entries", many_m_n(n as usize, n as usize, entry))(i)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FreeIndexBlock {
+    pub next: u32,
+    pub blocks: Vec<u32>,
+}
+
+pub(crate) fn parse_free_index<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], FreeIndexBlock, E> {
+    let (i, _) = context("free index magic", tag(FREE_MAGIC))(i)?;
+    let (i, next) = be_u32(i)?;
+    let (i, n) = be_u32(i)?;
+    let (i, blocks) = context("free blocks", many_m_n(n as usize, n as usize, be_u32))(i)?;
+    Ok((i, FreeIndexBlock { next, blocks }))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Index(IndexNode),
+    Leaf(Vec<Entry>),
+}
+
+/// Reads the node at `block`, returning it along with every block it
+/// occupies. `read` returns the raw contents of a single block.
+pub(crate) fn read_node<F>(
+    block: u32,
+    key_size: usize,
+    block_count: u32,
+    mut read: F,
+) -> Result<(Node, Vec<u32>), Box<dyn Error>>
+where
+    F: FnMut(u32) -> Result<Vec<u8>, Box<dyn Error>>,
+{
+    let b = read(block)?;
+    match &b[..2] {
+        m if m == INDEX_MAGIC => {
+            let (_, index) = render_nom_error(&b, parse_index_node(&b, key_size))
+                .map_err(|e| format!("index block {}: {}", block, e))?;
+            Ok((Node::Index(index), vec![block]))
+        }
+        m if m == LEAF_MAGIC => {
+            let mut data = Vec::new();
+            let mut blocks = Vec::new();
+            let mut next = block;
+            while next != INVALID_BLOCK {
+                if blocks.len() as u32 > block_count {
+                    return Err(format!("leaf chain starting at block {} loops", block).into());
+                }
+                let b = if next == block {
+                    b.clone()
+                } else {
+                    read(next)?
+                };
+                if &b[..2] != LEAF_MAGIC {
+                    return Err(format!("block {} is not a leaf block", next).into());
+                }
+                let (body, ptr) = b[2..].split_at(b.len() - 6);
+                data.extend_from_slice(body);
+                blocks.push(next);
+                next = u32::from_be_bytes([ptr[0], ptr[1], ptr[2], ptr[3]]);
+            }
+            let (_, entries) = render_nom_error(&data, parse_leaf_entries(&data, key_size))
+                .map_err(|e| format!("leaf block {}: {}", block, e))?;
+            Ok((Node::Leaf(entries), blocks))
+        }
+        m => Err(format!("block {} has unexpected type {:?}", block, m).into()),
+    }
+}
+
+/// Follows the free index chain from `head`, returning the index blocks
+/// themselves and the free blocks they list.
+pub(crate) fn read_free_list<F>(
+    head: u32,
+    block_count: u32,
+    mut read: F,
+) -> Result<(Vec<u32>, Vec<u32>), Box<dyn Error>>
+where
+    F: FnMut(u32) -> Result<Vec<u8>, Box<dyn Error>>,
+{
+    let mut index_blocks = Vec::new();
+    let mut free = Vec::new();
+    let mut next = head;
+    while next != INVALID_BLOCK {
+        if index_blocks.len() as u32 > block_count {
+            return Err("free block chain loops".into());
+        }
+        let b = read(next)?;
+        let (_, index) = render_nom_error(&b, parse_free_index(&b))
+            .map_err(|e| format!("free index block {}: {}", next, e))?;
+        index_blocks.push(next);
+        free.extend(index.blocks);
+        next = index.next;
+    }
+    Ok((index_blocks, free))
+}
+
+pub struct BTreeDb {
+    map: Mmap,
+    header: Header,
+}
+
+impl BTreeDb {
+    pub fn new(f: &File) -> Result<Self, Box<dyn Error>> {
+        let map = unsafe { Mmap::map(f)? };
+        let (_, header) = render_nom_error(&map, parse_header(&map))?;
+        if header.block_size < 16 || header.key_size == 0 {
+            return Err(format!(
+                "invalid block size {} or key size {}",
+                header.block_size, header.key_size
+            )
+            .into());
+        }
+        Ok(Self { map, header })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn identifier(&self) -> &str {
+        &self.header.identifier
+    }
+
+    pub fn key_size(&self) -> usize {
+        self.header.key_size as usize
+    }
+
+    pub fn block_count(&self) -> u32 {
+        (self.map.len().saturating_sub(HEADER_SIZE) / self.header.block_size as usize) as u32
+    }
+
+    pub fn block(&self, block: u32) -> Result<&[u8], Box<dyn Error>> {
+        let start = self.header.block_offset(block);
+        self.map
+            .get(start..start + self.header.block_size as usize)
+            .ok_or_else(|| format!("block {} is past the end of the file", block).into())
+    }
+
+    pub fn node(&self, block: u32) -> Result<Node, Box<dyn Error>> {
+        let (node, _) = read_node(block, self.key_size(), self.block_count(), |b| {
+            self.block(b).map(|b| b.to_vec())
+        })?;
+        Ok(node)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if key.len() != self.key_size() {
+            return Err(format!("key must be {} bytes", self.key_size()).into());
+        }
+        let mut block = self.header.root().root_block;
+        for _ in 0..=u8::MAX {
+            match self.node(block)? {
+                Node::Index(index) => block = index.child_for(key),
+                Node::Leaf(entries) => {
+                    return Ok(entries
+                        .into_iter()
+                        .find(|(k, _)| k.as_slice() == key)
+                        .map(|(_, v)| v))
+                }
+            }
+        }
+        Err("index is nested too deeply".into())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> Result<bool, Box<dyn Error>> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Every key and value in key order, reading one leaf at a time.
+    pub fn entries(&self) -> Entries<'_> {
+        Entries {
+            db: self,
+            pending: vec![self.header.root().root_block],
+            leaf: Vec::new().into_iter(),
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        self.entries().map(|e| e.map(|(k, _)| k)).collect()
+    }
+
+    pub fn len(&self) -> Result<usize, Box<dyn Error>> {
+        let mut n = 0;
+        for entry in self.entries() {
+            entry?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Every block on the free list, following the chain of free index
+    /// blocks from the active root.
+    pub fn free_blocks(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let (mut index_blocks, free) = read_free_list(
+            self.header.root().free_index_block,
+            self.block_count(),
+            |b| self.block(b).map(|b| b.to_vec()),
+        )?;
+        index_blocks.extend(free);
+        Ok(index_blocks)
+    }
+}
+
+pub struct Entries<'a> {
+    db: &'a BTreeDb,
+    pending: Vec<u32>,
+    leaf: std::vec::IntoIter<Entry>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.leaf.next() {
+                return Some(Ok(entry));
+            }
+            let block = self.pending.pop()?;
+            match self.db.node(block) {
+                Ok(Node::Index(index)) => {
+                    let mut children = index.pointers().collect::<Vec<_>>();
+                    children.reverse();
+                    self.pending.extend(children);
+                }
+                Ok(Node::Leaf(entries)) => self.leaf = entries.into_iter(),
+                Err(e) => {
+                    self.pending.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const BLOCK_SIZE: usize = 64;
+
+    fn block(magic: &[u8], body: &[u8], next: Option<u32>) -> Vec<u8> {
+        let mut b = magic.to_vec();
+        b.extend_from_slice(body);
+        b.resize(BLOCK_SIZE, 0);
+        if let Some(next) = next {
+            b[BLOCK_SIZE - 4..].copy_from_slice(&next.to_be_bytes());
+        }
+        b
+    }
+
+    fn leaf(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut body = (entries.len() as u32).to_be_bytes().to_vec();
+        for (k, v) in entries {
+            body.extend_from_slice(k);
+            body.push(v.len() as u8);
+            body.extend_from_slice(v);
+        }
+        body
+    }
+
+    /// An index at block 1 over two leaves, the second spanning two blocks.
+    fn database() -> File {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+        header.extend_from_slice(b"Test1\0\0\0\0\0\0\0\0\0\0\0");
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.push(1);
+        for root in &[(INVALID_BLOCK, 0u32), (4, 1)] {
+            header.extend_from_slice(&root.0.to_be_bytes());
+            header.extend_from_slice(&0i64.to_be_bytes());
+            header.extend_from_slice(&root.1.to_be_bytes());
+            header.push(0);
+        }
+        header.resize(HEADER_SIZE, 0);
+
+        let mut index = vec![0];
+        index.extend_from_slice(&1u32.to_be_bytes());
+        index.extend_from_slice(&0u32.to_be_bytes());
+        index.extend_from_slice(b"mm");
+        index.extend_from_slice(&2u32.to_be_bytes());
+
+        let big = vec![7u8; 70];
+        let second = leaf(&[(b"mm", b"middle"), (b"zz", &big)]);
+        let split = BLOCK_SIZE - 6;
+
+        let mut free = 5u32.to_be_bytes().to_vec();
+        free.extend_from_slice(&INVALID_BLOCK.to_be_bytes());
+        free.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut bytes = header;
+        bytes.extend(block(
+            LEAF_MAGIC,
+            &leaf(&[(b"aa", b"first")]),
+            Some(INVALID_BLOCK),
+        ));
+        bytes.extend(block(INDEX_MAGIC, &index, None));
+        bytes.extend(block(LEAF_MAGIC, &second[..split], Some(3)));
+        bytes.extend(block(LEAF_MAGIC, &second[split..], Some(INVALID_BLOCK)));
+        bytes.extend(block(FREE_MAGIC, &free[..4], None));
+        bytes.extend(block(FREE_MAGIC, &free[4..], None));
+
+        let path = std::env::temp_dir().join(format!("btreedb-test-{}.db", std::process::id()));
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let f = File::open(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        f
+    }
+
+    #[test]
+    fn test_read() {
+        let db = BTreeDb::new(&database()).unwrap();
+        assert_eq!(db.identifier(), "Test1");
+        assert_eq!(db.header().root().root_block, 1);
+        assert_eq!(db.header().alternate_root().root_block, 0);
+        assert_eq!(db.get(b"aa").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(db.get(b"mm").unwrap(), Some(b"middle".to_vec()));
+        assert_eq!(db.get(b"zz").unwrap(), Some(vec![7u8; 70]));
+        assert_eq!(db.get(b"qq").unwrap(), None);
+        assert_eq!(
+            db.keys().unwrap(),
+            vec![b"aa".to_vec(), b"mm".to_vec(), b"zz".to_vec()]
+        );
+        assert_eq!(db.free_blocks().unwrap(), vec![4, 5]);
+    }
+}
@@ -0,0 +1,678 @@
+use super::{
+    parse_header, read_free_list, read_node, Entry, Header, Node, RootInfo, FREE_MAGIC,
+    HEADER_SIZE, INDEX_MAGIC, INVALID_BLOCK, LEAF_MAGIC, MAGIC, ROOT_INFO_SIZE, ROOT_INFO_START,
+    ROOT_SELECTOR,
+};
+use crate::packed::render_nom_error;
+use crate::vlq::write_vlqu64;
+use byteorder::{BigEndian, WriteBytesExt};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+enum Child {
+    Stored(u32),
+    Loaded(Box<MemNode>),
+}
+
+enum Kind {
+    Leaf(Vec<Entry>),
+    /// `children[i]` holds the keys from `keys[i - 1]` up to `keys[i]`.
+    Index {
+        level: u8,
+        keys: Vec<Vec<u8>>,
+        children: Vec<Child>,
+    },
+}
+
+struct MemNode {
+    kind: Kind,
+    /// The blocks this node was read from, which become free once its
+    /// replacement is committed.
+    source: Vec<u32>,
+    dirty: bool,
+}
+
+impl Child {
+    fn new(kind: Kind) -> Self {
+        Child::Loaded(Box::new(MemNode {
+            kind,
+            source: Vec::new(),
+            dirty: true,
+        }))
+    }
+}
+
+/// The old value, and the separator and right half if the node split.
+type Inserted = (Option<Vec<u8>>, Option<(Vec<u8>, Child)>);
+
+/// Edits a BTreeDB5 file without overwriting any block the committed tree
+/// uses.
+///
+/// Changed nodes are written to free or appended blocks. `commit` then
+/// writes the new root into the inactive root slot and flips the root flag,
+/// syncing in between, so a crash leaves either the old or the new tree.
+pub struct BTreeDbWriter {
+    file: File,
+    header: Header,
+    root: Child,
+    /// Blocks no committed structure uses, free for this transaction.
+    free: Vec<u32>,
+    /// Blocks the committed state still uses, free after the next commit.
+    released: Vec<u32>,
+    next_block: u32,
+}
+
+impl BTreeDbWriter {
+    pub fn open(mut file: File) -> Result<Self, Box<dyn Error>> {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut buf)?;
+        let (_, header) = render_nom_error(&buf, parse_header(&buf))?;
+        check_sizes(header.block_size, header.key_size)?;
+        let mut writer = BTreeDbWriter {
+            file,
+            root: Child::Stored(INVALID_BLOCK),
+            header,
+            free: Vec::new(),
+            released: Vec::new(),
+            next_block: 0,
+        };
+        writer.reset()?;
+        Ok(writer)
+    }
+
+    /// Truncates `file` and writes an empty database to it.
+    pub fn create(
+        mut file: File,
+        identifier: &str,
+        key_size: u32,
+        block_size: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        if identifier.len() > 16 {
+            return Err("identifier must be at most 16 bytes".into());
+        }
+        check_sizes(block_size, key_size)?;
+
+        let root = RootInfo {
+            free_index_block: INVALID_BLOCK,
+            device_size: (HEADER_SIZE + block_size as usize) as i64,
+            root_block: 0,
+            root_is_leaf: true,
+        };
+        let header = Header {
+            block_size,
+            identifier: identifier.to_string(),
+            key_size,
+            using_alt_root: false,
+            roots: [root, root],
+        };
+
+        let mut buf = MAGIC.to_vec();
+        buf.write_u32::<BigEndian>(block_size)?;
+        buf.extend_from_slice(identifier.as_bytes());
+        buf.resize(ROOT_SELECTOR - 4, 0);
+        buf.write_u32::<BigEndian>(key_size)?;
+        buf.push(0);
+        write_root_info(&mut buf, &root)?;
+        write_root_info(&mut buf, &root)?;
+        buf.resize(HEADER_SIZE, 0);
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&buf)?;
+        let mut writer = BTreeDbWriter {
+            file,
+            root: Child::Stored(INVALID_BLOCK),
+            header,
+            free: Vec::new(),
+            released: Vec::new(),
+            next_block: 1,
+        };
+        let empty = writer.leaf_blocks(&[])?;
+        writer.write_block(0, &empty[0])?;
+        writer.file.sync_all()?;
+        writer.reset()?;
+        Ok(writer)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn key_size(&self) -> usize {
+        self.header.key_size as usize
+    }
+
+    fn block_size(&self) -> usize {
+        self.header.block_size as usize
+    }
+
+    /// Throws away the working tree and reloads the committed state.
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        let root = *self.header.root();
+        let committed = (root.device_size.max(0) as usize).saturating_sub(HEADER_SIZE);
+        self.next_block = (committed / self.block_size()) as u32;
+        if self.next_block == 0 {
+            let len = self.file.metadata()?.len() as usize;
+            self.next_block = (len.saturating_sub(HEADER_SIZE) / self.block_size()) as u32;
+        }
+
+        let next_block = self.next_block;
+        let (index_blocks, mut free) =
+            read_free_list(root.free_index_block, next_block, |b| self.read_block(b))?;
+        free.sort_unstable_by(|a, b| b.cmp(a));
+        self.root = Child::Stored(root.root_block);
+        self.free = free;
+        // The committed free list still lives in these, so they can only be
+        // reused once a new list replaces it.
+        self.released = index_blocks;
+        Ok(())
+    }
+
+    /// Discards every change made since the last commit.
+    pub fn rollback(&mut self) -> Result<(), Box<dyn Error>> {
+        self.reset()
+    }
+
+    fn read_block(&mut self, block: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut buf = vec![0u8; self.block_size()];
+        let offset = self.header.block_offset(block) as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file
+            .read_exact(&mut buf)
+            .map_err(|e| format!("block {}: {}", block, e))?;
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        let offset = self.header.block_offset(block) as u64;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> u32 {
+        self.free.pop().unwrap_or_else(|| {
+            self.next_block += 1;
+            self.next_block - 1
+        })
+    }
+
+    fn load<'c>(&mut self, child: &'c mut Child) -> Result<&'c mut MemNode, Box<dyn Error>> {
+        if let Child::Stored(block) = *child {
+            let (key_size, next_block) = (self.key_size(), self.next_block);
+            let (node, source) = read_node(block, key_size, next_block, |b| self.read_block(b))?;
+            let kind = match node {
+                Node::Leaf(entries) => Kind::Leaf(entries),
+                Node::Index(index) => {
+                    let (keys, pointers): (Vec<_>, Vec<_>) = index.children.into_iter().unzip();
+                    Kind::Index {
+                        level: index.level,
+                        keys,
+                        children: std::iter::once(index.begin)
+                            .chain(pointers)
+                            .map(Child::Stored)
+                            .collect(),
+                    }
+                }
+            };
+            *child = Child::Loaded(Box::new(MemNode {
+                kind,
+                source,
+                dirty: false,
+            }));
+        }
+        match child {
+            Child::Loaded(node) => Ok(node),
+            Child::Stored(_) => unreachable!(),
+        }
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        if key.len() != self.key_size() {
+            return Err(format!("key must be {} bytes", self.key_size()).into());
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the root, which has to be moved out of `self` while the
+    /// tree is walked.
+    fn with_root<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self, &mut Child) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut root = std::mem::replace(&mut self.root, Child::Stored(INVALID_BLOCK));
+        let result = f(self, &mut root);
+        self.root = root;
+        result
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.check_key(key)?;
+        self.with_root(|w, root| w.get_in(root, key))
+    }
+
+    fn get_in(&mut self, child: &mut Child, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match &mut self.load(child)?.kind {
+            Kind::Leaf(entries) => Ok(entries
+                .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                .ok()
+                .map(|i| entries[i].1.clone())),
+            Kind::Index { keys, children, .. } => {
+                let pos = keys.partition_point(|k| k.as_slice() <= key);
+                self.get_in(&mut children[pos], key)
+            }
+        }
+    }
+
+    /// Inserts or replaces the value for `key`, returning the old value.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.check_key(key)?;
+        self.with_root(|w, root| {
+            let (old, split) = w.insert_in(root, key, value)?;
+            if let Some((separator, right)) = split {
+                let level = match &w.load(root)?.kind {
+                    Kind::Leaf(_) => 0,
+                    Kind::Index { level, .. } => level + 1,
+                };
+                let left = std::mem::replace(root, Child::Stored(INVALID_BLOCK));
+                *root = Child::new(Kind::Index {
+                    level,
+                    keys: vec![separator],
+                    children: vec![left, right],
+                });
+            }
+            Ok(old)
+        })
+    }
+
+    fn insert_in(
+        &mut self,
+        child: &mut Child,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Inserted, Box<dyn Error>> {
+        let leaf_capacity = self.block_size() - 6;
+        let max_keys = max_index_keys(self.header.block_size, self.header.key_size);
+        let node = self.load(child)?;
+        node.dirty = true;
+
+        match &mut node.kind {
+            Kind::Leaf(entries) => {
+                let old = match entries.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                    Ok(i) => Some(std::mem::replace(&mut entries[i].1, value.to_vec())),
+                    Err(i) => {
+                        entries.insert(i, (key.to_vec(), value.to_vec()));
+                        None
+                    }
+                };
+                // Leaves may chain across blocks, but only large values
+                // should make them do so.
+                if entries.len() < 2 || leaf_size(entries) <= leaf_capacity {
+                    return Ok((old, None));
+                }
+                let right = entries.split_off(entries.len() / 2);
+                let separator = right[0].0.clone();
+                Ok((old, Some((separator, Child::new(Kind::Leaf(right))))))
+            }
+            Kind::Index {
+                level,
+                keys,
+                children,
+            } => {
+                let pos = keys.partition_point(|k| k.as_slice() <= key);
+                let (old, split) = self.insert_in(&mut children[pos], key, value)?;
+                if let Some((separator, right)) = split {
+                    keys.insert(pos, separator);
+                    children.insert(pos + 1, right);
+                }
+                if keys.len() <= max_keys {
+                    return Ok((old, None));
+                }
+                let mid = keys.len() / 2;
+                let right_keys = keys.split_off(mid + 1);
+                let separator = keys.pop().unwrap();
+                let right_children = children.split_off(mid + 1);
+                let right = Child::new(Kind::Index {
+                    level: *level,
+                    keys: right_keys,
+                    children: right_children,
+                });
+                Ok((old, Some((separator, right))))
+            }
+        }
+    }
+
+    /// Removes `key`, returning its value.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.check_key(key)?;
+        self.with_root(|w, root| {
+            let removed = w.remove_in(root, key)?;
+            // Drop index levels left with a single child.
+            while let Child::Loaded(node) = root {
+                match &mut node.kind {
+                    Kind::Index { children, .. } if children.len() == 1 => {
+                        let only = children.pop().unwrap();
+                        w.released.append(&mut node.source);
+                        *root = only;
+                    }
+                    _ => break,
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    fn remove_in(
+        &mut self,
+        child: &mut Child,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let node = self.load(child)?;
+        let removed = match &mut node.kind {
+            Kind::Leaf(entries) => entries
+                .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+                .ok()
+                .map(|i| entries.remove(i).1),
+            Kind::Index { keys, children, .. } => {
+                let pos = keys.partition_point(|k| k.as_slice() <= key);
+                let removed = self.remove_in(&mut children[pos], key)?;
+                if removed.is_some() && children.len() > 1 && is_empty(&children[pos]) {
+                    release(&mut self.released, children.remove(pos));
+                    // The begin child has no key, so dropping it promotes
+                    // the next child to begin.
+                    keys.remove(pos.saturating_sub(1));
+                }
+                removed
+            }
+        };
+        node.dirty |= removed.is_some();
+        Ok(removed)
+    }
+
+    /// Writes every pending change, then switches the file over to the new
+    /// tree.
+    pub fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        let (root_block, root_is_leaf) = self.with_root(|w, root| {
+            let is_leaf = match root {
+                Child::Stored(_) => w.header.root().root_is_leaf,
+                Child::Loaded(node) => matches!(node.kind, Kind::Leaf(_)),
+            };
+            Ok((w.flush(root)?, is_leaf))
+        })?;
+
+        // The new free list can't reuse released blocks for itself, since
+        // the committed state still references them.
+        let per_block = (self.block_size() - 10) / 4;
+        let mut index_blocks = Vec::new();
+        while index_blocks.len() * per_block < self.free.len() + self.released.len() {
+            index_blocks.push(self.allocate());
+        }
+        let mut listed = std::mem::take(&mut self.free);
+        listed.append(&mut self.released);
+        let mut chunks = listed.chunks(per_block);
+        for (i, &block) in index_blocks.iter().enumerate() {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let mut buf = FREE_MAGIC.to_vec();
+            buf.write_u32::<BigEndian>(index_blocks.get(i + 1).copied().unwrap_or(INVALID_BLOCK))?;
+            buf.write_u32::<BigEndian>(chunk.len() as u32)?;
+            for b in chunk {
+                buf.write_u32::<BigEndian>(*b)?;
+            }
+            buf.resize(self.block_size(), 0);
+            self.write_block(block, &buf)?;
+        }
+
+        let device_size = self.header.block_offset(self.next_block) as i64;
+        self.file.set_len(device_size as u64)?;
+        self.file.sync_all()?;
+
+        let info = RootInfo {
+            free_index_block: index_blocks.first().copied().unwrap_or(INVALID_BLOCK),
+            device_size,
+            root_block,
+            root_is_leaf,
+        };
+        let slot = !self.header.using_alt_root as usize;
+        let mut buf = Vec::new();
+        write_root_info(&mut buf, &info)?;
+        let offset = ROOT_INFO_START + slot * ROOT_INFO_SIZE;
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_all()?;
+
+        self.file.seek(SeekFrom::Start(ROOT_SELECTOR as u64))?;
+        self.file.write_all(&[slot as u8])?;
+        self.file.sync_all()?;
+
+        self.header.roots[slot] = info;
+        self.header.using_alt_root = slot == 1;
+        self.reset()
+    }
+
+    /// Writes the dirty nodes under `child` bottom-up, returning the block
+    /// the subtree now starts at.
+    fn flush(&mut self, child: &mut Child) -> Result<u32, Box<dyn Error>> {
+        let node = match child {
+            Child::Stored(block) => return Ok(*block),
+            Child::Loaded(node) if !node.dirty => return Ok(node.source[0]),
+            Child::Loaded(node) => node,
+        };
+
+        let blocks = match &mut node.kind {
+            Kind::Leaf(entries) => {
+                let data = self.leaf_blocks(entries)?;
+                let blocks: Vec<u32> = data.iter().map(|_| self.allocate()).collect();
+                for (i, mut data) in data.into_iter().enumerate() {
+                    let next = blocks.get(i + 1).copied().unwrap_or(INVALID_BLOCK);
+                    let len = data.len();
+                    data[len - 4..].copy_from_slice(&next.to_be_bytes());
+                    self.write_block(blocks[i], &data)?;
+                }
+                blocks
+            }
+            Kind::Index {
+                level,
+                keys,
+                children,
+            } => {
+                let mut pointers = Vec::with_capacity(children.len());
+                for c in children.iter_mut() {
+                    pointers.push(self.flush(c)?);
+                }
+                let mut buf = INDEX_MAGIC.to_vec();
+                buf.push(*level);
+                buf.write_u32::<BigEndian>(keys.len() as u32)?;
+                buf.write_u32::<BigEndian>(pointers[0])?;
+                for (key, pointer) in keys.iter().zip(&pointers[1..]) {
+                    buf.extend_from_slice(key);
+                    buf.write_u32::<BigEndian>(*pointer)?;
+                }
+                buf.resize(self.block_size(), 0);
+                let block = self.allocate();
+                self.write_block(block, &buf)?;
+                vec![block]
+            }
+        };
+
+        self.released.append(&mut node.source);
+        node.source = blocks;
+        node.dirty = false;
+        Ok(node.source[0])
+    }
+
+    /// Serializes a leaf into as many blocks as it needs. The next-block
+    /// pointers are left for the caller to fill in.
+    fn leaf_blocks(&self, entries: &[Entry]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(entries.len() as u32)?;
+        for (key, value) in entries {
+            data.extend_from_slice(key);
+            write_vlqu64(&mut data, value.len() as u64)?;
+            data.extend_from_slice(value);
+        }
+        Ok(data
+            .chunks(self.block_size() - 6)
+            .map(|chunk| {
+                let mut block = LEAF_MAGIC.to_vec();
+                block.extend_from_slice(chunk);
+                block.resize(self.block_size() - 4, 0);
+                block.extend_from_slice(&INVALID_BLOCK.to_be_bytes());
+                block
+            })
+            .collect())
+    }
+}
+
+fn check_sizes(block_size: u32, key_size: u32) -> Result<(), Box<dyn Error>> {
+    if key_size == 0 || block_size < 16 || max_index_keys(block_size, key_size) < 2 {
+        return Err(format!("invalid block size {} or key size {}", block_size, key_size).into());
+    }
+    Ok(())
+}
+
+fn max_index_keys(block_size: u32, key_size: u32) -> usize {
+    // magic, level, key count and begin pointer come first
+    (block_size as usize).saturating_sub(11) / (key_size as usize + 4)
+}
+
+fn write_root_info(buf: &mut Vec<u8>, root: &RootInfo) -> std::io::Result<()> {
+    buf.write_u32::<BigEndian>(root.free_index_block)?;
+    buf.write_i64::<BigEndian>(root.device_size)?;
+    buf.write_u32::<BigEndian>(root.root_block)?;
+    buf.push(root.root_is_leaf as u8);
+    Ok(())
+}
+
+fn leaf_size(entries: &[Entry]) -> usize {
+    let vlq_len =
+        |n: usize| std::cmp::max(1, (64 - (n as u64).leading_zeros() as usize).div_ceil(7));
+    4 + entries
+        .iter()
+        .map(|(k, v)| k.len() + vlq_len(v.len()) + v.len())
+        .sum::<usize>()
+}
+
+fn is_empty(child: &Child) -> bool {
+    match child {
+        Child::Stored(_) => false,
+        Child::Loaded(node) => match &node.kind {
+            Kind::Leaf(entries) => entries.is_empty(),
+            Kind::Index { children, .. } => children.len() == 1 && is_empty(&children[0]),
+        },
+    }
+}
+
+fn release(released: &mut Vec<u32>, child: Child) {
+    if let Child::Loaded(node) = child {
+        let node = *node;
+        released.extend(node.source);
+        if let Kind::Index { children, .. } = node.kind {
+            for c in children {
+                release(released, c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BTreeDb;
+    use super::*;
+    use std::fs::OpenOptions;
+
+    fn open(name: &str) -> (std::path::PathBuf, File) {
+        let path =
+            std::env::temp_dir().join(format!("btreedb-writer-{}-{}", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
+
+    fn key(n: u32) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_write() {
+        let (path, file) = open("write");
+        let mut w = BTreeDbWriter::create(file, "Test", 4, 64).unwrap();
+        for n in 0..200 {
+            w.insert(&key(n), format!("value {}", n).as_bytes())
+                .unwrap();
+        }
+        assert_eq!(
+            w.insert(&key(7), b"seven").unwrap(),
+            Some(b"value 7".to_vec())
+        );
+        w.commit().unwrap();
+
+        let db = BTreeDb::new(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(db.identifier(), "Test");
+        assert_eq!(db.len().unwrap(), 200);
+        assert_eq!(db.get(&key(7)).unwrap(), Some(b"seven".to_vec()));
+        assert_eq!(db.get(&key(150)).unwrap(), Some(b"value 150".to_vec()));
+        assert!(matches!(
+            db.node(db.header().root().root_block).unwrap(),
+            Node::Index(_)
+        ));
+
+        for n in (0..200).filter(|n| n % 3 != 0) {
+            assert!(w.remove(&key(n)).unwrap().is_some());
+        }
+        assert_eq!(w.remove(&key(1)).unwrap(), None);
+        w.insert(&key(1000), &[0xAB; 300]).unwrap();
+        w.commit().unwrap();
+
+        let db = BTreeDb::new(&File::open(&path).unwrap()).unwrap();
+        let keys = db.keys().unwrap();
+        assert_eq!(keys.len(), 68);
+        assert!(keys.windows(2).all(|k| k[0] < k[1]));
+        assert_eq!(db.get(&key(2)).unwrap(), None);
+        assert_eq!(db.get(&key(1000)).unwrap(), Some(vec![0xAB; 300]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_transactions() {
+        let (path, file) = open("transactions");
+        let mut w = BTreeDbWriter::create(file, "Test", 4, 64).unwrap();
+        for n in 0..50 {
+            w.insert(&key(n), b"first").unwrap();
+        }
+        w.commit().unwrap();
+        assert!(w.header().using_alt_root);
+
+        w.insert(&key(0), b"second").unwrap();
+        w.remove(&key(1)).unwrap();
+        w.rollback().unwrap();
+        assert_eq!(w.get(&key(0)).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(w.get(&key(1)).unwrap(), Some(b"first".to_vec()));
+
+        // Rewriting the same keys should settle into reusing freed blocks
+        // rather than growing the file forever.
+        let mut sizes = Vec::new();
+        for round in 0..6 {
+            for n in 0..50 {
+                w.insert(&key(n), format!("round {}", round).as_bytes())
+                    .unwrap();
+            }
+            w.commit().unwrap();
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+        }
+        assert_eq!(sizes[4], sizes[5]);
+
+        let file = OpenOptions::new().read(true).write(true).open(&path);
+        let mut w = BTreeDbWriter::open(file.unwrap()).unwrap();
+        assert_eq!(w.get(&key(49)).unwrap(), Some(b"round 5".to_vec()));
+        let db = BTreeDb::new(&File::open(&path).unwrap()).unwrap();
+        assert!(!db.free_blocks().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
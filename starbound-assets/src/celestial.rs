@@ -0,0 +1,645 @@
+use crate::bson::{parse_bson, Value};
+use crate::btreedb::BTreeDb;
+use crate::packed::{load_versioned_json, render_nom_error, string, VersionedJson};
+use crate::vlq::read_vlqu64;
+use crate::world::decompress;
+use nom::{
+    combinator::map,
+    error::{context, ParseError},
+    multi::many_m_n,
+    number::complete::{be_i32, be_u64},
+    sequence::{pair, tuple},
+    IResult,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::str::FromStr;
+
+/// Width and height of a universe chunk, in systems.
+pub const CHUNK_SIZE: i32 = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoordinateError {
+    WrongPartCount(usize),
+    InvalidNumber(String),
+    Malformed,
+}
+
+impl std::fmt::Display for CoordinateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoordinateError::WrongPartCount(n) => {
+                write!(f, "expected 3 to 5 coordinate parts, got {}", n)
+            }
+            CoordinateError::InvalidNumber(s) => write!(f, "invalid coordinate part {}", s),
+            CoordinateError::Malformed => f.write_str("malformed celestial coordinate"),
+        }
+    }
+}
+
+impl Error for CoordinateError {}
+
+/// A system, planet or satellite. Planets and satellites are numbered from
+/// 1, with 0 meaning the system itself or the planet itself.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct CelestialCoordinate {
+    pub location: [i32; 3],
+    pub planet: i32,
+    pub satellite: i32,
+}
+
+impl CelestialCoordinate {
+    pub fn system(location: [i32; 3]) -> Self {
+        CelestialCoordinate {
+            location,
+            planet: 0,
+            satellite: 0,
+        }
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.planet == 0
+    }
+
+    pub fn is_planet(&self) -> bool {
+        self.planet > 0 && self.satellite == 0
+    }
+
+    pub fn is_satellite(&self) -> bool {
+        self.planet > 0 && self.satellite > 0
+    }
+
+    /// The system a planet is in, or the planet a satellite orbits.
+    pub fn parent(&self) -> Option<Self> {
+        match (self.planet, self.satellite) {
+            (0, _) => None,
+            (_, 0) => Some(Self::system(self.location)),
+            _ => Some(CelestialCoordinate {
+                satellite: 0,
+                ..*self
+            }),
+        }
+    }
+
+    /// The universe chunk holding this coordinate's system.
+    pub fn chunk(&self) -> (i32, i32) {
+        (
+            self.location[0].div_euclid(CHUNK_SIZE),
+            self.location[1].div_euclid(CHUNK_SIZE),
+        )
+    }
+
+    /// Reads the `{"location": [x, y, z], "planet", "satellite"}` form
+    /// used in stored JSON.
+    pub fn from_value(v: &Value) -> Result<Self, CoordinateError> {
+        let o = match v {
+            Value::Object(o) => o,
+            _ => return Err(CoordinateError::Malformed),
+        };
+        let int = |v: Option<&Value>| match v {
+            Some(Value::Integer(i)) => Ok(*i as i32),
+            Some(Value::Float(f)) => Ok(*f as i32),
+            None => Ok(0),
+            _ => Err(CoordinateError::Malformed),
+        };
+        let location = match o.get("location") {
+            Some(Value::Array(a)) if a.len() == 3 => {
+                [int(a.first())?, int(a.get(1))?, int(a.get(2))?]
+            }
+            _ => return Err(CoordinateError::Malformed),
+        };
+        Ok(CelestialCoordinate {
+            location,
+            planet: int(o.get("planet"))?,
+            satellite: int(o.get("satellite"))?,
+        })
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut o = crate::bson::Map::new();
+        o.insert(
+            "location".to_string(),
+            Value::Array(
+                self.location
+                    .iter()
+                    .map(|i| Value::Integer(*i as i64))
+                    .collect(),
+            ),
+        );
+        o.insert("planet".to_string(), Value::Integer(self.planet as i64));
+        o.insert(
+            "satellite".to_string(),
+            Value::Integer(self.satellite as i64),
+        );
+        Value::Object(o)
+    }
+}
+
+impl std::fmt::Display for CelestialCoordinate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [x, y, z] = self.location;
+        write!(f, "{}:{}:{}", x, y, z)?;
+        if self.planet > 0 {
+            write!(f, ":{}", self.planet)?;
+            if self.satellite > 0 {
+                write!(f, ":{}", self.satellite)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CelestialCoordinate {
+    type Err = CoordinateError;
+
+    /// Parses `x:y:z[:planet[:satellite]]`, optionally prefixed with
+    /// `CelestialWorld:` as in world ids.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("CelestialWorld:").unwrap_or(s);
+        let parts = s
+            .split(':')
+            .map(|p| {
+                p.trim()
+                    .parse::<i32>()
+                    .map_err(|_| CoordinateError::InvalidNumber(p.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if parts.len() < 3 || parts.len() > 5 {
+            return Err(CoordinateError::WrongPartCount(parts.len()));
+        }
+        Ok(CelestialCoordinate {
+            location: [parts[0], parts[1], parts[2]],
+            planet: parts.get(3).copied().unwrap_or(0),
+            satellite: parts.get(4).copied().unwrap_or(0),
+        })
+    }
+}
+
+fn field<'a>(v: &'a Value, key: &str) -> Option<&'a Value> {
+    match v {
+        Value::Object(o) => o.get(key),
+        _ => None,
+    }
+}
+
+fn number(v: &Value) -> Option<f64> {
+    match v {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn vec2(v: Option<&Value>) -> Option<(f64, f64)> {
+    match v? {
+        Value::Array(a) if a.len() == 2 => Some((number(&a[0])?, number(&a[1])?)),
+        _ => None,
+    }
+}
+
+fn text(v: Option<&Value>) -> String {
+    match v {
+        Some(Value::String(s)) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Orbit {
+    pub target: CelestialCoordinate,
+    pub direction: i64,
+    pub enter_time: f64,
+    pub enter_position: (f64, f64),
+}
+
+impl Orbit {
+    fn from_value(v: &Value) -> Option<Self> {
+        Some(Orbit {
+            target: CelestialCoordinate::from_value(field(v, "target")?).ok()?,
+            direction: field(v, "direction").and_then(number).unwrap_or(1.0) as i64,
+            enter_time: field(v, "enterTime").and_then(number).unwrap_or_default(),
+            enter_position: vec2(field(v, "enterPosition")).unwrap_or_default(),
+        })
+    }
+}
+
+/// Something flying around a system: a station, an anomaly, a ship.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SystemObject {
+    pub uuid: String,
+    pub name: String,
+    pub orbit: Option<Orbit>,
+    pub position: Option<(f64, f64)>,
+    pub parameters: Value,
+}
+
+impl SystemObject {
+    fn from_value(v: &Value) -> Self {
+        SystemObject {
+            uuid: text(field(v, "uuid")),
+            name: text(field(v, "name")),
+            orbit: field(v, "orbit").and_then(Orbit::from_value),
+            position: vec2(field(v, "position")),
+            parameters: field(v, "parameters").cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// A `universe/x_y_z.system` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SystemFile {
+    pub location: [i32; 3],
+    pub objects: Vec<SystemObject>,
+    /// Player ships the server recorded in the system.
+    pub ships: Vec<SystemObject>,
+    pub store: VersionedJson,
+}
+
+impl SystemFile {
+    pub fn new(f: &File) -> Result<Self, Box<dyn Error>> {
+        Self::from_versioned_json(load_versioned_json(f)?)
+    }
+
+    pub fn from_versioned_json(store: VersionedJson) -> Result<Self, Box<dyn Error>> {
+        let location = match field(&store.content, "location") {
+            Some(Value::Array(a)) if a.len() == 3 => {
+                let n = |v: &Value| number(v).map(|n| n as i32).ok_or("invalid location");
+                [n(&a[0])?, n(&a[1])?, n(&a[2])?]
+            }
+            _ => return Err("system has no location".into()),
+        };
+        let list = |key: &str| match field(&store.content, key) {
+            Some(Value::Array(a)) => a.iter().map(SystemObject::from_value).collect(),
+            Some(Value::Object(o)) => o.values().map(SystemObject::from_value).collect(),
+            _ => Vec::new(),
+        };
+        Ok(SystemFile {
+            location,
+            objects: list("objects"),
+            ships: list("ships"),
+            store,
+        })
+    }
+
+    pub fn coordinate(&self) -> CelestialCoordinate {
+        CelestialCoordinate::system(self.location)
+    }
+
+    /// Objects and ships orbiting `target`, which is usually a planet.
+    pub fn orbit_occupants(&self, target: &CelestialCoordinate) -> Vec<&SystemObject> {
+        self.objects
+            .iter()
+            .chain(&self.ships)
+            .filter(|o| o.orbit.as_ref().map(|orbit| &orbit.target) == Some(target))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CelestialParameters {
+    pub coordinate: CelestialCoordinate,
+    pub seed: u64,
+    pub name: String,
+    pub parameters: Value,
+    pub visitable_parameters: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CelestialPlanet {
+    pub parameters: CelestialParameters,
+    pub satellites: BTreeMap<i32, CelestialParameters>,
+}
+
+pub type Constellation = Vec<((i32, i32), (i32, i32))>;
+
+/// One record of `universe.chunks`: the generated systems in a square of
+/// the universe, keyed by system location.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CelestialChunk {
+    pub index: (i32, i32),
+    pub constellations: Vec<Constellation>,
+    pub systems: BTreeMap<[i32; 3], CelestialParameters>,
+    pub planets: BTreeMap<[i32; 3], BTreeMap<i32, CelestialPlanet>>,
+}
+
+impl CelestialChunk {
+    pub fn parameters(&self, c: &CelestialCoordinate) -> Option<&CelestialParameters> {
+        if c.is_system() {
+            return self.systems.get(&c.location);
+        }
+        let planet = self.planets.get(&c.location)?.get(&c.planet)?;
+        match c.satellite {
+            0 => Some(&planet.parameters),
+            s => planet.satellites.get(&s),
+        }
+    }
+}
+
+fn counted<'a, O, E, F>(f: F) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], Vec<O>, E>
+where
+    E: ParseError<&'a [u8]>,
+    F: Fn(&'a [u8]) -> IResult<&'a [u8], O, E>,
+{
+    move |i| {
+        let (i, n) = read_vlqu64(i)?;
+        many_m_n(n as usize, n as usize, &f)(i)
+    }
+}
+
+fn vec2i<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], (i32, i32), E> {
+    pair(be_i32, be_i32)(i)
+}
+
+fn vec3i<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], [i32; 3], E> {
+    map(tuple((be_i32, be_i32, be_i32)), |(x, y, z)| [x, y, z])(i)
+}
+
+fn parse_coordinate<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], CelestialCoordinate, E> {
+    map(
+        tuple((vec3i, be_i32, be_i32)),
+        |(location, planet, satellite)| CelestialCoordinate {
+            location,
+            planet,
+            satellite,
+        },
+    )(i)
+}
+
+fn parse_parameters<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], CelestialParameters, E> {
+    let (i, coordinate) = parse_coordinate(i)?;
+    let (i, seed) = be_u64(i)?;
+    let (i, name) = string(i)?;
+    let (i, parameters) = parse_bson(i)?;
+    let (i, visitable_parameters) = parse_bson(i)?;
+    Ok((
+        i,
+        CelestialParameters {
+            coordinate,
+            seed,
+            name,
+            parameters,
+            visitable_parameters,
+        },
+    ))
+}
+
+fn parse_planet<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], CelestialPlanet, E> {
+    let (i, parameters) = context("planet", parse_parameters)(i)?;
+    let (i, satellites) = context("satellites", counted(pair(be_i32, parse_parameters)))(i)?;
+    Ok((
+        i,
+        CelestialPlanet {
+            parameters,
+            satellites: satellites.into_iter().collect(),
+        },
+    ))
+}
+
+pub(crate) fn parse_chunk<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], CelestialChunk, E> {
+    let (i, index) = vec2i(i)?;
+    let (i, constellations) = context("constellations", counted(counted(pair(vec2i, vec2i))))(i)?;
+    let (i, systems) = context("systems", counted(pair(vec3i, parse_parameters)))(i)?;
+    let (i, planets) = context(
+        "planets",
+        counted(pair(vec3i, counted(pair(be_i32, parse_planet)))),
+    )(i)?;
+    Ok((
+        i,
+        CelestialChunk {
+            index,
+            constellations,
+            systems: systems.into_iter().collect(),
+            planets: planets
+                .into_iter()
+                .map(|(l, p)| (l, p.into_iter().collect()))
+                .collect(),
+        },
+    ))
+}
+
+pub fn chunk_key(x: i32, y: i32) -> [u8; 8] {
+    let mut key = [0; 8];
+    key[..4].copy_from_slice(&x.to_be_bytes());
+    key[4..].copy_from_slice(&y.to_be_bytes());
+    key
+}
+
+/// The server's `universe/universe.chunks` cache of generated systems.
+pub struct UniverseChunks {
+    db: BTreeDb,
+}
+
+impl UniverseChunks {
+    pub fn new(f: &File) -> Result<Self, Box<dyn Error>> {
+        let db = BTreeDb::new(f)?;
+        if !db.identifier().starts_with("Celestial") || db.key_size() != 8 {
+            return Err(format!("not a universe chunk database: {}", db.identifier()).into());
+        }
+        Ok(UniverseChunks { db })
+    }
+
+    pub fn db(&self) -> &BTreeDb {
+        &self.db
+    }
+
+    pub fn chunk_indices(&self) -> Result<Vec<(i32, i32)>, Box<dyn Error>> {
+        Ok(self
+            .db
+            .keys()?
+            .into_iter()
+            .map(|k| {
+                (
+                    i32::from_be_bytes([k[0], k[1], k[2], k[3]]),
+                    i32::from_be_bytes([k[4], k[5], k[6], k[7]]),
+                )
+            })
+            .collect())
+    }
+
+    pub fn chunk(&self, x: i32, y: i32) -> Result<Option<CelestialChunk>, Box<dyn Error>> {
+        let data = match self.db.get(&chunk_key(x, y))? {
+            Some(data) => decompress(&data)?,
+            None => return Ok(None),
+        };
+        let (_, chunk) = render_nom_error(&data, parse_chunk(&data))
+            .map_err(|e| format!("chunk {}, {}: {}", x, y, e))?;
+        Ok(Some(chunk))
+    }
+
+    /// The generated parameters of a system, planet or satellite, if its
+    /// chunk has been generated.
+    pub fn parameters(
+        &self,
+        c: &CelestialCoordinate,
+    ) -> Result<Option<CelestialParameters>, Box<dyn Error>> {
+        let (x, y) = c.chunk();
+        Ok(self
+            .chunk(x, y)?
+            .and_then(|chunk| chunk.parameters(c).cloned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bson::serializer::to_writer;
+    use crate::btreedb::writer::BTreeDbWriter;
+    use crate::packed::save_versioned_json;
+    use flate2::{write::ZlibEncoder, Compression};
+    use serde_json::json;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_coordinates() {
+        let c: CelestialCoordinate = "-12:340:-5600:3:1".parse().unwrap();
+        assert_eq!(c.location, [-12, 340, -5600]);
+        assert!(c.is_satellite());
+        assert_eq!(c.to_string(), "-12:340:-5600:3:1");
+        assert_eq!(c.parent().unwrap().to_string(), "-12:340:-5600:3");
+        assert_eq!(
+            c.parent().unwrap().parent().unwrap().to_string(),
+            "-12:340:-5600"
+        );
+        assert_eq!(c.chunk(), (-1, 5));
+        assert_eq!(
+            "CelestialWorld:1:2:3:4"
+                .parse::<CelestialCoordinate>()
+                .unwrap(),
+            CelestialCoordinate {
+                location: [1, 2, 3],
+                planet: 4,
+                satellite: 0
+            }
+        );
+        assert_eq!(CelestialCoordinate::from_value(&c.to_value()), Ok(c));
+        assert_eq!(
+            "1:2".parse::<CelestialCoordinate>(),
+            Err(CoordinateError::WrongPartCount(2))
+        );
+        assert!("1:2:x".parse::<CelestialCoordinate>().is_err());
+    }
+
+    #[test]
+    fn test_system() {
+        let target = json!({"location": [1, 2, 3], "planet": 2, "satellite": 0});
+        let content = json!({
+            "location": [1, 2, 3],
+            "objects": [
+                {"uuid": "a", "name": "outpost", "orbit": {"target": target, "direction": -1}, "parameters": {}},
+                {"uuid": "b", "name": "anomaly", "position": [10.5, -3], "parameters": {}},
+            ],
+            "ships": {"c": {"uuid": "c", "orbit": {"target": target}}},
+        });
+        let system = SystemFile::from_versioned_json(VersionedJson {
+            identifier: "System".to_string(),
+            version: 1,
+            content: serde_json::from_value(content).unwrap(),
+        })
+        .unwrap();
+        assert_eq!(system.coordinate().to_string(), "1:2:3");
+        assert_eq!(system.objects[1].position, Some((10.5, -3.0)));
+        let planet = "1:2:3:2".parse().unwrap();
+        let occupants = system.orbit_occupants(&planet);
+        let names = occupants
+            .iter()
+            .map(|o| o.uuid.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "c"]);
+        assert_eq!(occupants[0].orbit.as_ref().unwrap().direction, -1);
+    }
+
+    fn parameters(out: &mut Vec<u8>, c: &CelestialCoordinate, name: &str) {
+        for n in c.location.iter().chain(&[c.planet, c.satellite]) {
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+        out.extend_from_slice(&42u64.to_be_bytes());
+        out.push(name.len() as u8);
+        out.extend_from_slice(name.as_bytes());
+        to_writer(&mut *out, &Value::Object(Default::default())).unwrap();
+        to_writer(&mut *out, &Value::Empty).unwrap();
+    }
+
+    #[test]
+    fn test_chunks() {
+        let system: CelestialCoordinate = "65:3:-7".parse().unwrap();
+        let planet: CelestialCoordinate = "65:3:-7:1".parse().unwrap();
+        let moon: CelestialCoordinate = "65:3:-7:1:2".parse().unwrap();
+        let mut chunk = Vec::new();
+        for n in &[1i32, 0] {
+            chunk.extend_from_slice(&n.to_be_bytes());
+        }
+        chunk.extend_from_slice(&[1, 1]);
+        for n in &[65i32, 3, 66, 4] {
+            chunk.extend_from_slice(&n.to_be_bytes());
+        }
+        chunk.push(1);
+        for n in &system.location {
+            chunk.extend_from_slice(&n.to_be_bytes());
+        }
+        parameters(&mut chunk, &system, "Alpha");
+        chunk.push(1);
+        for n in &system.location {
+            chunk.extend_from_slice(&n.to_be_bytes());
+        }
+        chunk.push(1);
+        chunk.extend_from_slice(&1i32.to_be_bytes());
+        parameters(&mut chunk, &planet, "Alpha I");
+        chunk.push(1);
+        chunk.extend_from_slice(&2i32.to_be_bytes());
+        parameters(&mut chunk, &moon, "Alpha I b");
+
+        let path = std::env::temp_dir().join(format!("universe-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut w = BTreeDbWriter::create(file, "Celestial2", 8, 512).unwrap();
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(&chunk).unwrap();
+        w.insert(&chunk_key(1, 0), &e.finish().unwrap()).unwrap();
+        w.commit().unwrap();
+
+        let universe = UniverseChunks::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(universe.chunk_indices().unwrap(), vec![(1, 0)]);
+        let chunk = universe.chunk(1, 0).unwrap().unwrap();
+        assert_eq!(chunk.constellations, vec![vec![((65, 3), (66, 4))]]);
+        assert_eq!(universe.parameters(&system).unwrap().unwrap().name, "Alpha");
+        assert_eq!(
+            universe.parameters(&planet).unwrap().unwrap().name,
+            "Alpha I"
+        );
+        let moon = universe.parameters(&moon).unwrap().unwrap();
+        assert_eq!((moon.name.as_str(), moon.seed), ("Alpha I b", 42));
+        assert_eq!(
+            universe.parameters(&"0:0:0".parse().unwrap()).unwrap(),
+            None
+        );
+
+        let mut system_file = VersionedJson {
+            identifier: "System".to_string(),
+            version: 0,
+            content: serde_json::from_value(json!({"location": [65, 3, -7]})).unwrap(),
+        };
+        let bytes = save_versioned_json(&mut system_file).unwrap();
+        let path = std::env::temp_dir().join(format!("system-{}", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let system_file = SystemFile::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(system_file.coordinate(), system);
+    }
+}
@@ -0,0 +1,293 @@
+//! Writing the assets of a pak out to a directory.
+
+use crate::glob::Filter;
+use crate::packed::{AssetError, PackedAssets};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug)]
+pub enum ExtractError {
+    Asset(AssetError),
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// An asset path that would land outside the destination, e.g. one
+    /// containing `..`.
+    UnsafePath(String),
+}
+
+impl std::fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtractError::Asset(e) => write!(f, "{}", e),
+            ExtractError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ExtractError::UnsafePath(p) => {
+                write!(f, "{} would be written outside the destination", p)
+            }
+        }
+    }
+}
+
+impl Error for ExtractError {}
+
+impl From<AssetError> for ExtractError {
+    fn from(e: AssetError) -> Self {
+        ExtractError::Asset(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Written,
+    /// Skipped because the file on disk already had the same contents.
+    Unchanged,
+}
+
+#[derive(Debug)]
+pub struct Extracted {
+    pub asset: String,
+    pub result: Result<Outcome, ExtractError>,
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// One entry per selected asset, in path order.
+    pub files: Vec<Extracted>,
+}
+
+impl ExtractReport {
+    fn with(&self, outcome: Outcome) -> Vec<&str> {
+        self.files
+            .iter()
+            .filter(|f| matches!(f.result, Ok(o) if o == outcome))
+            .map(|f| f.asset.as_str())
+            .collect()
+    }
+
+    pub fn written(&self) -> Vec<&str> {
+        self.with(Outcome::Written)
+    }
+
+    pub fn unchanged(&self) -> Vec<&str> {
+        self.with(Outcome::Unchanged)
+    }
+
+    pub fn errors(&self) -> Vec<(&str, &ExtractError)> {
+        self.files
+            .iter()
+            .filter_map(|f| f.result.as_ref().err().map(|e| (f.asset.as_str(), e)))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Only extract assets passing this filter.
+    pub filter: Filter,
+    /// How many threads to write with, or 0 for one per CPU.
+    pub threads: usize,
+    /// Leave files alone that already have the asset's contents.
+    pub skip_unchanged: bool,
+}
+
+/// Where `asset` goes under `dest`, unless it would escape it.
+fn destination(dest: &Path, asset: &str) -> Option<PathBuf> {
+    let relative = Path::new(asset.trim_start_matches('/'));
+    let normal = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if normal && relative.file_name().is_some() {
+        Some(dest.join(relative))
+    } else {
+        None
+    }
+}
+
+fn unchanged(path: &Path, bytes: &[u8]) -> bool {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.len() == bytes.len() as u64 => {
+            matches!(fs::read(path), Ok(old) if old == bytes)
+        }
+        _ => false,
+    }
+}
+
+fn extract_one(
+    assets: &PackedAssets,
+    asset: &str,
+    path: Option<&Path>,
+    skip_unchanged: bool,
+) -> Result<Outcome, ExtractError> {
+    let path = path.ok_or_else(|| ExtractError::UnsafePath(asset.to_string()))?;
+    let bytes = assets.file(asset)?;
+    if skip_unchanged && unchanged(path, bytes) {
+        return Ok(Outcome::Unchanged);
+    }
+    fs::write(path, bytes).map_err(|error| ExtractError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    Ok(Outcome::Written)
+}
+
+impl PackedAssets {
+    /// Extracts the assets passing the filter into `dest`, which is created
+    /// if needed. Failing to write one asset doesn't stop the others; only
+    /// failing to create `dest` is an error here.
+    pub fn extract<P: AsRef<Path>>(
+        &self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport, ExtractError> {
+        self.extract_with_progress(dest, options, |_| {})
+    }
+
+    /// Like `extract`, calling `progress` from the writing threads as each
+    /// asset is done.
+    pub fn extract_with_progress<P, F>(
+        &self,
+        dest: P,
+        options: &ExtractOptions,
+        progress: F,
+    ) -> Result<ExtractReport, ExtractError>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Extracted) + Sync,
+    {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest).map_err(|error| ExtractError::Io {
+            path: dest.to_path_buf(),
+            error,
+        })?;
+        let selected: Vec<_> = self
+            .assets()
+            .into_iter()
+            .filter(|a| options.filter.matches(a))
+            .map(|a| (a, destination(dest, a)))
+            .collect();
+
+        // Directories are made up front so the threads only write files. A
+        // directory that can't be made shows up as errors writing into it.
+        let dirs: BTreeSet<_> = selected
+            .iter()
+            .filter_map(|(_, path)| path.as_ref()?.parent())
+            .collect();
+        for dir in dirs {
+            let _ = fs::create_dir_all(dir);
+        }
+
+        let run = |chunk: &[(&str, Option<PathBuf>)]| -> Vec<Extracted> {
+            chunk
+                .iter()
+                .map(|(asset, path)| {
+                    let extracted = Extracted {
+                        asset: asset.to_string(),
+                        result: extract_one(self, asset, path.as_deref(), options.skip_unchanged),
+                    };
+                    progress(&extracted);
+                    extracted
+                })
+                .collect()
+        };
+        let threads = match options.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        if threads <= 1 || selected.len() <= 1 {
+            return Ok(ExtractReport {
+                files: run(&selected),
+            });
+        }
+        let chunk = selected.len().div_ceil(threads);
+        let files = std::thread::scope(|s| {
+            let handles: Vec<_> = selected
+                .chunks(chunk)
+                .map(|chunk| s.spawn(move || run(chunk)))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().expect("extract thread panicked"))
+                .collect()
+        });
+        Ok(ExtractReport { files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::Glob;
+    use crate::pack_directory;
+    use std::fs::File;
+
+    #[test]
+    fn test_extract() {
+        let dir = std::env::temp_dir().join(format!("extract-{}", std::process::id()));
+        let src = dir.join("src");
+        let files = [
+            ("a.item", "{}"),
+            ("items/b.item", "{\"price\": 1}"),
+            ("items/deep/c.png", "PNG"),
+            ("d.lua", "x = 1"),
+        ];
+        for (path, contents) in &files {
+            let path = src.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let pak = dir.join("test.pak");
+        pack_directory(File::create(&pak).unwrap(), &src).unwrap();
+        let assets = PackedAssets::new(&File::open(&pak).unwrap()).unwrap();
+
+        let out = dir.join("out");
+        let options = ExtractOptions {
+            filter: Filter {
+                include: vec![],
+                exclude: vec![Glob::new("*.lua")],
+            },
+            threads: 2,
+            skip_unchanged: true,
+        };
+        let report = assets.extract(&out, &options).unwrap();
+        assert_eq!(
+            report.written(),
+            vec!["/a.item", "/items/b.item", "/items/deep/c.png"]
+        );
+        assert!(report.errors().is_empty());
+        assert_eq!(fs::read(out.join("items/deep/c.png")).unwrap(), b"PNG");
+        assert!(!out.join("d.lua").exists());
+
+        fs::write(out.join("items/b.item"), "{\"price\": 2}").unwrap();
+        let report = assets.extract(&out, &options).unwrap();
+        assert_eq!(report.written(), vec!["/items/b.item"]);
+        assert_eq!(report.unchanged(), vec!["/a.item", "/items/deep/c.png"]);
+        assert_eq!(
+            fs::read_to_string(out.join("items/b.item")).unwrap(),
+            "{\"price\": 1}"
+        );
+
+        // A file where a directory should be
+        fs::remove_dir_all(out.join("items")).unwrap();
+        fs::write(out.join("items"), "").unwrap();
+        let report = assets.extract(&out, &ExtractOptions::default()).unwrap();
+        assert_eq!(report.written(), vec!["/a.item", "/d.lua"]);
+        let errors = report.errors();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ("/items/b.item", ExtractError::Io { .. })
+        ));
+
+        assert_eq!(
+            destination(&out, "/items/b.item"),
+            Some(out.join("items/b.item"))
+        );
+        assert_eq!(destination(&out, "/../escape"), None);
+        assert_eq!(destination(&out, "/"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+/// A shell-style pattern over asset paths. `*` and `?` stay within one path
+/// component, `**` crosses them. A pattern without a `/` is matched against
+/// the file name only, so `*.png` finds every PNG.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Glob {
+    pattern: String,
+}
+
+/// Whether `p` matches all of `s`. `table[i][j]` says whether `p[i..]`
+/// matches `s[j..]`; filling it in from the ends means each entry only looks
+/// at ones already known, so stars never backtrack.
+fn matches(p: &[u8], s: &[u8]) -> bool {
+    let width = s.len() + 1;
+    let at = |i: usize, j: usize| i * width + j;
+    let mut table = vec![false; (p.len() + 1) * width];
+    table[at(p.len(), s.len())] = true;
+    for j in (0..=s.len()).rev() {
+        let next = s.get(j);
+        let within = matches!(next, Some(&c) if c != b'/');
+        for i in (0..p.len()).rev() {
+            table[at(i, j)] = match p[i] {
+                b'*' if p.get(i + 1) == Some(&b'*') => {
+                    // `a/**/b` also matches `a/b`
+                    (p.get(i + 2) == Some(&b'/') && table[at(i + 3, j)])
+                        || table[at(i + 2, j)]
+                        || (next.is_some() && table[at(i, j + 1)])
+                }
+                b'*' => table[at(i + 1, j)] || (within && table[at(i, j + 1)]),
+                b'?' => within && table[at(i + 1, j + 1)],
+                c => next == Some(&c) && table[at(i + 1, j + 1)],
+            };
+        }
+    }
+    table[at(0, 0)]
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        Glob {
+            pattern: pattern.to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = if self.pattern.contains('/') {
+            path
+        } else {
+            path.rsplit('/').next().unwrap_or(path)
+        };
+        matches(self.pattern.as_bytes(), path.as_bytes())
+    }
+}
+
+impl std::str::FromStr for Glob {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Glob::new(s))
+    }
+}
+
+/// Include and exclude patterns together. A path passes if it matches any
+/// include, or there are none, and no exclude.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    pub include: Vec<Glob>,
+    pub exclude: Vec<Glob>,
+}
+
+impl Filter {
+    pub fn matches(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|g| g.matches(path)))
+            && !self.exclude.iter().any(|g| g.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let g = Glob::new;
+        assert!(g("*.png").matches("/items/generic/crafting/bar.png"));
+        assert!(!g("*.png").matches("/items/bar.png.frames"));
+        assert!(g("/items/*/bar.png").matches("/items/generic/bar.png"));
+        assert!(!g("/items/*.png").matches("/items/generic/bar.png"));
+        assert!(g("/items/**/*.png").matches("/items/a/b/c.png"));
+        assert!(g("/items/**/*.png").matches("/items/c.png"));
+        assert!(g("/items/**").matches("/items/a/b"));
+        assert!(g("/items/ba?.png").matches("/items/bar.png"));
+        assert!(!g("/items/?bar.png").matches("/items//bar.png"));
+        assert!(g("/a/**/**/b").matches("/a/b"));
+        assert!(g("***.png").matches("/a/b.png"));
+
+        let long = "a".repeat(100);
+        assert!(!g("*a*a*a*a*a*a*a*a*a*a*a*a*b").matches(&long));
+        assert!(!g("/**a**a**a**a**a**a**a**a**a**a**b").matches(&format!("/{}", long)));
+        assert!(g("*a*a*a*a*a*a*a*a*a*a*a*a").matches(&long));
+
+        let filter = Filter {
+            include: vec![g("/items/**")],
+            exclude: vec![g("*.frames")],
+        };
+        assert!(filter.matches("/items/bar.png"));
+        assert!(!filter.matches("/items/bar.frames"));
+        assert!(!filter.matches("/tiles/dirt.png"));
+        assert!(Filter::default().matches("/anything"));
+    }
+}
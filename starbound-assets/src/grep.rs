@@ -0,0 +1,356 @@
+//! Searching every asset of a pak at once, either as text or as parsed JSON.
+
+use crate::bson::pointer::escape;
+use crate::glob::Filter;
+use crate::packed::{AssetError, PackedAssets};
+use crate::pattern::Pattern;
+use serde_json::Value;
+
+/// Lines longer than this are cut short when shown as context.
+const MAX_CONTEXT: usize = 200;
+
+#[derive(Clone, Debug)]
+pub enum Needle {
+    /// These exact bytes anywhere in the asset.
+    Bytes(Vec<u8>),
+    /// A pattern matched line by line.
+    Text(Pattern),
+    /// JSON objects with this key.
+    Key(String),
+    /// JSON strings, numbers, booleans and nulls matching the pattern, with
+    /// non-strings matched as they are written.
+    Value(Pattern),
+    /// Assets where this JSON pointer resolves.
+    Pointer(String),
+}
+
+impl Needle {
+    fn is_json(&self) -> bool {
+        matches!(self, Needle::Key(_) | Needle::Value(_) | Needle::Pointer(_))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// 1-based, with the column counted in bytes.
+    Line {
+        line: usize,
+        column: usize,
+    },
+    Pointer(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub path: String,
+    pub location: Location,
+    /// The matching line, or the JSON value found.
+    pub context: String,
+}
+
+impl Match {
+    pub fn to_json(&self) -> Value {
+        use serde_json::json;
+        match &self.location {
+            Location::Line { line, column } => json!({
+                "path": self.path,
+                "line": line,
+                "column": column,
+                "text": self.context,
+            }),
+            Location::Pointer(pointer) => json!({
+                "path": self.path,
+                "pointer": pointer,
+                "value": self.context,
+            }),
+        }
+    }
+}
+
+/// `path:line:column: text` or `path:pointer: value`, like grep.
+impl std::fmt::Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Location::Line { line, column } => {
+                write!(f, "{}:{}:{}: {}", self.path, line, column, self.context)
+            }
+            Location::Pointer(pointer) => write!(f, "{}:{}: {}", self.path, pointer, self.context),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GrepReport {
+    pub matches: Vec<Match>,
+    /// Assets that couldn't be read and so weren't searched.
+    pub errors: Vec<AssetError>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GrepOptions {
+    /// Only search assets passing this filter, e.g. `*.item` for one
+    /// extension.
+    pub filter: Filter,
+    /// How many threads to search with, or 0 for one per CPU.
+    pub threads: usize,
+}
+
+fn context(line: &[u8]) -> String {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut s = String::from_utf8_lossy(line).trim().to_string();
+    if s.len() > MAX_CONTEXT {
+        let mut end = MAX_CONTEXT;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+        s.push_str("...");
+    }
+    s
+}
+
+/// The first match on each line.
+fn search_lines(path: &str, data: &[u8], needle: &Needle, out: &mut Vec<Match>) {
+    for (n, line) in data.split(|&b| b == b'\n').enumerate() {
+        let column = match needle {
+            Needle::Bytes(bytes) if !bytes.is_empty() => {
+                line.windows(bytes.len()).position(|w| w == &bytes[..])
+            }
+            Needle::Text(pattern) => match std::str::from_utf8(line) {
+                Ok(line) => pattern.find(line).map(|(start, _)| start),
+                Err(_) => pattern
+                    .find(&String::from_utf8_lossy(line))
+                    .map(|(start, _)| start),
+            },
+            _ => None,
+        };
+        if let Some(column) = column {
+            out.push(Match {
+                path: path.to_string(),
+                location: Location::Line {
+                    line: n + 1,
+                    column: column + 1,
+                },
+                context: context(line),
+            });
+        }
+    }
+}
+
+fn found(path: &str, pointer: String, v: &Value) -> Match {
+    Match {
+        path: path.to_string(),
+        location: Location::Pointer(pointer),
+        context: v.to_string(),
+    }
+}
+
+fn search_json(path: &str, v: &Value, pointer: &str, needle: &Needle, out: &mut Vec<Match>) {
+    match v {
+        Value::Object(o) => {
+            for (k, child) in o {
+                let pointer = format!("{}/{}", pointer, escape(k));
+                if matches!(needle, Needle::Key(key) if key == k) {
+                    out.push(found(path, pointer.clone(), child));
+                }
+                search_json(path, child, &pointer, needle, out);
+            }
+        }
+        Value::Array(a) => {
+            for (i, child) in a.iter().enumerate() {
+                search_json(path, child, &format!("{}/{}", pointer, i), needle, out);
+            }
+        }
+        Value::String(s) => {
+            if matches!(needle, Needle::Value(p) if p.is_match(s)) {
+                out.push(found(path, pointer.to_string(), v));
+            }
+        }
+        _ => {
+            if matches!(needle, Needle::Value(p) if p.is_match(&v.to_string())) {
+                out.push(found(path, pointer.to_string(), v));
+            }
+        }
+    }
+}
+
+fn search(assets: &PackedAssets, paths: &[&str], needle: &Needle) -> GrepReport {
+    let mut report = GrepReport::default();
+    let out = &mut report.matches;
+    for &path in paths {
+        let data = match assets.file(path) {
+            Ok(data) => data,
+            Err(e) => {
+                report.errors.push(e);
+                continue;
+            }
+        };
+        if !needle.is_json() {
+            search_lines(path, data, needle, out);
+            continue;
+        }
+        // Assets that aren't JSON can't match
+        let v = match assets.json(path) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        match needle {
+            Needle::Pointer(pointer) => {
+                if let Some(v) = v.pointer(pointer) {
+                    out.push(found(path, pointer.clone(), v));
+                }
+            }
+            _ => search_json(path, &v, "", needle, out),
+        }
+    }
+    report
+}
+
+impl PackedAssets {
+    /// Every match of `needle` in the assets passing the filter, ordered by
+    /// path and then position. Assets are split between threads reading
+    /// straight from the mapped pak. An asset that can't be read is skipped
+    /// and reported rather than stopping the search.
+    pub fn grep(&self, needle: &Needle, options: &GrepOptions) -> GrepReport {
+        let paths: Vec<_> = self
+            .assets()
+            .into_iter()
+            .filter(|p| options.filter.matches(p))
+            .collect();
+        let threads = match options.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        if threads <= 1 || paths.len() <= 1 {
+            return search(self, &paths, needle);
+        }
+        let chunk = paths.len().div_ceil(threads);
+        let results: Vec<_> = std::thread::scope(|s| {
+            let handles: Vec<_> = paths
+                .chunks(chunk)
+                .map(|paths| s.spawn(move || search(self, paths, needle)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("grep thread panicked"))
+                .collect()
+        });
+        let mut report = GrepReport::default();
+        for result in results {
+            report.matches.extend(result.matches);
+            report.errors.extend(result.errors);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::Glob;
+    use crate::pack_directory;
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    #[test]
+    fn test_grep() {
+        let dir = std::env::temp_dir().join(format!("grep-{}", std::process::id()));
+        let files = [
+            (
+                "a.item",
+                "{\n  \"itemName\": \"ironbar\",\n  \"price\": 10\n}",
+            ),
+            (
+                "b.item",
+                r#"{"itemName": "goldbar", "price": 50, "tags": ["bar", "gold"]}"#,
+            ),
+            ("c.lua", "-- ironbar\r\nlocal x = \"ironbar\"\n"),
+            ("d.png", "\u{1}ironbar\u{0}"),
+        ];
+        for (path, contents) in &files {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(path), contents).unwrap();
+        }
+        fs::write(dir.join("e.txt"), "x".repeat(200_000) + "!").unwrap();
+        let out = dir.with_extension("pak");
+        pack_directory(File::create(&out).unwrap(), Path::new(&dir)).unwrap();
+        // Point the directory entry of d.png past the end of the file
+        let mut pak = fs::read(&out).unwrap();
+        let entry = pak.windows(6).rposition(|w| w == b"/d.png").unwrap() + 6;
+        pak[entry + 8..entry + 16].copy_from_slice(&i64::MAX.to_be_bytes());
+        fs::write(&out, pak).unwrap();
+        let assets = PackedAssets::new(&File::open(&out).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&out).unwrap();
+
+        let grep = |needle: Needle, threads| {
+            let options = GrepOptions {
+                threads,
+                ..Default::default()
+            };
+            let report = assets.grep(&needle, &options);
+            assert_eq!(report.errors.len(), 1);
+            report
+                .matches
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+        };
+        let text = grep(Needle::Bytes(b"ironbar".to_vec()), 1);
+        assert_eq!(
+            text,
+            vec![
+                "/a.item:2:16: \"itemName\": \"ironbar\",",
+                "/c.lua:1:4: -- ironbar",
+                "/c.lua:2:12: local x = \"ironbar\"",
+            ]
+        );
+        assert_eq!(grep(Needle::Bytes(b"ironbar".to_vec()), 3), text);
+        let report = assets.grep(&Needle::Key("x".to_string()), &GrepOptions::default());
+        assert!(matches!(
+            &report.errors[..],
+            [AssetError::BadRange { path, .. }] if path == "/d.png"
+        ));
+        assert_eq!(
+            grep(Needle::Text(Pattern::new(r#"= "\w+"$"#).unwrap()), 0),
+            vec!["/c.lua:2:9: local x = \"ironbar\""]
+        );
+        // One long line, searched on another thread
+        assert_eq!(
+            grep(Needle::Text(Pattern::new(r"\w+!").unwrap()), 2),
+            vec![format!("/e.txt:1:1: {}...", "x".repeat(MAX_CONTEXT))]
+        );
+        assert_eq!(
+            grep(Needle::Key("price".to_string()), 2),
+            vec!["/a.item:/price: 10", "/b.item:/price: 50"]
+        );
+        assert_eq!(
+            grep(Needle::Value(Pattern::new("^(gold|50)").unwrap()), 0),
+            vec![
+                "/b.item:/itemName: \"goldbar\"",
+                "/b.item:/price: 50",
+                "/b.item:/tags/1: \"gold\"",
+            ]
+        );
+        assert_eq!(
+            grep(Needle::Pointer("/tags/0".to_string()), 0),
+            vec!["/b.item:/tags/0: \"bar\""]
+        );
+
+        let options = GrepOptions {
+            filter: Filter {
+                include: vec![Glob::new("*.lua")],
+                exclude: vec![],
+            },
+            threads: 0,
+        };
+        let report = assets.grep(&Needle::Bytes(b"ironbar".to_vec()), &options);
+        assert!(report.errors.is_empty());
+        let matches = report.matches;
+        assert_eq!(
+            matches[0].to_json(),
+            serde_json::json!({"path": "/c.lua", "line": 1, "column": 4, "text": "-- ironbar"})
+        );
+        assert_eq!(matches.len(), 2);
+    }
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemDescriptor {
+    pub name: String,
+    pub count: u64,
+    pub parameters: Map<String, Value>,
+}
+
+impl ItemDescriptor {
+    pub fn new(name: &str, count: u64) -> Self {
+        ItemDescriptor {
+            name: name.to_string(),
+            count,
+            parameters: Map::new(),
+        }
+    }
+
+    /// Accepts every shape the game accepts for an item: a bare name,
+    /// `[name, count, parameters]`, or an object keyed by `item` or `name`.
+    pub fn from_json(v: &Value) -> Option<Self> {
+        match v {
+            Value::String(name) => Some(Self::new(name, 1)),
+            Value::Array(a) => {
+                let name = a.first()?.as_str()?;
+                let count = a.get(1).map_or(Some(1), json_u64)?;
+                let parameters = a
+                    .get(2)
+                    .and_then(|p| p.as_object())
+                    .cloned()
+                    .unwrap_or_default();
+                Some(ItemDescriptor {
+                    name: name.to_string(),
+                    count,
+                    parameters,
+                })
+            }
+            Value::Object(o) => {
+                let name = o.get("item").or_else(|| o.get("name"))?.as_str()?;
+                let count = o.get("count").map_or(Some(1), json_u64)?;
+                let parameters = o
+                    .get("parameters")
+                    .or_else(|| o.get("data"))
+                    .and_then(|p| p.as_object())
+                    .cloned()
+                    .unwrap_or_default();
+                Some(ItemDescriptor {
+                    name: name.to_string(),
+                    count,
+                    parameters,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut o = Map::new();
+        o.insert("name".to_string(), Value::String(self.name.clone()));
+        o.insert("count".to_string(), Value::Number(self.count.into()));
+        o.insert(
+            "parameters".to_string(),
+            Value::Object(self.parameters.clone()),
+        );
+        Value::Object(o)
+    }
+}
+
+/// Asset files write counts as either integers or floats.
+pub(crate) fn json_u64(v: &Value) -> Option<u64> {
+    v.as_u64()
+        .or_else(|| v.as_f64().filter(|f| *f >= 0.0).map(|f| f as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_descriptor_shapes() {
+        let bare = ItemDescriptor::from_json(&json!("dirtmaterial")).unwrap();
+        assert_eq!(bare, ItemDescriptor::new("dirtmaterial", 1));
+
+        let arr = ItemDescriptor::from_json(&json!(["copperbar", 3.0])).unwrap();
+        assert_eq!(arr, ItemDescriptor::new("copperbar", 3));
+
+        let obj = ItemDescriptor::from_json(&json!({"item": "torch", "count": 4})).unwrap();
+        assert_eq!(obj, ItemDescriptor::new("torch", 4));
+
+        assert!(ItemDescriptor::from_json(&json!({"count": 4})).is_none());
+    }
+}
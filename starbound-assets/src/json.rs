@@ -1,18 +1,18 @@
 use nom::{
     branch::alt,
     bytes::complete::{escaped, is_a, is_not, tag, take_until},
-    character::complete::{multispace1, not_line_ending, one_of},
-    combinator::{cut, map, map_parser, map_res, opt, value},
+    character::complete::{digit1, multispace1, not_line_ending, one_of},
+    combinator::{all_consuming, cut, map, map_parser, map_res, not, opt, recognize, value},
     error::{context, ErrorKind, ParseError},
     multi::{many0, separated_list},
     number::complete::double,
-    sequence::{delimited, separated_pair},
+    sequence::{delimited, pair, separated_pair, terminated},
     Err, IResult,
 };
 use serde_json::{Number, Value};
 use std::fmt::Debug;
 
-fn ws<'a, O: Debug, F, E: ParseError<&'a [u8]>>(
+pub(crate) fn ws<'a, O: Debug, F, E: ParseError<&'a [u8]>>(
     comb: F,
 ) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], O, E>
 where
@@ -21,7 +21,7 @@ where
     move |i| {
         let line_comment = context(
             "line comment",
-            delimited(tag("//"), not_line_ending, cut(is_a("\r\n"))),
+            delimited(tag("//"), not_line_ending, opt(is_a("\r\n"))),
         );
         let block_comment = context(
             "block comment",
@@ -60,8 +60,8 @@ pub(crate) fn utf8<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8]
         .map(|s| (&i[s.len()..], s))
 }
 
-fn string<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], &'a str, E> {
-    let bytes = escaped(is_not("\\\""), '\\', one_of("nt\\\""));
+pub(crate) fn string<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], &'a str, E> {
+    let bytes = escaped(is_not("\\\""), '\\', one_of("bfnrtu/\\\""));
 
     context(
         "string",
@@ -80,7 +80,21 @@ fn string_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], V
     map(string, |s| Value::String(s.to_owned()))(i)
 }
 
-fn number_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
+fn integer_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
+    let digits = recognize(pair(opt(one_of("+-")), digit1));
+    let parse = map_res(terminated(digits, not(one_of(".eE"))), |b: &[u8]| {
+        std::str::from_utf8(b)
+            .ok()
+            .and_then(|s| s.trim_start_matches('+').parse::<i64>().ok())
+            .map(|n| Value::Number(n.into()))
+            .ok_or(())
+    });
+    context("integer", parse)(i)
+}
+
+pub(crate) fn number_value<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], Value, E> {
     let parse = map_res(double, |f| {
         if f == f64::INFINITY {
             Ok(Value::Number(Number::from_f64(f64::MAX).unwrap()))
@@ -95,7 +109,7 @@ fn number_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], V
             Ok(Value::Number(Number::from_f64(f).unwrap()))
         }
     });
-    context("number", parse)(i)
+    context("number", alt((integer_value, parse)))(i)
 }
 
 fn array_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
@@ -129,6 +143,10 @@ pub fn json_value<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8],
     ))(i)
 }
 
+pub fn document<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
+    all_consuming(ws(json_value))(i)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -255,6 +273,25 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_integers() {
+        let (_, v) = json_value::<VerboseError<&[u8]>>(b"[1, -2, 3.0, 4e2]").unwrap();
+        assert!(v[0].is_i64());
+        assert!(v[1].is_i64());
+        assert!(v[2].is_f64());
+        assert!(v[3].is_f64());
+    }
+
+    #[test]
+    fn test_document_comments() {
+        assert!(
+            document::<VerboseError<&[u8]>>(b"// header\n{\"key\": 1} // trailer")
+                .unwrap()
+                .1
+                .is_object()
+        )
+    }
+
     #[test]
     fn test_obj_w_empty_str() {
         assert!(json_value::<VerboseError<&[u8]>>(b"{\"key\": \"\"}")
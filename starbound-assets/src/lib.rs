@@ -2,12 +2,33 @@ use std::error::Error;
 use std::fs::File;
 
 pub mod bson;
+pub mod btreedb;
+pub mod celestial;
+pub mod extract;
+pub mod glob;
+pub mod grep;
+pub mod item;
 #[allow(dead_code)]
 mod json;
 mod packed;
+pub mod pakdiff;
+pub mod pattern;
+pub mod query;
+pub mod quest;
+pub mod recipe;
+pub mod save;
+pub mod species;
+pub mod storage;
+pub mod tech;
+pub mod treasure;
+pub mod verify;
 mod vlq;
+pub mod world;
 
-pub use packed::{save_versioned_json, PackedAssets, Player};
+pub use packed::{
+    load_versioned_json, pack_directory, save_versioned_json, write_packed_assets, AssetError,
+    LoadedFrom, Metadata, PackedAssets, Player, VersionedJson,
+};
 
 pub fn parse_packed(path: &str) -> Result<PackedAssets, Box<dyn Error>> {
     let f = File::open(path)?;
@@ -15,6 +36,5 @@ pub fn parse_packed(path: &str) -> Result<PackedAssets, Box<dyn Error>> {
 }
 
 pub fn parse_player(path: &str) -> Result<Player, Box<dyn Error>> {
-    let f = File::open(path)?;
-    packed::Player::new(&f)
+    packed::Player::open(path)
 }
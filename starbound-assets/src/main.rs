@@ -1,73 +1,863 @@
 use clap::{
-    app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg, ArgGroup,
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, App, AppSettings,
+    Arg, ArgMatches, ErrorKind, SubCommand,
 };
 use progress_bar::{
     color::{Color, Style},
     progress_bar::ProgressBar,
 };
-use starbound_assets::{parse_packed, parse_player};
-use std::collections::BTreeSet;
+use serde_json::json;
+use starbound_assets::bson::{text, Value};
+use starbound_assets::extract::ExtractOptions;
+use starbound_assets::glob::{Filter, Glob};
+use starbound_assets::grep::{GrepOptions, Needle};
+use starbound_assets::pakdiff::DiffOptions;
+use starbound_assets::pattern::Pattern;
+use starbound_assets::query::Query;
+use starbound_assets::verify::verify_file;
+use starbound_assets::world::render::{Palette, RenderOptions};
+use starbound_assets::world::World;
+use starbound_assets::{load_versioned_json, pack_directory, parse_packed, parse_player, Player};
+use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Mutex;
 
-fn extract_assets(assets_path: &str) {
-    let assets = parse_packed(assets_path).expect("could not open assets file");
-    println!("meta: {:?}", assets.metadata());
+/// Something went wrong while running the command.
+const EXIT_FAILURE: i32 = 1;
+/// The command line itself was wrong.
+const EXIT_USAGE: i32 = 2;
 
-    let base = PathBuf::from("./out");
-    fs::create_dir_all(&base).expect("could not create output directory");
+fn print_json(v: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(v).unwrap());
+}
+
+fn filter(matches: &ArgMatches) -> Filter {
+    let globs = |name| {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flatten()
+            .map(Glob::new)
+            .collect()
+    };
+    Filter {
+        include: globs("pattern"),
+        exclude: globs("exclude"),
+    }
+}
+
+fn list(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let assets = parse_packed(matches.value_of("pak").unwrap())?;
+    let filter = filter(matches);
+    let listed = assets.assets().into_iter().filter(|a| filter.matches(a));
+    if matches.is_present("json") {
+        let entries = listed
+            .map(|a| json!({"path": a, "size": assets.file(a).map_or(0, |f| f.len())}))
+            .collect();
+        print_json(&serde_json::Value::Array(entries));
+    } else if matches.is_present("long") {
+        for asset in listed {
+            println!(
+                "{:>10} {}",
+                assets.file(asset).map_or(0, |f| f.len()),
+                asset
+            );
+        }
+    } else {
+        for asset in listed {
+            println!("{}", asset);
+        }
+    }
+    Ok(())
+}
+
+fn cat(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let assets = parse_packed(matches.value_of("pak").unwrap())?;
+    let asset = matches.value_of("asset").unwrap();
+    let bytes = assets.file(asset)?;
+    std::io::stdout().lock().write_all(bytes)?;
+    Ok(())
+}
 
-    let mut progress = ProgressBar::new(assets.assets().len());
-    progress.set_action("Extracting", Color::White, Style::Bold);
+fn info(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = matches.value_of("pak").unwrap();
+    let assets = parse_packed(path)?;
+    let names = assets.assets();
+    let size = names
+        .iter()
+        .map(|a| assets.file(a).map_or(0, |f| f.len()))
+        .sum::<usize>();
+    let metadata = text::to_json(&Value::Object(assets.metadata()));
+    if matches.is_present("json") {
+        print_json(&json!({
+            "path": path,
+            "assets": names.len(),
+            "size": size,
+            "metadata": metadata,
+        }));
+    } else {
+        println!("path: {}", path);
+        println!("assets: {}", names.len());
+        println!("size: {}", size);
+        println!("metadata: {}", serde_json::to_string_pretty(&metadata)?);
+    }
+    Ok(())
+}
 
-    let mut dirs = BTreeSet::new();
-    dirs.insert(base.clone());
+fn extract(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let assets = parse_packed(matches.value_of("pak").unwrap())?;
+    let base = PathBuf::from(matches.value_of("output").unwrap());
+    let options = ExtractOptions {
+        filter: filter(matches),
+        threads: matches.value_of("threads").unwrap_or("0").parse()?,
+        skip_unchanged: matches.is_present("skip-unchanged"),
+    };
+    let as_json = matches.is_present("json");
 
-    for asset in assets.assets() {
-        let path = base.join(asset.trim_start_matches('/'));
-        let pdir = path.parent().unwrap();
-        if !dirs.contains(pdir) {
-            fs::create_dir_all(pdir).expect("could not create directory");
-            dirs.insert(pdir.to_path_buf());
+    let total = assets
+        .assets()
+        .into_iter()
+        .filter(|a| options.filter.matches(a))
+        .count();
+    let progress = Mutex::new(ProgressBar::new(total));
+    if !as_json {
+        let mut progress = progress.lock().unwrap();
+        progress.set_action("Extracting", Color::White, Style::Bold);
+    }
+    let report = assets.extract_with_progress(&base, &options, |_| {
+        if !as_json {
+            progress.lock().unwrap().inc();
         }
+    })?;
+    let errors = report.errors();
 
-        fs::write(path, assets.file(asset).unwrap()).expect("could not write asset");
-        progress.inc();
+    if as_json {
+        print_json(&json!({
+            "output": base,
+            "extracted": report.written(),
+            "unchanged": report.unchanged(),
+            "errors": errors
+                .iter()
+                .map(|(asset, e)| json!({"path": asset, "error": e.to_string()}))
+                .collect::<Vec<_>>(),
+        }));
+    } else {
+        println!();
+        for (asset, e) in &errors {
+            eprintln!("{}: {}", asset, e);
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} assets could not be extracted", errors.len()).into())
     }
+}
 
-    println!();
+fn diff_pak(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let old = parse_packed(matches.value_of("old").unwrap())?;
+    let new = parse_packed(matches.value_of("new").unwrap())?;
+    let options = DiffOptions {
+        filter: filter(matches),
+        json: matches.is_present("semantic"),
+    };
+    let diff = old.diff(&new, &options)?;
+    if matches.is_present("json") {
+        print_json(&json!({
+            "metadata": diff.metadata.iter().map(|c| c.to_json()).collect::<Vec<_>>(),
+            "assets": diff.assets.iter().map(|c| c.to_json()).collect::<Vec<_>>(),
+            "errors": diff.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+        }));
+    } else {
+        if !diff.metadata.is_empty() {
+            println!("metadata:");
+            for change in &diff.metadata {
+                println!("    {}", change);
+            }
+        }
+        for change in &diff.assets {
+            println!("{}", change);
+        }
+        for e in &diff.errors {
+            eprintln!("{}", e);
+        }
+    }
+    if diff.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} assets could not be compared", diff.errors.len()).into())
+    }
 }
 
-fn extract_player(player_path: &str) {
-    let player = parse_player(player_path).expect("could not parse player");
-    let f = std::fs::File::create("player.json").expect("could not open output");
-    serde_json::to_writer_pretty(f, &player.contents.content).expect("could not serialize player");
+fn verify(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = matches.value_of("pak").unwrap();
+    let report = verify_file(path).map_err(|e| format!("{}: {}", path, e))?;
+    if matches.is_present("json") {
+        print_json(&json!({
+            "path": path,
+            "assets": report.assets,
+            "problems": report.problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        }));
+    } else {
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+    }
+    if report.is_ok() {
+        if !matches.is_present("json") {
+            println!("{}: {} assets, ok", path, report.assets);
+        }
+        Ok(())
+    } else {
+        Err(format!("{}: {} problems found", path, report.problems.len()).into())
+    }
 }
 
-fn main() {
-    let matches = app_from_crate!()
-        .arg(
-            Arg::with_name("assets")
-                .short("a")
-                .takes_value(true)
-                .help("extract assets mode"),
+fn grep(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let assets = parse_packed(matches.value_of("pak").unwrap())?;
+    let search = matches.value_of("search").unwrap();
+    let pattern = || -> Result<Pattern, Box<dyn Error>> {
+        Ok(Pattern::new(search)?.ignore_case(matches.is_present("ignore-case")))
+    };
+    let needle = if matches.is_present("fixed") {
+        Needle::Bytes(search.as_bytes().to_vec())
+    } else if matches.is_present("key") {
+        Needle::Key(search.to_string())
+    } else if matches.is_present("value") {
+        Needle::Value(pattern()?)
+    } else if matches.is_present("pointer") {
+        Needle::Pointer(search.to_string())
+    } else {
+        Needle::Text(pattern()?)
+    };
+    let options = GrepOptions {
+        filter: filter(matches),
+        threads: matches.value_of("threads").unwrap_or("0").parse()?,
+    };
+    let report = assets.grep(&needle, &options);
+    if matches.is_present("json") {
+        print_json(&report.matches.iter().map(|m| m.to_json()).collect());
+    } else {
+        for m in &report.matches {
+            println!("{}", m);
+        }
+    }
+    for e in &report.errors {
+        eprintln!("{}", e);
+    }
+    if report.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} assets could not be searched", report.errors.len()).into())
+    }
+}
+
+fn pack(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let dir = matches.value_of("dir").unwrap();
+    let out = matches.value_of("pak").unwrap();
+    let f = fs::File::create(out).map_err(|e| format!("{}: {}", out, e))?;
+    let n = pack_directory(std::io::BufWriter::new(f), Path::new(dir))?;
+    if matches.is_present("json") {
+        print_json(&json!({"output": out, "assets": n}));
+    } else {
+        println!("packed {} assets into {}", n, out);
+    }
+    Ok(())
+}
+
+fn write_output(out: Option<&str>, contents: &str) -> Result<(), Box<dyn Error>> {
+    match out {
+        Some(out) => fs::write(out, contents).map_err(|e| format!("{}: {}", out, e).into()),
+        None => {
+            println!("{}", contents);
+            Ok(())
+        }
+    }
+}
+
+fn player_dump(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let player = parse_player(matches.value_of("player").unwrap())?;
+    write_output(
+        matches.value_of("output"),
+        &text::to_string(&player.contents.content),
+    )
+}
+
+fn player_load(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let json_path = matches.value_of("json").unwrap();
+    let original = parse_player(matches.value_of("original").unwrap())?;
+    let json = fs::read_to_string(json_path).map_err(|e| format!("{}: {}", json_path, e))?;
+    let content = text::from_str(&json)?;
+    let version = match matches.value_of("player-version") {
+        Some(v) => Some(v.parse().map_err(|_| format!("invalid version: {}", v))?),
+        None => None,
+    };
+
+    let mut player = Player::from_json(&original, content, version);
+    let out = match matches.value_of("output") {
+        Some(out) => PathBuf::from(out),
+        None => Path::new(json_path).with_extension("player"),
+    };
+    player.save_to(&out)?;
+    println!("wrote {}", out.display());
+    Ok(())
+}
+
+/// Whether `s` is written like a number, unlike `inf` or `nan` which Rust
+/// would also parse as floats.
+fn numeric(s: &str) -> bool {
+    s.bytes().any(|b| b.is_ascii_digit())
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b))
+}
+
+/// Reads a value given on the command line, either as `kind` or, without
+/// one, as whatever it looks like: a number, `true`/`false`, `null`, JSON in
+/// the text dialect, or failing all that, a string.
+fn literal(s: &str, kind: Option<&str>) -> Result<Value, Box<dyn Error>> {
+    let invalid = |kind| format!("invalid {}: {}", kind, s);
+    Ok(match kind {
+        Some("int") => Value::Integer(s.parse().map_err(|_| invalid("int"))?),
+        Some("float") => Value::Float(s.parse().map_err(|_| invalid("float"))?),
+        Some("bool") => Value::Boolean(s.parse().map_err(|_| invalid("bool"))?),
+        Some("string") => Value::String(s.to_string()),
+        Some(_) => text::from_str(s).map_err(|e| format!("invalid json: {}", e))?,
+        None => match (s.parse::<i64>(), s.parse::<f64>()) {
+            (Ok(i), _) => Value::Integer(i),
+            (_, Ok(f)) if numeric(s) => Value::Float(f),
+            _ if s == "true" || s == "false" => Value::Boolean(s == "true"),
+            _ if s == "null" => Value::Empty,
+            _ if s.starts_with(&['{', '[', '"'][..]) => {
+                text::from_str(s).map_err(|e| format!("invalid json: {}", e))?
+            }
+            _ => Value::String(s.to_string()),
+        },
+    })
+}
+
+/// `s` read as a replacement for what's at `pointer`. Without a `kind`, a
+/// float there stays a float even if `s` looks like an integer.
+fn replacement(
+    content: &Value,
+    pointer: &str,
+    s: &str,
+    kind: Option<&str>,
+) -> Result<Value, Box<dyn Error>> {
+    let mut value = literal(s, kind)?;
+    if let (None, Some(old)) = (kind, content.pointer(pointer)) {
+        value.restore_floats(old);
+    }
+    Ok(value)
+}
+
+fn player_diff(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let a = parse_player(matches.value_of("a").unwrap())?.contents;
+    let b = parse_player(matches.value_of("b").unwrap())?.contents;
+    let changes = a.content.diff(&b.content);
+    if matches.is_present("json") {
+        let version = if a.version != b.version {
+            json!({"from": a.version, "to": b.version})
+        } else {
+            serde_json::Value::Null
+        };
+        print_json(&json!({
+            "version": version,
+            "changes": changes.iter().map(|c| c.to_json()).collect::<Vec<_>>(),
+        }));
+    } else {
+        if a.version != b.version {
+            println!("version: {} -> {}", a.version, b.version);
+        }
+        for change in changes {
+            println!("{}", change);
+        }
+    }
+    Ok(())
+}
+
+fn player_get(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = matches.value_of("player").unwrap();
+    let pointer = matches.value_of("pointer").unwrap();
+    let player = parse_player(path)?;
+    match player.contents.content.pointer(pointer) {
+        Some(Value::String(s)) if matches.is_present("raw") => println!("{}", s),
+        Some(v) => println!("{}", text::to_string(v)),
+        None => return Err(format!("{}: nothing at {}", path, pointer).into()),
+    }
+    Ok(())
+}
+
+fn player_set(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let path = matches.value_of("player").unwrap();
+    let pointer = matches.value_of("pointer").unwrap();
+    let mut player = Player::open(path)?;
+    let content = &mut player.contents.content;
+
+    if matches.is_present("delete") {
+        content.remove_pointer(pointer)?;
+    } else if matches.is_present("append") {
+        let value = literal(matches.value_of("value").unwrap(), matches.value_of("type"))?;
+        match content.pointer_mut(pointer) {
+            Some(Value::Array(a)) => a.push(value),
+            Some(_) => return Err(format!("{} is not an array", pointer).into()),
+            None => return Err(format!("nothing at {}", pointer).into()),
+        }
+    } else {
+        let value = replacement(
+            content,
+            pointer,
+            matches.value_of("value").unwrap(),
+            matches.value_of("type"),
+        )?;
+        content.set_pointer(pointer, value)?;
+    }
+    player.save_to(matches.value_of("output").unwrap_or(path))?;
+    Ok(())
+}
+
+fn query(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let query: Query = matches.value_of("query").unwrap().parse()?;
+    let path = matches.value_of("file").unwrap();
+    let mut magic = [0u8; 8];
+    let n = fs::File::open(path)
+        .and_then(|mut f| f.read(&mut magic))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    // Results from a pak are labelled with the asset they came from
+    let results: Vec<(Option<String>, Value)> = match &magic[..n] {
+        b"SBAsset6" => parse_packed(path)?
+            .query(&query, &filter(matches))
+            .into_iter()
+            .map(|(asset, v)| (Some(asset), v))
+            .collect(),
+        m if m.starts_with(b"SBVJ01") => {
+            let json = load_versioned_json(&fs::File::open(path)?)?;
+            query
+                .run(&json.content)
+                .into_iter()
+                .map(|v| (None, v))
+                .collect()
+        }
+        _ => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            query
+                .run(&text::from_str(&text)?)
+                .into_iter()
+                .map(|v| (None, v))
+                .collect()
+        }
+    };
+
+    if matches.is_present("json") {
+        let results = results
+            .into_iter()
+            .map(|(asset, v)| match asset {
+                Some(asset) => json!({"path": asset, "value": text::to_json(&v)}),
+                None => text::to_json(&v),
+            })
+            .collect();
+        print_json(&serde_json::Value::Array(results));
+    } else {
+        for (asset, v) in results {
+            let v = serde_json::to_string(&text::to_json(&v))?;
+            match asset {
+                Some(asset) => println!("{}: {}", asset, v),
+                None => println!("{}", v),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn render(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let world_path = matches.value_of("world").unwrap();
+    let world = World::new(&fs::File::open(world_path)?)?;
+
+    let mut colors = Palette::default();
+    if let Some(packed) = matches.value_of("packed") {
+        colors = Palette::from_assets(&parse_packed(packed)?)?;
+    }
+    if let Some(palette) = matches.value_of("palette") {
+        let json = serde_json::from_reader(fs::File::open(palette)?)
+            .map_err(|e| format!("{}: {}", palette, e))?;
+        colors.merge(Palette::from_json(&json)?);
+    }
+
+    let rendered = world.render(&colors, &RenderOptions::default())?;
+    let out = match matches.value_of("output") {
+        Some(out) => PathBuf::from(out),
+        None => Path::new(world_path).with_extension("png"),
+    };
+    rendered.image.save(&out)?;
+    println!("wrote {}", out.display());
+    for (_, e) in &rendered.errors {
+        eprintln!("{}", e);
+    }
+    if rendered.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} sectors could not be read", rendered.errors.len()).into())
+    }
+}
+
+fn pak_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("pak")
+        .required(true)
+        .help("packed assets file")
+}
+
+fn json_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("json")
+        .long("json")
+        .help("print machine readable json")
+}
+
+fn filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("pattern")
+            .multiple(true)
+            .help("only assets matching these globs, e.g. '*.png' or '/items/**'"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .short("x")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("skip assets matching this glob"),
+    ]
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    app_from_crate!()
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("list the assets in a pak")
+                .arg(pak_arg())
+                .args(&filter_args())
+                .arg(Arg::with_name("long").short("l").help("show sizes"))
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("write an asset to stdout")
+                .arg(pak_arg())
+                .arg(Arg::with_name("asset").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("extract assets from a pak")
+                .arg(pak_arg())
+                .args(&filter_args())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .takes_value(true)
+                        .default_value("out")
+                        .help("directory to extract into"),
+                )
+                .arg(
+                    Arg::with_name("skip-unchanged")
+                        .long("skip-unchanged")
+                        .short("u")
+                        .help("leave files that already match the asset alone"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .short("j")
+                        .takes_value(true)
+                        .help("threads to write with, one per cpu by default"),
+                )
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("show a pak's metadata and size")
+                .arg(pak_arg())
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("diff-pak")
+                .about("show the assets added, removed and modified between two paks")
+                .arg(Arg::with_name("old").required(true).help("old pak"))
+                .arg(Arg::with_name("new").required(true).help("new pak"))
+                .args(&filter_args())
+                .arg(
+                    Arg::with_name("semantic")
+                        .long("semantic")
+                        .short("s")
+                        .help("show what changed inside modified json assets"),
+                )
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("check a pak for corruption")
+                .arg(pak_arg())
+                .arg(json_arg()),
         )
-        .arg(
-            Arg::with_name("player")
-                .short("p")
-                .takes_value(true)
-                .help("extract player mode"),
+        .subcommand(
+            SubCommand::with_name("grep")
+                .about("search the assets of a pak as text or json")
+                .arg(pak_arg())
+                .arg(
+                    Arg::with_name("search")
+                        .required(true)
+                        .help("pattern to search for, or a key or pointer with --key or --pointer"),
+                )
+                .args(&filter_args())
+                .arg(
+                    Arg::with_name("fixed")
+                        .long("fixed")
+                        .short("F")
+                        .help("search for these exact bytes"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .conflicts_with_all(&["fixed", "value", "pointer"])
+                        .help("find json objects with this key"),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .long("value")
+                        .conflicts_with_all(&["fixed", "pointer"])
+                        .help("match json values instead of lines"),
+                )
+                .arg(
+                    Arg::with_name("pointer")
+                        .long("pointer")
+                        .conflicts_with("fixed")
+                        .help("find json assets where this pointer resolves"),
+                )
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .long("ignore-case")
+                        .short("i")
+                        .help("match the pattern case-insensitively"),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .long("threads")
+                        .short("j")
+                        .takes_value(true)
+                        .help("threads to search with, one per cpu by default"),
+                )
+                .arg(json_arg()),
         )
-        .group(
-            ArgGroup::with_name("mode")
-                .args(&["assets", "player"])
-                .required(true),
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("pack a directory into a pak")
+                .arg(
+                    Arg::with_name("dir")
+                        .required(true)
+                        .help("directory to pack, with an optional _metadata file"),
+                )
+                .arg(pak_arg().help("pak to write"))
+                .arg(json_arg()),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("player")
+                .about("read and edit player files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("dump")
+                        .about("print a player's content as json")
+                        .arg(Arg::with_name("player").required(true))
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .takes_value(true)
+                                .help("write to a file instead of stdout"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("load")
+                        .about("write a player from json made by dump")
+                        .arg(Arg::with_name("json").required(true))
+                        .arg(
+                            Arg::with_name("original")
+                                .long("original")
+                                .takes_value(true)
+                                .required(true)
+                                .help("player file the json was dumped from"),
+                        )
+                        .arg(
+                            Arg::with_name("player-version")
+                                .long("player-version")
+                                .takes_value(true)
+                                .help("version to write, defaults to the original's"),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .takes_value(true)
+                                .help("player to write, defaults to the json path"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("diff")
+                        .about("show what changed between two player files")
+                        .arg(Arg::with_name("a").required(true).help("old player"))
+                        .arg(Arg::with_name("b").required(true).help("new player"))
+                        .arg(json_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("print the value at a json pointer, e.g. /identity/name")
+                        .arg(Arg::with_name("player").required(true))
+                        .arg(Arg::with_name("pointer").required(true))
+                        .arg(
+                            Arg::with_name("raw")
+                                .long("raw")
+                                .help("print strings without quotes"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("set, append to or delete the value at a json pointer")
+                        .arg(Arg::with_name("player").required(true))
+                        .arg(
+                            Arg::with_name("pointer")
+                                .required(true)
+                                .help("where to set, /array/- appends"),
+                        )
+                        .arg(
+                            Arg::with_name("value")
+                                .required_unless("delete")
+                                .help("the new value, read as --type or guessed from its form"),
+                        )
+                        .arg(
+                            Arg::with_name("type")
+                                .long("type")
+                                .short("t")
+                                .takes_value(true)
+                                .possible_values(&["int", "float", "bool", "string", "json"]),
+                        )
+                        .arg(
+                            Arg::with_name("append")
+                                .long("append")
+                                .help("append the value to the array at the pointer"),
+                        )
+                        .arg(
+                            Arg::with_name("delete")
+                                .long("delete")
+                                .conflicts_with_all(&["value", "append", "type"])
+                                .help("remove the value at the pointer"),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .short("o")
+                                .takes_value(true)
+                                .help("write to another file instead"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("run a jq-style query over a pak, player or json file")
+                .arg(
+                    Arg::with_name("query")
+                        .required(true)
+                        .help("e.g. '..|select(.rarity == \"legendary\")|.itemName'"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .help("pak, versioned json (e.g. a .player) or json file"),
+                )
+                .args(&filter_args())
+                .arg(json_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("render a world to png")
+                .arg(Arg::with_name("world").required(true))
+                .arg(
+                    Arg::with_name("packed")
+                        .long("packed")
+                        .takes_value(true)
+                        .help("assets file to take material colors from"),
+                )
+                .arg(
+                    Arg::with_name("palette")
+                        .long("palette")
+                        .takes_value(true)
+                        .help("json palette of material and liquid colors"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("output png path"),
+                ),
+        )
+}
 
-    if matches.is_present("assets") {
-        extract_assets(matches.value_of("assets").unwrap());
-    } else {
-        extract_player(matches.value_of("player").unwrap())
+fn main() {
+    let matches = match app().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e) if e.kind == ErrorKind::HelpDisplayed || e.kind == ErrorKind::VersionDisplayed => {
+            e.exit()
+        }
+        Err(e) => {
+            eprintln!("{}", e.message);
+            exit(EXIT_USAGE);
+        }
+    };
+
+    let result = match matches.subcommand() {
+        ("list", Some(m)) => list(m),
+        ("cat", Some(m)) => cat(m),
+        ("extract", Some(m)) => extract(m),
+        ("info", Some(m)) => info(m),
+        ("diff-pak", Some(m)) => diff_pak(m),
+        ("verify", Some(m)) => verify(m),
+        ("grep", Some(m)) => grep(m),
+        ("pack", Some(m)) => pack(m),
+        ("player", Some(m)) => match m.subcommand() {
+            ("dump", Some(m)) => player_dump(m),
+            ("load", Some(m)) => player_load(m),
+            ("diff", Some(m)) => player_diff(m),
+            ("get", Some(m)) => player_get(m),
+            ("set", Some(m)) => player_set(m),
+            _ => unreachable!(),
+        },
+        ("query", Some(m)) => query(m),
+        ("render", Some(m)) => render(m),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(EXIT_FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(literal("12", None).unwrap(), Value::Integer(12));
+        assert_eq!(literal("-1.5e3", None).unwrap(), Value::Float(-1500.0));
+        assert_eq!(literal("true", None).unwrap(), Value::Boolean(true));
+        for s in &["Nan", "NaN", "inf", "-Infinity", "e"] {
+            assert_eq!(literal(s, None).unwrap(), Value::String(s.to_string()));
+        }
+        assert!(
+            matches!(literal("inf", Some("float")).unwrap(), Value::Float(f) if f.is_infinite())
+        );
+
+        let content = text::from_str(r#"{"health": 50.5, "level": 3}"#).unwrap();
+        let set = |pointer, s, kind| replacement(&content, pointer, s, kind).unwrap();
+        assert_eq!(set("/health", "100", None), Value::Float(100.0));
+        assert_eq!(set("/health", "100", Some("int")), Value::Integer(100));
+        assert_eq!(set("/level", "4", None), Value::Integer(4));
+        assert_eq!(set("/new", "4", None), Value::Integer(4));
     }
 }
@@ -1,13 +1,13 @@
 use crate::bson::{
     parse_bson, parse_maybe_u32, parse_object, serializer as bson_serializer, Map, Value,
 };
-use crate::json::utf8;
-use crate::vlq::read_vlqu64;
+use crate::json::{document, utf8};
+use crate::vlq::{read_vlqu64, write_vlqu64};
 use byteorder::{BigEndian, WriteBytesExt};
 use memmap::Mmap;
 use nom::{
     bytes::complete::tag,
-    combinator::map,
+    combinator::{map, verify},
     error::{context, ParseError, VerboseError},
     multi::{length_value, many_m_n},
     number::complete::{be_i64, be_u64},
@@ -16,9 +16,14 @@ use nom::{
 };
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 type Directory = BTreeMap<String, (i64, i64)>;
 pub type Metadata = Map;
@@ -29,7 +34,9 @@ pub struct Index {
     dir: Directory,
 }
 
-fn parse_metadata<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Metadata, E> {
+pub(crate) fn parse_metadata<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], Metadata, E> {
     context(
         "metadata",
         map(parse_object, |o| match o {
@@ -39,7 +46,7 @@ fn parse_metadata<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8],
     )(i)
 }
 
-fn string<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String, E> {
+pub(crate) fn string<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String, E> {
     context(
         "string",
         map(length_value(map(read_vlqu64, |v| v as usize), utf8), |s| {
@@ -50,7 +57,12 @@ fn string<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String,
 
 fn parse_directory<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Directory, E> {
     let entry = context("entry", pair(string, pair(be_i64, be_i64)));
-    let (i, n) = read_vlqu64(i)?;
+    // many_m_n allocates for all n entries up front, so don't trust a count
+    // the rest of the file can't hold
+    let (i, n) = context(
+        "directory size",
+        verify(read_vlqu64, |&n| n <= i.len() as u64 / 17),
+    )(i)?;
     context(
         "directory",
         map(many_m_n(n as usize, n as usize, entry), |tuples| {
@@ -84,7 +96,7 @@ impl std::fmt::Debug for NomError {
 
 impl Error for NomError {}
 
-fn render_nom_error<'a, O>(
+pub(crate) fn render_nom_error<'a, O>(
     i: &'a [u8],
     r: IResult<&'a [u8], O, VerboseError<&'a [u8]>>,
 ) -> Result<(&'a [u8], O), Box<dyn Error>> {
@@ -105,19 +117,19 @@ fn render_nom_error<'a, O>(
                                     "While parsing {context} at offset {offset}:\n{hex}\n",
                                     context = ctx,
                                     offset = i.offset(start),
-                                    hex = &start[..20].to_hex(10)
+                                    hex = &start[..std::cmp::min(20, start.len())].to_hex(10)
                                 ),
                                 nom::error::VerboseErrorKind::Char(c) => format!(
                                     "Expected char {c} at {offset}:\n{hex}\n",
                                     c = c,
                                     offset = i.offset(start),
-                                    hex = &start[..20].to_hex(10)
+                                    hex = &start[..std::cmp::min(20, start.len())].to_hex(10)
                                 ),
                                 nom::error::VerboseErrorKind::Nom(kind) => format!(
                                     "Parser error ({kind}) at offset {offset}:\n{hex}\n",
                                     kind = kind.description(),
                                     offset = i.offset(start),
-                                    hex = &start[..20].to_hex(10)
+                                    hex = &start[..std::cmp::min(20, start.len())].to_hex(10)
                                 ),
                             }
                         })
@@ -129,8 +141,35 @@ fn render_nom_error<'a, O>(
     })
 }
 
+/// Why an asset could not be read from a pak.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AssetError {
+    NotFound(String),
+    /// The directory entry points outside the file.
+    BadRange {
+        path: String,
+        offset: i64,
+        len: i64,
+    },
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetError::NotFound(path) => write!(f, "asset not found: {}", path),
+            AssetError::BadRange { path, offset, len } => write!(
+                f,
+                "{} has a bad range: {} bytes at offset {}",
+                path, len, offset
+            ),
+        }
+    }
+}
+
+impl Error for AssetError {}
+
 pub struct PackedAssets {
-    map: Mmap,
+    pub(crate) map: Mmap,
     index: Index,
 }
 
@@ -139,8 +178,11 @@ impl PackedAssets {
         let map = unsafe { Mmap::map(f)? };
         let hdr = tag("SBAsset6");
 
-        let (_, (_, _idx_off)) = render_nom_error(&map, tuple((hdr, be_u64))(&map))?;
-        let metadata_start = &map[(_idx_off as usize)..];
+        let (_, (_, idx_off)) = render_nom_error(&map, tuple((hdr, be_u64))(&map))?;
+        let metadata_start = usize::try_from(idx_off)
+            .ok()
+            .and_then(|off| map.get(off..))
+            .ok_or_else(|| format!("index offset {} is past the end of the file", idx_off))?;
         let (_, index) = render_nom_error(&map, parse_index(metadata_start))?;
         Ok(Self { map, index })
     }
@@ -153,11 +195,126 @@ impl PackedAssets {
         self.index.meta.clone()
     }
 
-    pub fn file<'a>(&'a self, path: &str) -> Option<&'a [u8]> {
-        let (start, len) = self.index.dir.get(path).copied()?;
-        let (start, len) = (start as usize, len as usize);
-        Some(&self.map[start..][..len])
+    pub fn contains(&self, path: &str) -> bool {
+        self.index.dir.contains_key(path)
+    }
+
+    pub fn file<'a>(&'a self, path: &str) -> Result<&'a [u8], AssetError> {
+        let (offset, len) = self
+            .index
+            .dir
+            .get(path)
+            .copied()
+            .ok_or_else(|| AssetError::NotFound(path.to_string()))?;
+        let range = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(len).ok())
+            .and_then(|(start, len)| Some(start..start.checked_add(len)?));
+        range
+            .and_then(|r| self.map.get(r))
+            .ok_or_else(|| AssetError::BadRange {
+                path: path.to_string(),
+                offset,
+                len,
+            })
+    }
+
+    pub fn assets_with_extension(&self, ext: &str) -> Vec<&str> {
+        self.index
+            .dir
+            .keys()
+            .filter(|path| Path::new(path).extension() == Some(OsStr::new(ext)))
+            .map(|s| s.as_str())
+            .collect()
+    }
+
+    pub fn json(&self, path: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+        let bytes = self.file(path)?;
+        let (_, value) = render_nom_error(bytes, document(bytes))
+            .map_err(|e| format!("could not parse {}: {}", path, e))?;
+        Ok(value)
+    }
+}
+
+/// Writes a pak holding `files`, pairs of asset path and the file on disk
+/// to take its contents from.
+pub fn write_packed_assets<W: Write + Seek>(
+    mut w: W,
+    metadata: &Metadata,
+    files: &[(String, PathBuf)],
+) -> Result<(), Box<dyn Error>> {
+    w.write_all(b"SBAsset6")?;
+    w.write_u64::<BigEndian>(0)?;
+    let mut offset = 16;
+    let mut dir = Vec::new();
+    for (asset, path) in files {
+        let len = std::io::copy(
+            &mut File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+            &mut w,
+        )?;
+        dir.push((asset, offset, len));
+        offset += len;
+    }
+
+    w.write_all(b"INDEX")?;
+    let mut meta = Vec::new();
+    bson_serializer::to_writer(&mut meta, &Value::Object(metadata.clone()))?;
+    w.write_all(&meta[1..])?;
+    write_vlqu64(&mut w, dir.len() as u64)?;
+    for (asset, start, len) in dir {
+        write_vlqu64(&mut w, asset.len() as u64)?;
+        w.write_all(asset.as_bytes())?;
+        w.write_i64::<BigEndian>(start as i64)?;
+        w.write_i64::<BigEndian>(len as i64)?;
+    }
+    w.seek(SeekFrom::Start(8))?;
+    w.write_u64::<BigEndian>(offset)?;
+    w.flush()?;
+    Ok(())
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Packs every file under `dir` the way the game's asset packer does, with
+/// `dir/_metadata`, if there is one, as the pak's metadata. Returns the
+/// number of assets written.
+pub fn pack_directory<W: Write + Seek>(w: W, dir: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut paths = Vec::new();
+    walk(dir, &mut paths)?;
+    let mut metadata = Metadata::new();
+    let mut files = Vec::new();
+    for path in paths {
+        let relative = path.strip_prefix(dir)?;
+        if relative == Path::new("_metadata") {
+            let bytes = std::fs::read(&path)?;
+            let (_, json) = render_nom_error(&bytes, document(&bytes))
+                .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+            match serde_json::from_value(json)? {
+                Value::Object(o) => metadata = o,
+                _ => return Err(format!("{} is not an object", path.display()).into()),
+            }
+            continue;
+        }
+        let asset = relative
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("{} is not valid UTF-8", path.display()))?;
+        files.push((format!("/{}", asset.join("/")), path));
     }
+    files.sort();
+    write_packed_assets(w, &metadata, &files)?;
+    Ok(files.len())
 }
 
 #[derive(Clone, Debug, PartialOrd, PartialEq, Serialize, Deserialize)]
@@ -167,7 +324,7 @@ pub struct VersionedJson {
     pub content: Value,
 }
 
-fn parse_versioned_json<'a, E: ParseError<&'a [u8]>>(
+pub(crate) fn parse_versioned_json<'a, E: ParseError<&'a [u8]>>(
     i: &'a [u8],
 ) -> IResult<&'a [u8], VersionedJson, E> {
     let (i, identifier) = string(i)?;
@@ -183,35 +340,81 @@ fn parse_versioned_json<'a, E: ParseError<&'a [u8]>>(
     ))
 }
 
+/// The file a player was read from, as it was when read.
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+pub struct LoadedFrom {
+    pub path: PathBuf,
+    pub modified: Option<SystemTime>,
+    pub len: u64,
+}
+
+impl LoadedFrom {
+    pub(crate) fn new(path: &Path, meta: &std::fs::Metadata) -> Self {
+        LoadedFrom {
+            path: path.to_path_buf(),
+            modified: meta.modified().ok(),
+            len: meta.len(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialOrd, PartialEq, Serialize, Deserialize)]
 pub struct Player {
     pub contents: VersionedJson,
+    #[serde(skip)]
+    pub source: Option<LoadedFrom>,
+}
+
+/// Reads a standalone `SBVJ01` file such as a `.player` or `.system`.
+pub fn load_versioned_json(f: &File) -> Result<VersionedJson, Box<dyn Error>> {
+    let map = unsafe { Mmap::map(f)? };
+
+    let (i, _) = render_nom_error(&map, tag("SBVJ01")(&map))?;
+    let (_, json) = render_nom_error(&map, parse_versioned_json(i))?;
+    Ok(json)
 }
 
 impl Player {
     pub fn new(f: &File) -> Result<Player, Box<dyn Error>> {
-        let map = unsafe { Mmap::map(f)? };
-
-        let (i, _) = render_nom_error(&map, tag("SBVJ01")(&map))?;
-        let (_, player) = render_nom_error(&map, parse_versioned_json(i))?;
+        Ok(Player {
+            contents: load_versioned_json(f)?,
+            source: None,
+        })
+    }
 
-        Ok(Player { contents: player })
+    /// Reads a player, remembering the file so `save_to` can tell whether
+    /// something else has changed it since.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Player, Box<dyn Error>> {
+        let path = path.as_ref();
+        let f = File::open(path)?;
+        let mut player = Player::new(&f)?;
+        player.source = Some(LoadedFrom::new(path, &f.metadata()?));
+        Ok(player)
     }
 }
 
+/// Writes `json` the way it appears inside other structures: identifier,
+/// optional version and content, without the file magic.
+pub(crate) fn write_versioned_json(
+    out: &mut Vec<u8>,
+    json: &VersionedJson,
+) -> Result<(), crate::bson::serializer::Error> {
+    let mut buf = Vec::new();
+    bson_serializer::to_writer(&mut buf, &json.identifier)?;
+    out.extend_from_slice(&buf[1..]);
+    out.push(b'\x01');
+    out.write_u32::<BigEndian>(json.version)
+        .map_err(|e| crate::bson::serializer::Error(e.to_string()))?;
+    bson_serializer::to_writer(out, &json.content)
+}
+
 pub fn save_versioned_json(
     json: &mut VersionedJson,
 ) -> Result<Vec<u8>, Arc<Mutex<crate::bson::serializer::Error>>> {
     json.version += 1;
     let mut out = Vec::new();
     out.extend_from_slice("SBVJ01".as_bytes());
-    let mut buf = Vec::new();
-    bson_serializer::to_writer(&mut buf, &json.identifier).map_err(|e| Arc::new(Mutex::new(e)))?;
-    out.extend_from_slice(&buf[1..]);
-    out.push(b'\x01');
-    out.write_u32::<BigEndian>(json.version)
-        .map_err(|e| Arc::new(Mutex::new(crate::bson::serializer::Error(e.to_string()))))?;
-    bson_serializer::to_writer(&mut out, &json.content).map_err(|e| Arc::new(Mutex::new(e)))?;
+    write_versioned_json(&mut out, json).map_err(|e| Arc::new(Mutex::new(e)))?;
     Ok(out)
 }
 
@@ -227,4 +430,33 @@ mod tests {
         .unwrap();
         let bytes = save_versioned_json(&mut p.contents);
     }
+
+    #[test]
+    fn test_pack() {
+        let dir = std::env::temp_dir().join(format!("pack-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("items/bars")).unwrap();
+        std::fs::write(
+            dir.join("_metadata"),
+            r#"{"name": "mod", /* x */ "priority": 1}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("items/bars/iron.item"), "{}").unwrap();
+        std::fs::write(dir.join("player.config.patch"), "[]").unwrap();
+
+        let out = dir.with_extension("pak");
+        let n = pack_directory(File::create(&out).unwrap(), &dir).unwrap();
+        assert_eq!(n, 2);
+        let assets = PackedAssets::new(&File::open(&out).unwrap()).unwrap();
+        assert_eq!(
+            assets.assets(),
+            vec!["/items/bars/iron.item", "/player.config.patch"]
+        );
+        assert_eq!(assets.file("/player.config.patch"), Ok(&b"[]"[..]));
+        assert_eq!(
+            assets.metadata().get("name"),
+            Some(&Value::String("mod".to_string()))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&out).unwrap();
+    }
 }
@@ -0,0 +1,354 @@
+//! What changed between two versions of a pak, e.g. before and after a game
+//! or mod update.
+
+use crate::bson::diff::Change;
+use crate::bson::Value;
+use crate::glob::Filter;
+use crate::packed::{AssetError, PackedAssets};
+use std::error::Error;
+
+/// A 64-bit FNV-1a hash, enough to tell versions of an asset apart in a
+/// report and stable across runs and platforms.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetChange {
+    Added {
+        path: String,
+        hash: u64,
+        len: usize,
+    },
+    Removed {
+        path: String,
+        hash: u64,
+        len: usize,
+    },
+    Modified {
+        path: String,
+        old_hash: u64,
+        new_hash: u64,
+        old_len: usize,
+        new_len: usize,
+        /// How the JSON changed, if asked for and both versions parse. Empty
+        /// if only the formatting changed.
+        json: Option<Vec<Change>>,
+    },
+}
+
+impl AssetChange {
+    pub fn path(&self) -> &str {
+        match self {
+            AssetChange::Added { path, .. }
+            | AssetChange::Removed { path, .. }
+            | AssetChange::Modified { path, .. } => path,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::json;
+        let hex = |h: &u64| format!("{:016x}", h);
+        match self {
+            AssetChange::Added { path, hash, len } => {
+                json!({"op": "added", "path": path, "hash": hex(hash), "size": len})
+            }
+            AssetChange::Removed { path, hash, len } => {
+                json!({"op": "removed", "path": path, "hash": hex(hash), "size": len})
+            }
+            AssetChange::Modified {
+                path,
+                old_hash,
+                new_hash,
+                old_len,
+                new_len,
+                json,
+            } => {
+                let mut v = json!({
+                    "op": "modified",
+                    "path": path,
+                    "from": {"hash": hex(old_hash), "size": old_len},
+                    "to": {"hash": hex(new_hash), "size": new_len},
+                });
+                if let Some(changes) = json {
+                    v["changes"] = changes.iter().map(Change::to_json).collect();
+                }
+                v
+            }
+        }
+    }
+}
+
+/// `A`, `D` or `M` and the path, followed by any JSON changes indented on
+/// the lines below.
+impl std::fmt::Display for AssetChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetChange::Added { path, .. } => write!(f, "A {}", path),
+            AssetChange::Removed { path, .. } => write!(f, "D {}", path),
+            AssetChange::Modified {
+                path,
+                old_len,
+                new_len,
+                json,
+                ..
+            } => {
+                write!(f, "M {} ({} -> {} bytes)", path, old_len, new_len)?;
+                match json {
+                    Some(changes) if changes.is_empty() => write!(f, "\n    formatting only"),
+                    Some(changes) => changes.iter().try_for_each(|c| write!(f, "\n    {}", c)),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PakDiff {
+    pub metadata: Vec<Change>,
+    /// In path order.
+    pub assets: Vec<AssetChange>,
+    /// Assets that couldn't be read from either pak, and so are left out of
+    /// `assets`.
+    pub errors: Vec<AssetError>,
+}
+
+impl PakDiff {
+    pub fn is_empty(&self) -> bool {
+        self.metadata.is_empty() && self.assets.is_empty() && self.errors.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiffOptions {
+    /// Only compare assets passing this filter.
+    pub filter: Filter,
+    /// Also diff the contents of modified JSON assets.
+    pub json: bool,
+}
+
+fn json_changes(old: &PackedAssets, new: &PackedAssets, path: &str) -> Option<Vec<Change>> {
+    let old = Value::from(old.json(path).ok()?);
+    let new = Value::from(new.json(path).ok()?);
+    Some(old.diff(&new))
+}
+
+fn file<'a>(assets: &'a PackedAssets, path: &str) -> Result<(&'a [u8], u64, usize), AssetError> {
+    let bytes = assets.file(path)?;
+    Ok((bytes, content_hash(bytes), bytes.len()))
+}
+
+fn compare(
+    old: &PackedAssets,
+    new: &PackedAssets,
+    path: &str,
+    options: &DiffOptions,
+) -> Result<Option<AssetChange>, AssetError> {
+    let change = match (old.contains(path), new.contains(path)) {
+        (true, false) => {
+            let (_, hash, len) = file(old, path)?;
+            let path = path.to_string();
+            AssetChange::Removed { path, hash, len }
+        }
+        (false, true) => {
+            let (_, hash, len) = file(new, path)?;
+            let path = path.to_string();
+            AssetChange::Added { path, hash, len }
+        }
+        _ => {
+            let (old_bytes, old_hash, old_len) = file(old, path)?;
+            let (new_bytes, new_hash, new_len) = file(new, path)?;
+            if old_bytes == new_bytes {
+                return Ok(None);
+            }
+            AssetChange::Modified {
+                path: path.to_string(),
+                old_hash,
+                new_hash,
+                old_len,
+                new_len,
+                json: if options.json {
+                    json_changes(old, new, path)
+                } else {
+                    None
+                },
+            }
+        }
+    };
+    Ok(Some(change))
+}
+
+impl PackedAssets {
+    /// Everything that changed going from this pak to `new`. Assets that
+    /// can't be read are skipped and reported in `errors`.
+    pub fn diff(
+        &self,
+        new: &PackedAssets,
+        options: &DiffOptions,
+    ) -> Result<PakDiff, Box<dyn Error>> {
+        let metadata = Value::Object(self.metadata()).diff(&Value::Object(new.metadata()));
+        let mut paths: Vec<_> = self.assets().into_iter().chain(new.assets()).collect();
+        paths.sort_unstable();
+        paths.dedup();
+        let mut assets = Vec::new();
+        let mut errors = Vec::new();
+        for path in paths.into_iter().filter(|p| options.filter.matches(p)) {
+            match compare(self, new, path, options) {
+                Ok(change) => assets.extend(change),
+                Err(e) => errors.push(e),
+            }
+        }
+        Ok(PakDiff {
+            metadata,
+            assets,
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob::Glob;
+    use crate::pack_directory;
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    /// A pak of `files`, with the directory entry of `bad` pointing past the
+    /// end of it.
+    fn pak(name: &str, files: &[(&str, &str)], bad: Option<&str>) -> PackedAssets {
+        let dir = std::env::temp_dir().join(format!("pakdiff-{}-{}", std::process::id(), name));
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let out = dir.with_extension("pak");
+        pack_directory(File::create(&out).unwrap(), Path::new(&dir)).unwrap();
+        if let Some(bad) = bad {
+            let mut pak = fs::read(&out).unwrap();
+            let entry = pak
+                .windows(bad.len())
+                .rposition(|w| w == bad.as_bytes())
+                .unwrap()
+                + bad.len();
+            pak[entry + 8..entry + 16].copy_from_slice(&i64::MAX.to_be_bytes());
+            fs::write(&out, pak).unwrap();
+        }
+        let assets = PackedAssets::new(&File::open(&out).unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&out).unwrap();
+        assets
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = pak(
+            "old",
+            &[
+                ("_metadata", r#"{"version": "1.0"}"#),
+                ("a.item", r#"{"price": 10, "name": "a"}"#),
+                ("b.item", r#"{"price": 5}"#),
+                ("c.item", "{}"),
+                ("d.png", "PNG1"),
+                ("gone.lua", "x = 1"),
+            ],
+            None,
+        );
+        let new = pak(
+            "new",
+            &[
+                ("_metadata", r#"{"version": "1.1"}"#),
+                ("a.item", r#"{"price": 12, "name": "a"}"#),
+                ("b.item", "{\n  \"price\": 5\n}"),
+                ("c.item", "{}"),
+                ("d.png", "PNG2"),
+                ("new.lua", "y = 2"),
+            ],
+            None,
+        );
+
+        let options = DiffOptions {
+            json: true,
+            ..Default::default()
+        };
+        let diff = old.diff(&new, &options).unwrap();
+        assert_eq!(
+            diff.metadata
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec!["~ /version: \"1.0\" -> \"1.1\""]
+        );
+        assert_eq!(
+            diff.assets
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "M /a.item (26 -> 26 bytes)\n    ~ /price: 10 -> 12",
+                "M /b.item (12 -> 16 bytes)\n    formatting only",
+                "M /d.png (4 -> 4 bytes)",
+                "D /gone.lua",
+                "A /new.lua",
+            ]
+        );
+        assert_eq!(
+            diff.assets[3].to_json(),
+            serde_json::json!({
+                "op": "removed",
+                "path": "/gone.lua",
+                "hash": format!("{:016x}", content_hash(b"x = 1")),
+                "size": 5,
+            })
+        );
+
+        let options = DiffOptions {
+            filter: Filter {
+                include: vec![Glob::new("*.item")],
+                exclude: vec![],
+            },
+            json: false,
+        };
+        let diff = old.diff(&new, &options).unwrap();
+        assert_eq!(
+            diff.assets
+                .iter()
+                .map(AssetChange::path)
+                .collect::<Vec<_>>(),
+            vec!["/a.item", "/b.item"]
+        );
+        assert!(old.diff(&old, &options).unwrap().is_empty());
+
+        let broken = pak(
+            "broken",
+            &[
+                ("a.item", r#"{"price": 12, "name": "a"}"#),
+                ("d.png", "PNG1"),
+            ],
+            Some("/d.png"),
+        );
+        let diff = old.diff(&broken, &DiffOptions::default()).unwrap();
+        assert_eq!(
+            diff.assets
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "M /a.item (26 -> 26 bytes)",
+                "D /b.item",
+                "D /c.item",
+                "D /gone.lua",
+            ]
+        );
+        assert!(matches!(
+            &diff.errors[..],
+            [AssetError::BadRange { path, .. }] if path == "/d.png"
+        ));
+        assert_eq!(content_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
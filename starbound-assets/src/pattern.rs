@@ -0,0 +1,532 @@
+//! Regular expressions, enough for searching assets: literals, `.`, classes
+//! like `[a-z_]` and `[^0-9]`, `\d`, `\w`, `\s` and their negations, `*`,
+//! `+`, `?`, `{n}`, `{n,}`, `{n,m}`, groups, `|`, `^` and `$`. Matching
+//! takes time linear in the text, however long its lines.
+
+use std::error::Error;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Char(char),
+    Any,
+    /// Inclusive ranges, and whether the class is negated.
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+/// The least and most times to repeat.
+type Repeat = (usize, Option<usize>);
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: &[(char, char)] = &[(' ', ' '), ('\t', '\r')];
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> Box<dyn Error> {
+        format!(
+            "invalid pattern {:?} at {}: {}",
+            self.pattern, self.pos, message
+        )
+        .into()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, Box<dyn Error>> {
+        let mut alts = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, Box<dyn Error>> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let mut node = self.atom()?;
+            while let Some((min, max)) = self.quantifier()? {
+                node = Node::Repeat(Box::new(node), min, max);
+            }
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn quantifier(&mut self) -> Result<Option<Repeat>, Box<dyn Error>> {
+        let q = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self
+                    .number()
+                    .ok_or_else(|| self.error("expected a count"))?;
+                let max = match self.next() {
+                    Some('}') => return Ok(Some((min, Some(min)))),
+                    Some(',') if self.peek() == Some('}') => None,
+                    Some(',') => Some(
+                        self.number()
+                            .ok_or_else(|| self.error("expected a count"))?,
+                    ),
+                    _ => return Err(self.error("expected , or }")),
+                };
+                if self.next() != Some('}') {
+                    return Err(self.error("expected }"));
+                }
+                if matches!(max, Some(max) if max < min) {
+                    return Err(self.error("repeat range is backwards"));
+                }
+                return Ok(Some((min, max)));
+            }
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+        Ok(Some(q))
+    }
+
+    fn escape(&mut self) -> Result<Node, Box<dyn Error>> {
+        let class = |ranges: &[(char, char)], negated| Node::Class(ranges.to_vec(), negated);
+        Ok(
+            match self.next().ok_or_else(|| self.error("trailing \\"))? {
+                'd' => class(DIGIT, false),
+                'D' => class(DIGIT, true),
+                'w' => class(WORD, false),
+                'W' => class(WORD, true),
+                's' => class(SPACE, false),
+                'S' => class(SPACE, true),
+                'n' => Node::Char('\n'),
+                'r' => Node::Char('\r'),
+                't' => Node::Char('\t'),
+                c => Node::Char(c),
+            },
+        )
+    }
+
+    fn class(&mut self) -> Result<Node, Box<dyn Error>> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                None => return Err(self.error("unclosed [")),
+                Some(']') if !first => break,
+                Some('\\') => match self.escape()? {
+                    Node::Char(c) => c,
+                    Node::Class(r, false) => {
+                        ranges.extend(r);
+                        continue;
+                    }
+                    _ => return Err(self.error("negated classes can't go in [...]")),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            if self.peek() == Some('-')
+                && matches!(self.chars.get(self.pos + 1), Some(&c) if c != ']')
+            {
+                self.pos += 1;
+                let end = match self.next() {
+                    Some('\\') => match self.escape()? {
+                        Node::Char(c) => c,
+                        _ => return Err(self.error("bad range end")),
+                    },
+                    Some(c) => c,
+                    None => return Err(self.error("unclosed [")),
+                };
+                if end < c {
+                    return Err(self.error("range is backwards"));
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn atom(&mut self) -> Result<Node, Box<dyn Error>> {
+        Ok(match self.next() {
+            Some('(') => {
+                let alts = self.alternatives()?;
+                if self.next() != Some(')') {
+                    return Err(self.error("unclosed ("));
+                }
+                Node::Group(alts)
+            }
+            Some('[') => self.class()?,
+            Some('.') => Node::Any,
+            Some('^') => Node::Start,
+            Some('$') => Node::End,
+            Some('\\') => self.escape()?,
+            Some('*') | Some('+') | Some('?') | Some('{') => {
+                self.pos -= 1;
+                return Err(self.error("nothing to repeat"));
+            }
+            Some(c) => Node::Char(c),
+            None => unreachable!(),
+        })
+    }
+}
+
+/// The compiled form of a pattern: instructions for a Pike VM, which runs
+/// every possible match at once in a single pass over the text.
+#[derive(Clone, Debug, PartialEq)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    /// Try both, preferring the first.
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+/// Counted repeats copy their operand, so this keeps e.g. `(a{100}){100}`
+/// from taking all of memory.
+const MAX_PROGRAM: usize = 1 << 16;
+
+struct Compiler {
+    prog: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, Box<dyn Error>> {
+        if self.prog.len() >= MAX_PROGRAM {
+            return Err("pattern is too big".into());
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    /// Sets the targets of the split or jump at `at`.
+    fn patch(&mut self, at: usize, inst: Inst) {
+        self.prog[at] = inst;
+    }
+
+    fn node(&mut self, node: &Node) -> Result<(), Box<dyn Error>> {
+        match node {
+            Node::Char(c) => self.push(Inst::Char(*c)).map(drop),
+            Node::Any => self.push(Inst::Any).map(drop),
+            Node::Class(ranges, negated) => {
+                self.push(Inst::Class(ranges.clone(), *negated)).map(drop)
+            }
+            Node::Start => self.push(Inst::Start).map(drop),
+            Node::End => self.push(Inst::End).map(drop),
+            Node::Group(alts) => {
+                let mut jumps = Vec::new();
+                for (n, seq) in alts.iter().enumerate() {
+                    let split = if n + 1 < alts.len() {
+                        Some(self.push(Inst::Match)?)
+                    } else {
+                        None
+                    };
+                    for node in seq {
+                        self.node(node)?;
+                    }
+                    if let Some(split) = split {
+                        jumps.push(self.push(Inst::Match)?);
+                        let next = self.prog.len();
+                        self.patch(split, Inst::Split(split + 1, next));
+                    }
+                }
+                let end = self.prog.len();
+                for jump in jumps {
+                    self.patch(jump, Inst::Jmp(end));
+                }
+                Ok(())
+            }
+            Node::Repeat(node, min, max) => {
+                for _ in 0..*min {
+                    self.node(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Match)?;
+                        self.node(node)?;
+                        self.push(Inst::Jmp(split))?;
+                        let end = self.prog.len();
+                        self.patch(split, Inst::Split(split + 1, end));
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Match)?);
+                            self.node(node)?;
+                        }
+                        let end = self.prog.len();
+                        for split in splits {
+                            self.patch(split, Inst::Split(split + 1, end));
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The threads alive at one position of the text, in priority order, each
+/// with where its match started.
+struct Threads {
+    threads: Vec<(usize, usize)>,
+    seen: Vec<usize>,
+    generation: usize,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            threads: Vec::new(),
+            seen: vec![0; len],
+            generation: 1,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        self.generation += 1;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    source: String,
+    prog: Vec<Inst>,
+    ignore_case: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, Box<dyn Error>> {
+        let mut parser = Parser {
+            pattern,
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let alts = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched )"));
+        }
+        let mut compiler = Compiler { prog: Vec::new() };
+        compiler
+            .node(&Node::Group(alts))
+            .map_err(|e| format!("invalid pattern {:?}: {}", pattern, e))?;
+        compiler.push(Inst::Match)?;
+        Ok(Pattern {
+            source: pattern.to_string(),
+            prog: compiler.prog,
+            ignore_case: false,
+        })
+    }
+
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    fn single(&self, inst: &Inst, c: char) -> bool {
+        let folded = |c: char| -> Vec<char> {
+            if self.ignore_case {
+                c.to_lowercase().chain(c.to_uppercase()).collect()
+            } else {
+                vec![c]
+            }
+        };
+        match inst {
+            Inst::Any => c != '\n',
+            Inst::Char(p) if self.ignore_case => c.to_lowercase().eq(p.to_lowercase()),
+            Inst::Char(p) => c == *p,
+            Inst::Class(ranges, negated) => {
+                let found = folded(c)
+                    .into_iter()
+                    .any(|c| ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi));
+                found != *negated
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds the thread at `pc` to `list`, following splits and jumps and
+    /// checking anchors at `i`, a position in text `len` characters long.
+    fn add(
+        &self,
+        list: &mut Threads,
+        stack: &mut Vec<usize>,
+        pc: usize,
+        start: usize,
+        i: usize,
+        len: usize,
+    ) {
+        stack.push(pc);
+        while let Some(pc) = stack.pop() {
+            if list.seen[pc] == list.generation {
+                continue;
+            }
+            list.seen[pc] = list.generation;
+            match self.prog[pc] {
+                Inst::Jmp(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Start if i == 0 => stack.push(pc + 1),
+                Inst::End if i == len => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                _ => list.threads.push((pc, start)),
+            }
+        }
+    }
+
+    /// The first match in `text`, as a byte range. The leftmost match wins,
+    /// and from there repeats are greedy and alternatives tried in order.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let chars: Vec<char> = text.chars().collect();
+        let offsets: Vec<usize> = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(Some(text.len()))
+            .collect();
+        let len = chars.len();
+        let mut current = Threads::new(self.prog.len());
+        let mut next = Threads::new(self.prog.len());
+        let mut stack = Vec::new();
+        let mut found = None;
+        self.add(&mut current, &mut stack, 0, 0, 0, len);
+        for i in 0..=len {
+            let c = chars.get(i).copied();
+            next.clear();
+            for &(pc, start) in &current.threads {
+                match &self.prog[pc] {
+                    // Anything after this thread has lower priority
+                    Inst::Match => {
+                        found = Some((start, i));
+                        break;
+                    }
+                    inst => {
+                        if matches!(c, Some(c) if self.single(inst, c)) {
+                            self.add(&mut next, &mut stack, pc + 1, start, i + 1, len);
+                        }
+                    }
+                }
+            }
+            if i == len || (found.is_some() && next.threads.is_empty()) {
+                break;
+            }
+            // Until something matches, a match could also start here
+            if found.is_none() {
+                self.add(&mut next, &mut stack, 0, i + 1, i + 1, len);
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+        found.map(|(start, end)| (offsets[start], offsets[end]))
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.find(text).is_some()
+    }
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pattern::new(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let find = |p: &str, s: &str| Pattern::new(p).unwrap().find(s);
+        assert_eq!(find("bar", "ironbar"), Some((4, 7)));
+        assert_eq!(find("^bar", "ironbar"), None);
+        assert_eq!(find("a.c", "xabc"), Some((1, 4)));
+        assert_eq!(find("[a-c]+", "xxbcaz"), Some((2, 5)));
+        assert_eq!(find("[^a-c]+", "abxyc"), Some((2, 4)));
+        assert_eq!(find(r"\d{2,3}", "a1b2345"), Some((3, 6)));
+        assert_eq!(find(r"\d{2}$", "12a34"), Some((3, 5)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("(iron|gold)bar", "a goldbar"), Some((2, 9)));
+        assert_eq!(find(r"\w+\s*=", "  x_1 = 2"), Some((2, 7)));
+        assert_eq!(find("a*", "bbb"), Some((0, 0)));
+        assert_eq!(find("(a*)*b", "aab"), Some((0, 3)));
+        assert_eq!(find("é+", "caféé!"), Some((3, 7)));
+        assert_eq!(find(r"[\d.]+", "v1.5"), Some((1, 4)));
+        assert_eq!(find(r"\.", "a.b"), Some((1, 2)));
+
+        let p = Pattern::new("legendary").unwrap().ignore_case(true);
+        assert!(p.is_match("rarity: Legendary"));
+        assert!(Pattern::new("[A-Z]")
+            .unwrap()
+            .ignore_case(true)
+            .is_match("x"));
+
+        // No recursion per character, so long lines are fine
+        let line = "a".repeat(100_000);
+        let p = Pattern::new(r"\w+!").unwrap();
+        assert_eq!(p.find(&line), None);
+        assert_eq!(p.find(&format!("{}!", line)), Some((0, 100_001)));
+        assert_eq!(find("(a|ab)(c|bcd)", "abcd"), Some((0, 4)));
+        assert_eq!(find("a|b|c", "xc"), Some((1, 2)));
+        assert_eq!(find("$", "ab"), Some((2, 2)));
+        assert_eq!(find("x{2}y?", "axxxy"), Some((1, 3)));
+
+        for bad in &[
+            "(a",
+            "a)",
+            "[a",
+            "*a",
+            "a{2,1}",
+            "[z-a]",
+            "a\\",
+            "(a{1000}){1000}",
+        ] {
+            assert!(Pattern::new(bad).is_err(), "{}", bad);
+        }
+    }
+}
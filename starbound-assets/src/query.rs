@@ -0,0 +1,641 @@
+//! A small jq-like language for picking values out of player content and
+//! asset JSON.
+//!
+//! - `.` is the input, `.name`, `."odd key"`, `.[0]`, `.[-1]` and `.["key"]`
+//!   step into it and `.[]` or `.*` gives every element or value.
+//! - `..` is the input and everything inside it, `..name` is `..|.name`.
+//! - `a | b` runs `b` on each output of `a`, `a, b` gives both.
+//! - `==`, `!=`, `<`, `<=`, `>`, `>=`, `and`, `or` compare and combine.
+//! - `[expr]` collects outputs into an array, `{name, count: .amount}`
+//!   builds objects.
+//! - `select(cond)`, `has(key)`, `contains(x)`, `startswith(s)`,
+//!   `endswith(s)`, `length`, `keys`, `not` and `type` work as in jq, with
+//!   arguments separated by `;`.
+//!
+//! Stepping into something that isn't an object or array gives nothing
+//! rather than an error, so `..|select(.rarity == "legendary")` just works.
+
+use crate::bson::Value;
+use crate::glob::Filter;
+use crate::json::{number_value, string, ws};
+use crate::packed::{render_nom_error, PackedAssets};
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{alpha1, alphanumeric1, digit1},
+    combinator::{all_consuming, cut, map, map_res, not, opt, recognize, value},
+    error::{context, ParseError},
+    multi::{many0, separated_list},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    IResult,
+};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Identity,
+    Recurse,
+    Literal(Value),
+    Key(Box<Expr>, String),
+    Index(Box<Expr>, i64),
+    Iterate(Box<Expr>),
+    Pipe(Box<Expr>, Box<Expr>),
+    Comma(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, Op, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Collect(Option<Box<Expr>>),
+    Object(Vec<(String, Expr)>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Key(String),
+    Index(i64),
+    Iterate,
+}
+
+impl Step {
+    fn apply(self, e: Expr) -> Expr {
+        match self {
+            Step::Key(k) => Expr::Key(Box::new(e), k),
+            Step::Index(n) => Expr::Index(Box::new(e), n),
+            Step::Iterate => Expr::Iterate(Box::new(e)),
+        }
+    }
+}
+
+/// Functions and how many arguments they take.
+const FUNCTIONS: &[(&str, usize)] = &[
+    ("select", 1),
+    ("has", 1),
+    ("contains", 1),
+    ("startswith", 1),
+    ("endswith", 1),
+    ("length", 0),
+    ("keys", 0),
+    ("not", 0),
+    ("type", 0),
+];
+
+fn ident<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String, E> {
+    let name = recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0(alt((alphanumeric1, tag("_")))),
+    ));
+    map(name, |n: &[u8]| String::from_utf8_lossy(n).into_owned())(i)
+}
+
+fn keyword<'a, E: ParseError<&'a [u8]>>(
+    word: &'static str,
+) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
+    ws(terminated(tag(word), not(alt((alphanumeric1, tag("_"))))))
+}
+
+fn quoted<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String, E> {
+    // The JSON string parser leaves escapes alone
+    map_res(string, |s| serde_json::from_str(&format!("\"{}\"", s)))(i)
+}
+
+fn integer<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], i64, E> {
+    map_res(recognize(pair(opt(tag("-")), digit1)), |n: &[u8]| {
+        String::from_utf8_lossy(n).parse()
+    })(i)
+}
+
+fn key_name<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], String, E> {
+    alt((ident, quoted))(i)
+}
+
+fn bracket<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Step, E> {
+    let inner = opt(alt((
+        map(ws(integer), Step::Index),
+        map(ws(quoted), Step::Key),
+    )));
+    context(
+        "brackets",
+        map(delimited(tag("["), inner, cut(tag("]"))), |s| {
+            s.unwrap_or(Step::Iterate)
+        }),
+    )(i)
+}
+
+/// What may follow a `.`.
+fn after_dot<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Step, E> {
+    alt((
+        map(key_name, Step::Key),
+        bracket,
+        value(Step::Iterate, tag("*")),
+    ))(i)
+}
+
+fn step<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Step, E> {
+    terminated(alt((preceded(tag("."), after_dot), bracket)), opt(tag("?")))(i)
+}
+
+fn literal<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Value, E> {
+    alt((
+        value(Value::Boolean(true), keyword("true")),
+        value(Value::Boolean(false), keyword("false")),
+        value(Value::Empty, keyword("null")),
+        map(number_value, Value::from),
+        map(quoted, Value::String),
+    ))(i)
+}
+
+fn field<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], (String, Expr), E> {
+    let explicit = separated_pair(ws(key_name), ws(tag(":")), cut(or_expr));
+    let shorthand = map(ws(key_name), |k| {
+        (k.clone(), Expr::Key(Box::new(Expr::Identity), k))
+    });
+    alt((explicit, shorthand))(i)
+}
+
+fn call<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let args = opt(delimited(
+        ws(tag("(")),
+        separated_list(ws(tag(";")), pipeline),
+        cut(ws(tag(")"))),
+    ));
+    context(
+        "function",
+        map_res(pair(ident, args), |(name, args)| {
+            let args = args.unwrap_or_default();
+            match FUNCTIONS.iter().find(|(f, _)| *f == name) {
+                Some((_, n)) if *n == args.len() => Ok(Expr::Call(name, args)),
+                _ => Err(()),
+            }
+        }),
+    )(i)
+}
+
+fn primary<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let recurse = map(preceded(tag(".."), opt(key_name)), |k| match k {
+        Some(k) => Expr::Key(Box::new(Expr::Recurse), k),
+        None => Expr::Recurse,
+    });
+    let dot = map(preceded(tag("."), opt(after_dot)), |s| match s {
+        Some(s) => s.apply(Expr::Identity),
+        None => Expr::Identity,
+    });
+    let parens = delimited(tag("("), pipeline, cut(ws(tag(")"))));
+    let collect = map(delimited(tag("["), opt(pipeline), cut(ws(tag("]")))), |e| {
+        Expr::Collect(e.map(Box::new))
+    });
+    let object = map(
+        delimited(
+            tag("{"),
+            separated_list(ws(tag(",")), field),
+            cut(ws(tag("}"))),
+        ),
+        Expr::Object,
+    );
+    ws(alt((
+        recurse,
+        dot,
+        map(literal, Expr::Literal),
+        parens,
+        collect,
+        object,
+        call,
+    )))(i)
+}
+
+fn postfix<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let (i, e) = primary(i)?;
+    let (i, steps) = many0(step)(i)?;
+    let (i, _) = ws(opt(tag("?")))(i)?;
+    Ok((i, steps.into_iter().fold(e, |e, s| s.apply(e))))
+}
+
+fn comparison<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let op = ws(alt((
+        value(Op::Eq, tag("==")),
+        value(Op::Ne, tag("!=")),
+        value(Op::Le, tag("<=")),
+        value(Op::Ge, tag(">=")),
+        value(Op::Lt, tag("<")),
+        value(Op::Gt, tag(">")),
+    )));
+    let (i, lhs) = postfix(i)?;
+    let (i, rhs) = opt(pair(op, cut(postfix)))(i)?;
+    Ok((
+        i,
+        match rhs {
+            Some((op, rhs)) => Expr::Compare(Box::new(lhs), op, Box::new(rhs)),
+            None => lhs,
+        },
+    ))
+}
+
+fn and_expr<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let (i, first) = comparison(i)?;
+    let (i, rest) = many0(preceded(keyword("and"), cut(comparison)))(i)?;
+    Ok((
+        i,
+        rest.into_iter()
+            .fold(first, |a, b| Expr::And(Box::new(a), Box::new(b))),
+    ))
+}
+
+fn or_expr<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let (i, first) = and_expr(i)?;
+    let (i, rest) = many0(preceded(keyword("or"), cut(and_expr)))(i)?;
+    Ok((
+        i,
+        rest.into_iter()
+            .fold(first, |a, b| Expr::Or(Box::new(a), Box::new(b))),
+    ))
+}
+
+fn comma<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let (i, first) = or_expr(i)?;
+    let (i, rest) = many0(preceded(ws(tag(",")), cut(or_expr)))(i)?;
+    Ok((
+        i,
+        rest.into_iter()
+            .fold(first, |a, b| Expr::Comma(Box::new(a), Box::new(b))),
+    ))
+}
+
+fn pipeline<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Expr, E> {
+    let (i, first) = comma(i)?;
+    let (i, rest) = many0(preceded(ws(tag("|")), cut(comma)))(i)?;
+    Ok((
+        i,
+        rest.into_iter()
+            .fold(first, |a, b| Expr::Pipe(Box::new(a), Box::new(b))),
+    ))
+}
+
+fn truthy(v: &Value) -> bool {
+    !matches!(v, Value::Empty | Value::Unset | Value::Boolean(false))
+}
+
+/// Integers and floats compare by value; other values only with their own
+/// kind.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+        (a, b) if a.is_null() && b.is_null() => Some(Ordering::Equal),
+        (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+        _ => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ if a == b => Some(Ordering::Equal),
+            _ => None,
+        },
+    }
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((ka, a), (kb, b))| ka == kb && equal(a, b))
+        }
+        _ => compare(a, b) == Some(Ordering::Equal),
+    }
+}
+
+/// As in jq: substrings, every element of an array in some element of the
+/// other, every key of an object contained in the other's value.
+fn contains(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+        (Value::Array(a), Value::Array(b)) => b.iter().all(|b| a.iter().any(|a| contains(a, b))),
+        (Value::Object(a), Value::Object(b)) => b
+            .iter()
+            .all(|(k, b)| matches!(a.get(k), Some(a) if contains(a, b))),
+        (a, b) => equal(a, b),
+    }
+}
+
+fn descendants(v: &Value, out: &mut Vec<Value>) {
+    out.push(v.clone());
+    match v {
+        Value::Array(a) => a.iter().for_each(|v| descendants(v, out)),
+        Value::Object(o) => o.values().for_each(|v| descendants(v, out)),
+        _ => {}
+    }
+}
+
+/// Every combination of one output from each field.
+fn objects(
+    fields: &[(String, Expr)],
+    input: &Value,
+    built: &crate::bson::Map,
+    out: &mut Vec<Value>,
+) {
+    let ((key, expr), rest) = match fields.split_first() {
+        Some(f) => f,
+        None => return out.push(Value::Object(built.clone())),
+    };
+    for v in expr.eval(input) {
+        let mut built = built.clone();
+        built.insert(key.clone(), v);
+        objects(rest, input, &built, out);
+    }
+}
+
+impl Expr {
+    fn eval(&self, input: &Value) -> Vec<Value> {
+        let mut out = Vec::new();
+        match self {
+            Expr::Identity => out.push(input.clone()),
+            Expr::Recurse => descendants(input, &mut out),
+            Expr::Literal(v) => out.push(v.clone()),
+            Expr::Key(e, k) => {
+                for v in e.eval(input) {
+                    if let Value::Object(o) = v {
+                        out.push(o.get(k).cloned().unwrap_or_default());
+                    }
+                }
+            }
+            Expr::Index(e, n) => {
+                for v in e.eval(input) {
+                    if let Value::Array(a) = v {
+                        let i = if *n < 0 { a.len() as i64 + n } else { *n };
+                        let item = usize::try_from(i).ok().and_then(|i| a.get(i));
+                        out.push(item.cloned().unwrap_or_default());
+                    }
+                }
+            }
+            Expr::Iterate(e) => {
+                for v in e.eval(input) {
+                    match v {
+                        Value::Array(a) => out.extend(a),
+                        Value::Object(o) => out.extend(o.into_values()),
+                        _ => {}
+                    }
+                }
+            }
+            Expr::Pipe(a, b) => {
+                for v in a.eval(input) {
+                    out.extend(b.eval(&v));
+                }
+            }
+            Expr::Comma(a, b) => {
+                out.extend(a.eval(input));
+                out.extend(b.eval(input));
+            }
+            Expr::Compare(a, op, b) => {
+                for l in a.eval(input) {
+                    for r in b.eval(input) {
+                        let ord = compare(&l, &r);
+                        out.push(Value::Boolean(match op {
+                            Op::Eq => equal(&l, &r),
+                            Op::Ne => !equal(&l, &r),
+                            Op::Lt => ord == Some(Ordering::Less),
+                            Op::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                            Op::Gt => ord == Some(Ordering::Greater),
+                            Op::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                        }));
+                    }
+                }
+            }
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                let is_and = matches!(self, Expr::And(..));
+                for l in a.eval(input) {
+                    if truthy(&l) != is_and {
+                        out.push(Value::Boolean(!is_and));
+                        continue;
+                    }
+                    out.extend(b.eval(input).iter().map(|r| Value::Boolean(truthy(r))));
+                }
+            }
+            Expr::Collect(e) => out.push(Value::Array(
+                e.as_ref().map(|e| e.eval(input)).unwrap_or_default(),
+            )),
+            Expr::Object(fields) => objects(fields, input, &Default::default(), &mut out),
+            Expr::Call(name, args) => self.call(name, args, input, &mut out),
+        }
+        out
+    }
+
+    fn call(&self, name: &str, args: &[Expr], input: &Value, out: &mut Vec<Value>) {
+        let arg = || args[0].eval(input);
+        match name {
+            "select" => {
+                if arg().iter().any(truthy) {
+                    out.push(input.clone());
+                }
+            }
+            "has" => out.extend(arg().iter().map(|k| {
+                Value::Boolean(match (input, k) {
+                    (Value::Object(o), Value::String(k)) => o.contains_key(k),
+                    (Value::Array(a), k) => matches!(k.as_u64(), Some(i) if (i as usize) < a.len()),
+                    _ => false,
+                })
+            })),
+            "contains" => out.extend(arg().iter().map(|x| Value::Boolean(contains(input, x)))),
+            "startswith" | "endswith" => out.extend(arg().iter().map(|s| {
+                Value::Boolean(match (input, s) {
+                    (Value::String(a), Value::String(s)) if name == "startswith" => {
+                        a.starts_with(s.as_str())
+                    }
+                    (Value::String(a), Value::String(s)) => a.ends_with(s.as_str()),
+                    _ => false,
+                })
+            })),
+            "length" => out.push(match input {
+                Value::String(s) => Value::Integer(s.chars().count() as i64),
+                Value::Array(a) => Value::Integer(a.len() as i64),
+                Value::Object(o) => Value::Integer(o.len() as i64),
+                Value::Integer(i) => Value::Integer(i.abs()),
+                Value::Float(f) => Value::Float(f.abs()),
+                _ => Value::Integer(0),
+            }),
+            "keys" => match input {
+                Value::Object(o) => out.push(Value::Array(
+                    o.keys().map(|k| Value::String(k.clone())).collect(),
+                )),
+                Value::Array(a) => out.push(Value::Array(
+                    (0..a.len() as i64).map(Value::Integer).collect(),
+                )),
+                _ => {}
+            },
+            "not" => out.push(Value::Boolean(!truthy(input))),
+            "type" => out.push(Value::String(
+                match input {
+                    Value::Integer(_) | Value::Float(_) => "number",
+                    v => v.type_name(),
+                }
+                .to_string(),
+            )),
+            _ => unreachable!("unknown function {}", name),
+        }
+    }
+}
+
+/// A parsed query, ready to run over any number of values.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    expr: Expr,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self, Box<dyn Error>> {
+        let i = query.as_bytes();
+        let (_, expr) = render_nom_error(i, all_consuming(ws(pipeline))(i))
+            .map_err(|e| format!("invalid query {:?}: {}", query, e))?;
+        Ok(Query { expr })
+    }
+
+    pub fn run(&self, v: &Value) -> Vec<Value> {
+        self.expr.eval(v)
+    }
+
+    pub fn run_json(&self, v: &serde_json::Value) -> Vec<Value> {
+        self.run(&Value::from(v.clone()))
+    }
+}
+
+impl std::str::FromStr for Query {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Query::parse(s)
+    }
+}
+
+impl PackedAssets {
+    /// Runs `query` over every JSON asset passing `filter`, giving each
+    /// output with the asset it came from. Assets that aren't JSON are
+    /// skipped.
+    pub fn query(&self, query: &Query, filter: &Filter) -> Vec<(String, Value)> {
+        let mut out = Vec::new();
+        for path in self.assets().into_iter().filter(|p| filter.matches(p)) {
+            if let Ok(json) = self.json(path) {
+                out.extend(
+                    query
+                        .run_json(&json)
+                        .into_iter()
+                        .map(|v| (path.to_string(), v)),
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bson::text;
+
+    fn run(query: &str, input: &str) -> Value {
+        let query = Query::parse(query).unwrap();
+        Value::Array(query.run(&text::from_str(input).unwrap()))
+    }
+
+    fn values(s: &str) -> Value {
+        text::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_paths() {
+        let player = r#"{
+            "identity": {"name": "Nova", "odd key": 1},
+            "bags": {
+                "main": [
+                    {"name": "sword", "parameters": {"rarity": "legendary"}},
+                    null,
+                    {"name": "dirt", "count": 500, "parameters": {"rarity": "common"}}
+                ],
+                "tools": [{"name": "pick", "parameters": {"rarity": "Legendary"}}]
+            }
+        }"#;
+        assert_eq!(run(".identity.name", player), values(r#"["Nova"]"#));
+        assert_eq!(run(r#".identity."odd key""#, player), values("[1]"));
+        assert_eq!(run(r#".["identity"]["odd key"]"#, player), values("[1]"));
+        assert_eq!(run(".bags.main[-1].count", player), values("[500]"));
+        assert_eq!(run(".bags.main[5]", player), values("[null]"));
+        assert_eq!(run(".bags.missing", player), values("[null]"));
+        assert_eq!(run(".identity.name.deeper", player), values("[]"));
+        assert_eq!(
+            run(".bags.*[]?.name", player),
+            values(r#"["sword", "dirt", "pick"]"#)
+        );
+        assert_eq!(
+            run(
+                r#"..|select(.parameters.rarity == "legendary")|.name"#,
+                player
+            ),
+            values(r#"["sword"]"#)
+        );
+        assert_eq!(
+            run(r#"[..name | select(type == "string")] | length"#, player),
+            values("[4]")
+        );
+        assert_eq!(
+            run(
+                ".bags.main[] | select(.count >= 100 and has(\"parameters\")) | {name, n: .count}",
+                player
+            ),
+            values(r#"[{"name": "dirt", "n": 500}]"#)
+        );
+        assert_eq!(
+            run(".identity | keys", player),
+            values(r#"[["name", "odd key"]]"#)
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(
+            run("1 == 1.0, 1 < 2, \"a\" < 1, null == null", "null"),
+            values("[true, true, false, true]")
+        );
+        assert_eq!(run(". > 1 or . == 0", "0"), values("[true]"));
+        assert_eq!(run("(. == 1) | not", "1"), values("[false]"));
+        assert_eq!(
+            run(
+                ".[] | select(startswith(\"tit\"))",
+                r#"["titaniumbar", "ironbar"]"#
+            ),
+            values(r#"["titaniumbar"]"#)
+        );
+        assert_eq!(
+            run("contains({\"a\": [1]})", r#"{"a": [1, 2], "b": 3}"#),
+            values("[true]")
+        );
+        assert_eq!(
+            run("{a: (1, 2), b: 3}", "null"),
+            values(r#"[{"a": 1, "b": 3}, {"a": 2, "b": 3}]"#)
+        );
+        assert_eq!(run("[]", "null"), values("[[]]"));
+        assert!(Query::parse(".a |").is_err());
+        assert!(Query::parse("nosuchfunction").is_err());
+        assert!(Query::parse("select(1; 2)").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let recipe = serde_json::json!({
+            "input": [{"item": "titaniumbar", "count": 2}, {"item": "wire"}],
+            "output": {"item": "titaniumsword", "count": 1}
+        });
+        let query =
+            Query::parse(r#"select(.input[].item == "titaniumbar") | .output.item"#).unwrap();
+        assert_eq!(query.run_json(&recipe), vec![Value::from("titaniumsword")]);
+    }
+}
@@ -0,0 +1,534 @@
+use crate::bson::{Map, Value};
+use crate::packed::{PackedAssets, Player};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuestState {
+    New,
+    Offer,
+    Active,
+    Complete,
+    Failed,
+}
+
+impl QuestState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QuestState::New => "New",
+            QuestState::Offer => "Offer",
+            QuestState::Active => "Active",
+            QuestState::Complete => "Complete",
+            QuestState::Failed => "Failed",
+        }
+    }
+}
+
+impl std::fmt::Display for QuestState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for QuestState {
+    type Err = QuestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "New" => Ok(QuestState::New),
+            "Offer" => Ok(QuestState::Offer),
+            "Active" => Ok(QuestState::Active),
+            "Complete" => Ok(QuestState::Complete),
+            "Failed" => Ok(QuestState::Failed),
+            _ => Err(QuestError::UnknownState(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuestError {
+    UnknownQuest(String),
+    UnknownState(String),
+    MalformedPlayer(String),
+}
+
+impl std::fmt::Display for QuestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuestError::UnknownQuest(q) => write!(f, "player has no quest {}", q),
+            QuestError::UnknownState(s) => write!(f, "unknown quest state {}", s),
+            QuestError::MalformedPlayer(what) => write!(f, "malformed player quests: {}", what),
+        }
+    }
+}
+
+impl Error for QuestError {}
+
+fn field<'a>(v: &'a Value, key: &str) -> Option<&'a Value> {
+    match v {
+        Value::Object(o) => o.get(key),
+        _ => None,
+    }
+}
+
+fn string(v: Option<&Value>) -> Option<String> {
+    match v {
+        Some(Value::String(s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+/// Quests and arcs are stored as `{"id", "version", "content"}` wrappers.
+fn versioned_content(v: &Value) -> &Value {
+    match (field(v, "id"), field(v, "content")) {
+        (Some(Value::String(_)), Some(content)) => content,
+        _ => v,
+    }
+}
+
+fn versioned_content_mut(v: &mut Value) -> &mut Value {
+    let wrapped = match v {
+        Value::Object(o) => {
+            matches!(o.get("id"), Some(Value::String(_))) && o.contains_key("content")
+        }
+        _ => false,
+    };
+    if !wrapped {
+        return v;
+    }
+    match v {
+        Value::Object(o) => o.get_mut("content").unwrap(),
+        _ => unreachable!(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuestDescriptor {
+    pub quest_id: String,
+    pub template_id: String,
+    pub parameters: Value,
+    pub seed: i64,
+}
+
+impl QuestDescriptor {
+    fn from_value(v: &Value) -> Option<Self> {
+        let v = versioned_content(v);
+        Some(QuestDescriptor {
+            quest_id: string(field(v, "questId"))?,
+            template_id: string(field(v, "templateId"))?,
+            parameters: field(v, "parameters").cloned().unwrap_or_default(),
+            seed: match field(v, "seed") {
+                Some(Value::Integer(i)) => *i,
+                _ => 0,
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quest {
+    pub id: String,
+    pub state: QuestState,
+    pub title: String,
+    pub arc: Vec<QuestDescriptor>,
+    pub arc_pos: usize,
+    pub parameters: Value,
+    pub server_uuid: Option<String>,
+}
+
+impl Quest {
+    fn from_value(id: &str, v: &Value) -> Result<Self, QuestError> {
+        let v = versioned_content(v);
+        let malformed = |what: &str| QuestError::MalformedPlayer(format!("{}: {}", id, what));
+        let state = string(field(v, "state"))
+            .ok_or_else(|| malformed("missing state"))?
+            .parse()?;
+        let arc = match field(v, "arc")
+            .map(versioned_content)
+            .and_then(|a| field(a, "quests"))
+        {
+            Some(Value::Array(quests)) => quests
+                .iter()
+                .map(|q| QuestDescriptor::from_value(q).ok_or_else(|| malformed("bad arc entry")))
+                .collect::<Result<_, _>>()?,
+            _ => return Err(malformed("missing arc")),
+        };
+        let arc_pos = match field(v, "arcPos") {
+            Some(Value::Integer(i)) if *i >= 0 => *i as usize,
+            _ => 0,
+        };
+
+        Ok(Quest {
+            id: id.to_string(),
+            state,
+            title: string(field(v, "title")).unwrap_or_default(),
+            arc,
+            arc_pos,
+            parameters: field(v, "parameters").cloned().unwrap_or_default(),
+            server_uuid: string(field(v, "serverUuid")),
+        })
+    }
+
+    /// The template of this quest's own entry in its arc.
+    pub fn template_id(&self) -> Option<&str> {
+        self.arc
+            .iter()
+            .find(|d| d.quest_id == self.id)
+            .map(|d| d.template_id.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuestTemplate {
+    pub id: String,
+    pub path: String,
+    pub title: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QuestTemplateDatabase {
+    templates: BTreeMap<String, QuestTemplate>,
+}
+
+impl QuestTemplateDatabase {
+    pub fn new(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let mut db = Self::default();
+        for path in assets.assets_with_extension("questtemplate") {
+            let template = assets.json(path)?;
+            let id = template
+                .get("id")
+                .and_then(|i| i.as_str())
+                .ok_or_else(|| format!("{}: quest template has no id", path))?;
+            let title = template
+                .get("title")
+                .and_then(|t| t.as_str())
+                .unwrap_or_default();
+            db.add(QuestTemplate {
+                id: id.to_string(),
+                path: path.to_string(),
+                title: title.to_string(),
+            });
+        }
+        Ok(db)
+    }
+
+    pub fn add(&mut self, template: QuestTemplate) {
+        self.templates.insert(template.id.clone(), template);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&QuestTemplate> {
+        self.templates.get(id)
+    }
+
+    pub fn templates(&self) -> impl Iterator<Item = &QuestTemplate> {
+        self.templates.values()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuestProblem {
+    /// The quest entry could not be read at all.
+    Malformed { quest: String, error: QuestError },
+    /// A quest in the arc uses a template that no loaded asset defines.
+    MissingTemplate { quest: String, template: String },
+    /// `currentQuest` refers to a quest the player doesn't have.
+    DanglingCurrentQuest(String),
+}
+
+impl QuestProblem {
+    pub fn quest(&self) -> &str {
+        match self {
+            QuestProblem::Malformed { quest, .. } => quest,
+            QuestProblem::MissingTemplate { quest, .. } => quest,
+            QuestProblem::DanglingCurrentQuest(quest) => quest,
+        }
+    }
+}
+
+impl std::fmt::Display for QuestProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuestProblem::Malformed { error, .. } => write!(f, "{}", error),
+            QuestProblem::MissingTemplate { quest, template } => {
+                write!(f, "quest {} uses missing template {}", quest, template)
+            }
+            QuestProblem::DanglingCurrentQuest(quest) => {
+                write!(f, "current quest {} does not exist", quest)
+            }
+        }
+    }
+}
+
+impl Player {
+    fn quest_log(&self) -> Option<&Map> {
+        match field(&self.contents.content, "quests")? {
+            Value::Object(log) => Some(log),
+            _ => None,
+        }
+    }
+
+    fn quest_log_mut(&mut self) -> Result<&mut Map, QuestError> {
+        let malformed = |what: &str| QuestError::MalformedPlayer(what.to_string());
+        match &mut self.contents.content {
+            Value::Object(o) => match o.get_mut("quests") {
+                Some(Value::Object(log)) => Ok(log),
+                _ => Err(malformed("quests is not an object")),
+            },
+            _ => Err(malformed("player is not an object")),
+        }
+    }
+
+    fn quest_entries(&self) -> Option<&Map> {
+        match self.quest_log()?.get("quests")? {
+            Value::Object(quests) => Some(quests),
+            _ => None,
+        }
+    }
+
+    fn quest_entry_mut(&mut self, id: &str) -> Result<&mut Map, QuestError> {
+        let entry = match self.quest_log_mut()?.get_mut("quests") {
+            Some(Value::Object(quests)) => quests.get_mut(id),
+            _ => None,
+        }
+        .ok_or_else(|| QuestError::UnknownQuest(id.to_string()))?;
+        match versioned_content_mut(entry) {
+            Value::Object(quest) => Ok(quest),
+            _ => Err(QuestError::MalformedPlayer(format!(
+                "{} is not an object",
+                id
+            ))),
+        }
+    }
+
+    pub fn quest_ids(&self) -> Vec<String> {
+        self.quest_entries()
+            .map(|q| q.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn quest(&self, id: &str) -> Result<Quest, QuestError> {
+        let entry = self
+            .quest_entries()
+            .and_then(|q| q.get(id))
+            .ok_or_else(|| QuestError::UnknownQuest(id.to_string()))?;
+        Quest::from_value(id, entry)
+    }
+
+    pub fn quests(&self) -> Vec<Result<Quest, QuestError>> {
+        self.quest_entries()
+            .map(|q| q.iter().map(|(id, v)| Quest::from_value(id, v)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn current_quest(&self) -> Option<String> {
+        string(self.quest_log()?.get("currentQuest"))
+    }
+
+    pub fn set_quest_state(&mut self, id: &str, state: QuestState) -> Result<(), QuestError> {
+        let quest = self.quest_entry_mut(id)?;
+        quest.insert(
+            "state".to_string(),
+            Value::String(state.as_str().to_string()),
+        );
+        Ok(())
+    }
+
+    pub fn complete_quest(&mut self, id: &str) -> Result<(), QuestError> {
+        self.set_quest_state(id, QuestState::Complete)
+    }
+
+    pub fn fail_quest(&mut self, id: &str) -> Result<(), QuestError> {
+        self.set_quest_state(id, QuestState::Failed)
+    }
+
+    /// Restarts a quest: its script storage and server binding are dropped
+    /// and it becomes active again, so the quest script starts from scratch
+    /// the next time the player logs in.
+    pub fn reset_quest(&mut self, id: &str) -> Result<(), QuestError> {
+        let quest = self.quest_entry_mut(id)?;
+        quest.insert("scriptStorage".to_string(), Value::Object(Map::new()));
+        quest.insert("serverUuid".to_string(), Value::Empty);
+        quest.insert(
+            "state".to_string(),
+            Value::String(QuestState::Active.as_str().to_string()),
+        );
+        Ok(())
+    }
+
+    /// Removes a quest, clearing `currentQuest` if it pointed at it.
+    pub fn remove_quest(&mut self, id: &str) -> Result<(), QuestError> {
+        let current = self.current_quest();
+        let log = self.quest_log_mut()?;
+        let removed = match log.get_mut("quests") {
+            Some(Value::Object(quests)) => quests.remove(id),
+            _ => None,
+        };
+        if removed.is_none() {
+            return Err(QuestError::UnknownQuest(id.to_string()));
+        }
+        if current.as_deref() == Some(id) {
+            log.insert("currentQuest".to_string(), Value::Empty);
+        }
+        Ok(())
+    }
+
+    pub fn validate_quests(&self, db: &QuestTemplateDatabase) -> Vec<QuestProblem> {
+        let mut problems = Vec::new();
+        let entries = self.quest_entries().cloned().unwrap_or_default();
+        for (id, entry) in &entries {
+            match Quest::from_value(id, entry) {
+                Ok(quest) => {
+                    for descriptor in &quest.arc {
+                        if db.get(&descriptor.template_id).is_none() {
+                            problems.push(QuestProblem::MissingTemplate {
+                                quest: id.clone(),
+                                template: descriptor.template_id.clone(),
+                            });
+                        }
+                    }
+                }
+                Err(error) => problems.push(QuestProblem::Malformed {
+                    quest: id.clone(),
+                    error,
+                }),
+            }
+        }
+        if let Some(current) = self.current_quest() {
+            if !entries.contains_key(&current) {
+                problems.push(QuestProblem::DanglingCurrentQuest(current));
+            }
+        }
+        problems
+    }
+
+    /// Removes every quest [`validate_quests`](Player::validate_quests)
+    /// reports a problem with, returning the ids removed.
+    pub fn remove_broken_quests(&mut self, db: &QuestTemplateDatabase) -> Vec<String> {
+        let mut removed = Vec::new();
+        for problem in self.validate_quests(db) {
+            let quest = problem.quest().to_string();
+            match problem {
+                QuestProblem::DanglingCurrentQuest(_) => {
+                    if let Ok(log) = self.quest_log_mut() {
+                        log.insert("currentQuest".to_string(), Value::Empty);
+                    }
+                }
+                _ => {
+                    if self.remove_quest(&quest).is_ok() {
+                        removed.push(quest);
+                    }
+                }
+            }
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packed::VersionedJson;
+    use serde_json::json;
+
+    fn quest(id: &str, template: &str) -> serde_json::Value {
+        json!({
+            "content": {
+                "arc": {
+                    "content": {
+                        "quests": [{
+                            "content": {"parameters": {}, "questId": id, "seed": -15, "templateId": template},
+                            "id": "QuestDescriptor",
+                            "version": 3
+                        }],
+                        "stagehandUniqueId": null
+                    },
+                    "id": "QuestArcDescriptor",
+                    "version": 1
+                },
+                "arcPos": 0,
+                "parameters": {},
+                "scriptStorage": {"stage": 3},
+                "serverUuid": "f00d",
+                "state": "Active",
+                "title": "The Protectorate"
+            },
+            "id": "Quest",
+            "version": 3
+        })
+    }
+
+    fn player() -> Player {
+        Player {
+            contents: VersionedJson {
+                identifier: "PlayerEntity".to_string(),
+                version: 30,
+                content: serde_json::from_value(json!({
+                    "quests": {
+                        "currentQuest": "modquest",
+                        "quests": {
+                            "protectorate": quest("protectorate", "protectorate"),
+                            "modquest": quest("modquest", "removedmodquest")
+                        }
+                    }
+                }))
+                .unwrap(),
+            },
+            source: None,
+        }
+    }
+
+    fn database() -> QuestTemplateDatabase {
+        let mut db = QuestTemplateDatabase::default();
+        db.add(QuestTemplate {
+            id: "protectorate".to_string(),
+            path: "/quests/protectorate.questtemplate".to_string(),
+            title: "The Protectorate".to_string(),
+        });
+        db
+    }
+
+    #[test]
+    fn test_typed_access() {
+        let p = player();
+        let q = p.quest("protectorate").unwrap();
+        assert_eq!(q.state, QuestState::Active);
+        assert_eq!(q.template_id(), Some("protectorate"));
+        assert_eq!(q.arc[0].seed, -15);
+        assert_eq!(q.server_uuid, Some("f00d".to_string()));
+        assert_eq!(p.current_quest(), Some("modquest".to_string()));
+    }
+
+    #[test]
+    fn test_state_edits() {
+        let mut p = player();
+        p.complete_quest("protectorate").unwrap();
+        assert_eq!(p.quest("protectorate").unwrap().state, QuestState::Complete);
+        p.reset_quest("protectorate").unwrap();
+        let q = p.quest("protectorate").unwrap();
+        assert_eq!(q.state, QuestState::Active);
+        assert_eq!(q.server_uuid, None);
+        assert_eq!(
+            p.fail_quest("nope"),
+            Err(QuestError::UnknownQuest("nope".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_repair() {
+        let mut p = player();
+        let db = database();
+        assert_eq!(
+            p.validate_quests(&db),
+            vec![QuestProblem::MissingTemplate {
+                quest: "modquest".to_string(),
+                template: "removedmodquest".to_string(),
+            }]
+        );
+        assert_eq!(p.remove_broken_quests(&db), vec!["modquest"]);
+        assert_eq!(p.quest_ids(), vec!["protectorate"]);
+        assert_eq!(p.current_quest(), None);
+        assert!(p.validate_quests(&db).is_empty());
+    }
+}
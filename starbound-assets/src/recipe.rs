@@ -0,0 +1,364 @@
+use crate::item::{json_u64, ItemDescriptor};
+use crate::packed::PackedAssets;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub path: String,
+    pub input: Vec<ItemDescriptor>,
+    pub currency_inputs: BTreeMap<String, u64>,
+    pub output: ItemDescriptor,
+    pub groups: Vec<String>,
+    pub duration: f64,
+}
+
+impl Recipe {
+    pub fn from_json(path: &str, v: &Value) -> Result<Self, Box<dyn Error>> {
+        let input = v
+            .get("input")
+            .and_then(|i| i.as_array())
+            .ok_or("recipe has no input list")?
+            .iter()
+            .map(|i| ItemDescriptor::from_json(i).ok_or("invalid input item"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let output = v
+            .get("output")
+            .and_then(ItemDescriptor::from_json)
+            .ok_or("recipe has no valid output")?;
+        let currency_inputs = v
+            .get("currencyInputs")
+            .and_then(|c| c.as_object())
+            .map(|c| {
+                c.iter()
+                    .filter_map(|(k, v)| Some((k.clone(), json_u64(v)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let groups = v
+            .get("groups")
+            .and_then(|g| g.as_array())
+            .map(|g| {
+                g.iter()
+                    .filter_map(|g| g.as_str())
+                    .map(|g| g.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let duration = v.get("duration").and_then(|d| d.as_f64()).unwrap_or(0.0);
+
+        Ok(Recipe {
+            path: path.to_string(),
+            input,
+            currency_inputs,
+            output,
+            groups,
+            duration,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecipeError {
+    UnknownItem(String),
+    Cycle(Vec<String>),
+    /// The amounts needed for this item or recipe don't fit in a `u64`.
+    Overflow(String),
+}
+
+impl std::fmt::Display for RecipeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecipeError::UnknownItem(item) => write!(f, "no recipe produces {}", item),
+            RecipeError::Cycle(items) => write!(f, "recipe cycle: {}", items.join(" -> ")),
+            RecipeError::Overflow(name) => write!(f, "too many items needed for {}", name),
+        }
+    }
+}
+
+impl Error for RecipeError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BillOfMaterials {
+    /// Items that no recipe produces, and so have to be gathered.
+    pub raw: BTreeMap<String, u64>,
+    pub currencies: BTreeMap<String, u64>,
+    /// Every recipe used, with the number of times it is crafted, in the
+    /// order the crafts have to happen.
+    pub crafts: Vec<(String, u64)>,
+    /// Output left over because recipes produce more than one item per craft.
+    pub surplus: BTreeMap<String, u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RecipeDatabase {
+    recipes: Vec<Recipe>,
+    by_output: BTreeMap<String, Vec<usize>>,
+    by_group: BTreeMap<String, Vec<usize>>,
+}
+
+impl RecipeDatabase {
+    pub fn new(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let mut db = Self::default();
+        for path in assets.assets_with_extension("recipe") {
+            let recipe = Recipe::from_json(path, &assets.json(path)?)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            db.add(recipe);
+        }
+        Ok(db)
+    }
+
+    pub fn add(&mut self, recipe: Recipe) {
+        let idx = self.recipes.len();
+        self.by_output
+            .entry(recipe.output.name.clone())
+            .or_default()
+            .push(idx);
+        for group in &recipe.groups {
+            self.by_group.entry(group.clone()).or_default().push(idx);
+        }
+        self.recipes.push(recipe);
+    }
+
+    pub fn recipes(&self) -> &[Recipe] {
+        &self.recipes
+    }
+
+    pub fn recipes_producing(&self, item: &str) -> Vec<&Recipe> {
+        self.lookup(&self.by_output, item)
+    }
+
+    pub fn recipes_in_group(&self, group: &str) -> Vec<&Recipe> {
+        self.lookup(&self.by_group, group)
+    }
+
+    pub fn recipes_using(&self, item: &str) -> Vec<&Recipe> {
+        self.recipes
+            .iter()
+            .filter(|r| r.input.iter().any(|i| i.name == item))
+            .collect()
+    }
+
+    fn lookup(&self, index: &BTreeMap<String, Vec<usize>>, key: &str) -> Vec<&Recipe> {
+        index
+            .get(key)
+            .map(|idxs| idxs.iter().map(|&i| &self.recipes[i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// Expands `item` into the raw materials needed to craft `count` of it.
+    ///
+    /// When an item has several recipes the first one that doesn't lead back
+    /// into an item already being expanded is used; an item is only a cycle
+    /// error when every one of its recipes loops.
+    pub fn bill_of_materials(
+        &self,
+        item: &str,
+        count: u64,
+    ) -> Result<BillOfMaterials, RecipeError> {
+        if !self.by_output.contains_key(item) {
+            return Err(RecipeError::UnknownItem(item.to_string()));
+        }
+        let mut bill = BillOfMaterials::default();
+        let mut stack = Vec::new();
+        self.expand(item, count, &mut stack, &mut bill)?;
+        Ok(bill)
+    }
+
+    fn expand(
+        &self,
+        item: &str,
+        count: u64,
+        stack: &mut Vec<String>,
+        bill: &mut BillOfMaterials,
+    ) -> Result<(), RecipeError> {
+        let mut count = count;
+        if let Some(spare) = bill.surplus.get_mut(item) {
+            let used = std::cmp::min(*spare, count);
+            *spare -= used;
+            count -= used;
+            if *spare == 0 {
+                bill.surplus.remove(item);
+            }
+        }
+        if count == 0 {
+            return Ok(());
+        }
+
+        let candidates = match self.by_output.get(item) {
+            Some(c) => c,
+            None => {
+                let raw = bill.raw.entry(item.to_string()).or_default();
+                *raw = raw
+                    .checked_add(count)
+                    .ok_or_else(|| RecipeError::Overflow(item.to_string()))?;
+                return Ok(());
+            }
+        };
+
+        if stack.iter().any(|s| s == item) {
+            let mut cycle = stack.clone();
+            cycle.push(item.to_string());
+            return Err(RecipeError::Cycle(cycle));
+        }
+
+        stack.push(item.to_string());
+        let mut err = None;
+        for &idx in candidates {
+            let recipe = &self.recipes[idx];
+            let mut attempt = bill.clone();
+            match self.craft(recipe, count, stack, &mut attempt) {
+                Ok(()) => {
+                    *bill = attempt;
+                    err = None;
+                    break;
+                }
+                Err(e) => {
+                    if err.is_none() {
+                        err = Some(e);
+                    }
+                }
+            }
+        }
+        stack.pop();
+
+        match err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn craft(
+        &self,
+        recipe: &Recipe,
+        count: u64,
+        stack: &mut Vec<String>,
+        bill: &mut BillOfMaterials,
+    ) -> Result<(), RecipeError> {
+        let overflow = || RecipeError::Overflow(recipe.path.clone());
+        let per_craft = std::cmp::max(recipe.output.count, 1);
+        let crafts = count.div_ceil(per_craft);
+
+        for input in &recipe.input {
+            let needed = input.count.checked_mul(crafts).ok_or_else(overflow)?;
+            self.expand(&input.name, needed, stack, bill)?;
+        }
+        for (currency, amount) in &recipe.currency_inputs {
+            let total = bill.currencies.entry(currency.clone()).or_default();
+            *total = amount
+                .checked_mul(crafts)
+                .and_then(|a| total.checked_add(a))
+                .ok_or_else(overflow)?;
+        }
+
+        let extra = crafts.checked_mul(per_craft).ok_or_else(overflow)? - count;
+        if extra > 0 {
+            let surplus = bill.surplus.entry(recipe.output.name.clone()).or_default();
+            *surplus = surplus.checked_add(extra).ok_or_else(overflow)?;
+        }
+        bill.crafts.push((recipe.path.clone(), crafts));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recipe(path: &str, v: Value) -> Recipe {
+        Recipe::from_json(path, &v).unwrap()
+    }
+
+    fn database() -> RecipeDatabase {
+        let mut db = RecipeDatabase::default();
+        db.add(recipe(
+            "/recipes/copperbar.recipe",
+            json!({
+                "input": [{"item": "copperore", "count": 2}],
+                "output": {"item": "copperbar", "count": 1},
+                "groups": ["craftingfurnace"]
+            }),
+        ));
+        db.add(recipe(
+            "/recipes/wire.recipe",
+            json!({
+                "input": [["copperbar", 1]],
+                "output": ["wire", 4],
+                "groups": ["craftinganvil"],
+                "duration": 0.5
+            }),
+        ));
+        db.add(recipe(
+            "/recipes/lamp.recipe",
+            json!({
+                "input": [{"item": "wire", "count": 3}, {"item": "copperbar", "count": 1}],
+                "currencyInputs": {"money": 50},
+                "output": {"item": "lamp", "count": 1},
+                "groups": ["craftinganvil", "all"]
+            }),
+        ));
+        db
+    }
+
+    #[test]
+    fn test_queries() {
+        let db = database();
+        assert_eq!(db.recipes_producing("wire").len(), 1);
+        assert_eq!(db.recipes_in_group("craftinganvil").len(), 2);
+        assert_eq!(db.recipes_using("copperbar").len(), 2);
+        assert!(db.recipes_producing("copperore").is_empty());
+    }
+
+    #[test]
+    fn test_bill() {
+        let db = database();
+        let bill = db.bill_of_materials("lamp", 2).unwrap();
+        // 6 wire need 2 crafts of 4 wire (2 bars), plus 2 bars for the lamps
+        assert_eq!(bill.raw.get("copperore"), Some(&8));
+        assert_eq!(bill.currencies.get("money"), Some(&100));
+        assert_eq!(bill.surplus.get("wire"), Some(&2));
+
+        assert_eq!(
+            db.bill_of_materials("lamp", u64::MAX / 40),
+            Err(RecipeError::Overflow("/recipes/lamp.recipe".to_string()))
+        );
+        assert_eq!(
+            db.bill_of_materials("wire", u64::MAX),
+            Err(RecipeError::Overflow("/recipes/wire.recipe".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_cycle() {
+        let mut db = RecipeDatabase::default();
+        db.add(recipe(
+            "/a.recipe",
+            json!({"input": [["b", 1]], "output": ["a", 1]}),
+        ));
+        db.add(recipe(
+            "/b.recipe",
+            json!({"input": [["a", 1]], "output": ["b", 1]}),
+        ));
+        assert_eq!(
+            db.bill_of_materials("a", 1),
+            Err(RecipeError::Cycle(vec![
+                "a".to_string(),
+                "b".to_string(),
+                "a".to_string()
+            ]))
+        );
+
+        // an alternative recipe breaks the cycle
+        db.add(recipe(
+            "/b2.recipe",
+            json!({"input": [["ore", 2]], "output": ["b", 1]}),
+        ));
+        assert_eq!(
+            db.bill_of_materials("a", 1).unwrap().raw.get("ore"),
+            Some(&2)
+        );
+    }
+}
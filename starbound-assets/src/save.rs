@@ -0,0 +1,304 @@
+use crate::bson::merge::{Conflict, Merge};
+use crate::bson::Value;
+use crate::packed::{load_versioned_json, write_versioned_json, LoadedFrom, Player, VersionedJson};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// How many backups the game keeps of each player file.
+pub const DEFAULT_BACKUPS: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SaveError {
+    ModifiedSinceLoad(PathBuf),
+    VerifyFailed(PathBuf),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::ModifiedSinceLoad(p) => {
+                write!(f, "{} was modified after it was loaded", p.display())
+            }
+            SaveError::VerifyFailed(p) => {
+                write!(f, "{} did not read back as what was written", p.display())
+            }
+        }
+    }
+}
+
+impl Error for SaveError {}
+
+/// `player.bak2` for `player` and generation 2.
+pub fn backup_path(path: &Path, generation: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak{}", generation));
+    path.with_file_name(name)
+}
+
+/// Shifts `.bak1` to `.bak2` and so on, dropping the oldest, then copies
+/// `path` to `.bak1`. The original is copied rather than moved so that it
+/// stays in place until it is replaced.
+fn rotate_backups(path: &Path, count: u32) -> Result<(), Box<dyn Error>> {
+    if count == 0 || !path.exists() {
+        return Ok(());
+    }
+    for generation in (2..=count).rev() {
+        let older = backup_path(path, generation - 1);
+        if older.exists() {
+            fs::rename(&older, backup_path(path, generation))?;
+        }
+    }
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+/// Whether `a` and `b` name the same file, however they are written.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn sync_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    // Directories can't be opened for syncing everywhere; the rename has
+    // still happened, it just may not be durable yet.
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+impl Player {
+    /// Builds a player from edited JSON content, keeping the identifier of
+    /// `original` and restoring floats that came out of the editor as
+    /// integers. The version is `original`'s unless given, and saving over
+    /// the file `original` was loaded from checks it hasn't changed since.
+    pub fn from_json(original: &Player, content: Value, version: Option<u32>) -> Player {
+        let mut content = content;
+        content.restore_floats(&original.contents.content);
+        Player {
+            contents: VersionedJson {
+                identifier: original.contents.identifier.clone(),
+                version: version.unwrap_or(original.contents.version),
+                content,
+            },
+            source: original.source.clone(),
+        }
+    }
+
+    /// Merges our edits of `base` with `theirs`, typically a newer save the
+    /// game wrote meanwhile. The result takes the newer of the two versions
+    /// and is tied to the file `theirs` was loaded from, so that it can be
+    /// saved over it unless the game writes again.
+    pub fn merge(&self, base: &Player, theirs: &Player) -> (Player, Vec<Conflict>) {
+        let Merge { merged, conflicts } = self
+            .contents
+            .content
+            .merge3(&base.contents.content, &theirs.contents.content);
+        let player = Player {
+            contents: VersionedJson {
+                identifier: theirs.contents.identifier.clone(),
+                version: self.contents.version.max(theirs.contents.version),
+                content: merged,
+            },
+            source: theirs.source.clone(),
+        };
+        (player, conflicts)
+    }
+
+    /// The player file as the game reads it. Unlike `save_versioned_json`
+    /// this leaves the version alone.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut out = b"SBVJ01".to_vec();
+        write_versioned_json(&mut out, &self.contents)?;
+        Ok(out)
+    }
+
+    /// Saves to `path` keeping the game's default number of backups.
+    pub fn save_to<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        self.save_with_backups(path, DEFAULT_BACKUPS)
+    }
+
+    /// Replaces `path` atomically: the new file is written and synced next
+    /// to it, read back and checked, then renamed over the original after
+    /// rotating `backups` numbered copies of it.
+    ///
+    /// If this player was loaded from `path` and the file has changed since,
+    /// nothing is written.
+    pub fn save_with_backups<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        backups: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        if let (Some(source), Ok(meta)) = (&self.source, fs::metadata(path)) {
+            if same_file(&source.path, path) && *source != LoadedFrom::new(path, &meta) {
+                return Err(SaveError::ModifiedSinceLoad(path.to_path_buf()).into());
+            }
+        }
+
+        let bytes = self.to_bytes()?;
+        let mut name = path
+            .file_name()
+            .ok_or("no file name to save to")?
+            .to_os_string();
+        name.push(".tmp");
+        let tmp = path.with_file_name(name);
+
+        let written = (|| -> Result<(), Box<dyn Error>> {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp)?;
+            f.write_all(&bytes)?;
+            f.sync_all()?;
+            if load_versioned_json(&File::open(&tmp)?)? != self.contents {
+                return Err(SaveError::VerifyFailed(tmp.clone()).into());
+            }
+            Ok(())
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        rotate_backups(path, backups)?;
+        fs::rename(&tmp, path)?;
+        if let Some(dir) = path.parent() {
+            sync_dir(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            })?;
+        }
+
+        self.source = Some(LoadedFrom::new(path, &fs::metadata(path)?));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn player(level: u32) -> Player {
+        Player {
+            contents: VersionedJson {
+                identifier: "PlayerEntity".to_string(),
+                version: 30,
+                content: serde_json::from_value(json!({"level": level, "ratio": 0.1})).unwrap(),
+            },
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = std::env::temp_dir().join(format!("save-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("abc.player");
+
+        for level in 0..5 {
+            player(level).save_with_backups(&path, 3).unwrap();
+        }
+        let level = |p: &Path| Player::open(p).unwrap().contents;
+        assert_eq!(level(&path), player(4).contents);
+        assert_eq!(level(&backup_path(&path, 1)), player(3).contents);
+        assert_eq!(level(&backup_path(&path, 3)), player(1).contents);
+        assert!(!backup_path(&path, 4).exists());
+        assert!(!dir.join("abc.player.tmp").exists());
+
+        let mut loaded = Player::open(&path).unwrap();
+        assert_eq!(loaded.contents.version, 30);
+        loaded.save_to(&path).unwrap();
+        loaded.save_to(&path).unwrap();
+
+        // Someone else writes the file in the meantime
+        let mut other = Player::open(&path).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b" ")
+            .unwrap();
+        assert_eq!(
+            other
+                .save_to(&path)
+                .unwrap_err()
+                .downcast_ref::<SaveError>(),
+            Some(&SaveError::ModifiedSinceLoad(path.clone()))
+        );
+        // Also when saved through another path to the same file
+        let other_path = dir
+            .join("..")
+            .join(dir.file_name().unwrap())
+            .join("abc.player");
+        assert!(other
+            .save_to(&other_path)
+            .unwrap_err()
+            .downcast_ref::<SaveError>()
+            .is_some());
+        // Saving elsewhere is still fine
+        other.save_to(dir.join("copy.player")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_json() {
+        let mut original = player(1);
+        original.contents.content = serde_json::from_value(json!({
+            "position": [1.5, 2.5],
+            "stats": {"health": 100.0, "level": 3},
+            "items": [{"count": 1, "durability": 0.5}],
+        }))
+        .unwrap();
+        let edited = serde_json::from_str(
+            r#"{
+                "position": [10, 20, 30],
+                "stats": {"health": 50, "level": 4, "added": 7},
+                "items": [{"count": 2, "durability": 1}, {"count": 5}]
+            }"#,
+        )
+        .unwrap();
+        let imported = Player::from_json(&original, edited, Some(31));
+        assert_eq!(imported.contents.identifier, "PlayerEntity");
+        assert_eq!(imported.contents.version, 31);
+        assert_eq!(
+            imported.contents.content,
+            serde_json::from_value(json!({
+                "position": [10.0, 20.0, 30.0],
+                "stats": {"health": 50.0, "level": 4, "added": 7},
+                "items": [{"count": 2, "durability": 1.0}, {"count": 5}],
+            }))
+            .unwrap()
+        );
+
+        let bytes = imported.to_bytes().unwrap();
+        let path = std::env::temp_dir().join(format!("import-{}.player", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        assert_eq!(Player::open(&path).unwrap().contents, imported.contents);
+
+        // The game saves between loading and importing
+        let loaded = Player::open(&path).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b" ")
+            .unwrap();
+        let mut imported = Player::from_json(&loaded, loaded.contents.content.clone(), None);
+        assert_eq!(
+            imported
+                .save_to(&path)
+                .unwrap_err()
+                .downcast_ref::<SaveError>(),
+            Some(&SaveError::ModifiedSinceLoad(path.clone()))
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
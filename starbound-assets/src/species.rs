@@ -0,0 +1,688 @@
+use crate::bson;
+use crate::item::ItemDescriptor;
+use crate::packed::PackedAssets;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+const HUMANOID_CONFIG: &str = "/humanoid.config";
+
+fn strings(v: Option<&Value>) -> Vec<String> {
+    v.and_then(|v| v.as_array())
+        .map(|a| {
+            a.iter()
+                .filter_map(|s| s.as_str())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn string(v: Option<&Value>) -> String {
+    v.and_then(|v| v.as_str()).unwrap_or_default().to_string()
+}
+
+fn flag(v: &Value, key: &str) -> bool {
+    v.get(key).and_then(|f| f.as_bool()).unwrap_or(false)
+}
+
+/// Palettes are written either as colour replacement maps or as literal
+/// directive strings.
+fn directives(v: Option<&Value>) -> Vec<String> {
+    v.and_then(|v| v.as_array())
+        .map(|a| {
+            a.iter()
+                .map(|p| match p {
+                    Value::Object(map) => {
+                        let mut d = "?replace".to_string();
+                        for (from, to) in map {
+                            let to = to.as_str().unwrap_or_default();
+                            d.push_str(&format!(";{}={}", from, to));
+                        }
+                        d
+                    }
+                    Value::String(s) => s.clone(),
+                    _ => String::new(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gender {
+    pub name: String,
+    pub hair_group: String,
+    pub hair: Vec<String>,
+    pub facial_hair_group: String,
+    pub facial_hair: Vec<String>,
+    pub facial_mask_group: String,
+    pub facial_mask: Vec<String>,
+    pub shirt: Vec<String>,
+    pub pants: Vec<String>,
+}
+
+impl Gender {
+    fn from_json(v: &Value) -> Option<Self> {
+        Some(Gender {
+            name: v.get("name")?.as_str()?.to_string(),
+            hair_group: string(v.get("hairGroup")),
+            hair: strings(v.get("hair")),
+            facial_hair_group: string(v.get("facialHairGroup")),
+            facial_hair: strings(v.get("facialHair")),
+            facial_mask_group: string(v.get("facialMaskGroup")),
+            facial_mask: strings(v.get("facialMask")),
+            shirt: strings(v.get("shirt")),
+            pants: strings(v.get("pants")),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Personality {
+    pub idle: String,
+    pub arm_idle: String,
+    pub head_offset: (f64, f64),
+    pub arm_offset: (f64, f64),
+}
+
+/// What the game falls back to without any configured personalities.
+impl Default for Personality {
+    fn default() -> Self {
+        Personality {
+            idle: "idle.1".to_string(),
+            arm_idle: "idle.1".to_string(),
+            head_offset: (0.0, 0.0),
+            arm_offset: (0.0, 0.0),
+        }
+    }
+}
+
+impl Personality {
+    fn from_json(v: &Value) -> Option<Self> {
+        let offset = |v: Option<&Value>| -> Option<(f64, f64)> {
+            let v = v?;
+            Some((v.get(0)?.as_f64()?, v.get(1)?.as_f64()?))
+        };
+        Some(Personality {
+            idle: v.get(0)?.as_str()?.to_string(),
+            arm_idle: v.get(1)?.as_str()?.to_string(),
+            head_offset: offset(v.get(2))?,
+            arm_offset: offset(v.get(3))?,
+        })
+    }
+
+    fn list(v: Option<&Value>) -> Vec<Self> {
+        v.and_then(|v| v.as_array())
+            .map(|a| a.iter().filter_map(Personality::from_json).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Indices into each of a species' character creation lists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CharacterOptions {
+    pub gender: usize,
+    pub body_color: usize,
+    /// Undy colour and/or facial mask, depending on the species.
+    pub alt: usize,
+    pub hair: usize,
+    /// Hair colour and/or facial hair, depending on the species.
+    pub head: usize,
+    pub shirt: usize,
+    pub pants: usize,
+    pub personality: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    pub name: String,
+    pub species: String,
+    pub gender: String,
+    pub hair_group: String,
+    pub hair_type: String,
+    pub hair_directives: String,
+    pub body_directives: String,
+    pub emote_directives: String,
+    pub facial_hair_group: String,
+    pub facial_hair_type: String,
+    pub facial_hair_directives: String,
+    pub facial_mask_group: String,
+    pub facial_mask_type: String,
+    pub facial_mask_directives: String,
+    pub personality_idle: String,
+    pub personality_arm_idle: String,
+    pub personality_head_offset: (f64, f64),
+    pub personality_arm_offset: (f64, f64),
+    pub color: (u8, u8, u8),
+}
+
+impl Identity {
+    pub fn to_value(&self) -> bson::Value {
+        serde_json::to_value(self)
+            .and_then(serde_json::from_value)
+            .expect("identity is always representable")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpeciesError {
+    UnknownSpecies(String),
+    OptionOutOfRange(&'static str, usize, usize),
+}
+
+impl std::fmt::Display for SpeciesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpeciesError::UnknownSpecies(s) => write!(f, "unknown species {}", s),
+            SpeciesError::OptionOutOfRange(option, idx, len) => write!(
+                f,
+                "{} option {} is out of range (species has {})",
+                option, idx, len
+            ),
+        }
+    }
+}
+
+impl Error for SpeciesError {}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Species {
+    pub kind: String,
+    pub genders: Vec<Gender>,
+    pub body_color: Vec<String>,
+    pub undy_color: Vec<String>,
+    pub hair_color: Vec<String>,
+    pub personalities: Vec<Personality>,
+    pub default_items: Vec<ItemDescriptor>,
+    pub default_blueprints: BTreeMap<String, Vec<ItemDescriptor>>,
+
+    pub alt_option_as_undy_color: bool,
+    pub alt_option_as_hair_color: bool,
+    pub alt_option_as_facial_mask: bool,
+    pub head_option_as_hair_color: bool,
+    pub head_option_as_facial_hair: bool,
+    pub hair_color_as_body_sub_color: bool,
+    pub body_color_as_facial_mask_sub_color: bool,
+    pub alt_color_as_facial_mask_sub_color: bool,
+}
+
+impl Species {
+    pub fn from_json(
+        v: &Value,
+        default_personalities: &[Personality],
+    ) -> Result<Self, Box<dyn Error>> {
+        let kind = v
+            .get("kind")
+            .and_then(|k| k.as_str())
+            .ok_or("species has no kind")?
+            .to_string();
+        let genders = v
+            .get("genders")
+            .and_then(|g| g.as_array())
+            .ok_or("species has no genders")?
+            .iter()
+            .map(|g| Gender::from_json(g).ok_or("invalid gender"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut personalities = Personality::list(v.get("personalities"));
+        if personalities.is_empty() {
+            personalities = default_personalities.to_vec();
+        }
+        let default_items = v
+            .get("defaultItems")
+            .and_then(|i| i.as_array())
+            .map(|i| i.iter().filter_map(ItemDescriptor::from_json).collect())
+            .unwrap_or_default();
+        let default_blueprints = v
+            .get("defaultBlueprints")
+            .and_then(|b| b.as_object())
+            .map(|b| {
+                b.iter()
+                    .map(|(tier, items)| {
+                        let items = items
+                            .as_array()
+                            .map(|i| i.iter().filter_map(ItemDescriptor::from_json).collect())
+                            .unwrap_or_default();
+                        (tier.clone(), items)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Species {
+            kind,
+            genders,
+            body_color: directives(v.get("bodyColor")),
+            undy_color: directives(v.get("undyColor")),
+            hair_color: directives(v.get("hairColor")),
+            personalities,
+            default_items,
+            default_blueprints,
+            alt_option_as_undy_color: flag(v, "altOptionAsUndyColor"),
+            alt_option_as_hair_color: flag(v, "altOptionAsHairColor"),
+            alt_option_as_facial_mask: flag(v, "altOptionAsFacialMask"),
+            head_option_as_hair_color: flag(v, "headOptionAsHairColor"),
+            head_option_as_facial_hair: flag(v, "headOptionAsFacialhair"),
+            hair_color_as_body_sub_color: flag(v, "hairColorAsBodySubColor"),
+            body_color_as_facial_mask_sub_color: flag(v, "bodyColorAsFacialMaskSubColor"),
+            alt_color_as_facial_mask_sub_color: flag(v, "altColorAsFacialMaskSubColor"),
+        })
+    }
+
+    /// The number of choices for each option of the given gender. Options a
+    /// species doesn't use have a single choice.
+    pub fn option_counts(&self, gender: usize) -> Option<CharacterOptions> {
+        let g = self.genders.get(gender)?;
+        let choices = |uses: bool, len: usize| if uses { std::cmp::max(len, 1) } else { 1 };
+        Some(CharacterOptions {
+            gender: self.genders.len(),
+            body_color: choices(true, self.body_color.len()),
+            alt: std::cmp::max(
+                choices(self.alt_option_as_undy_color, self.undy_color.len()),
+                choices(self.alt_option_as_facial_mask, g.facial_mask.len()),
+            ),
+            hair: choices(true, g.hair.len()),
+            head: std::cmp::max(
+                choices(self.head_option_as_hair_color, self.hair_color.len()),
+                choices(self.head_option_as_facial_hair, g.facial_hair.len()),
+            ),
+            shirt: choices(true, g.shirt.len()),
+            pants: choices(true, g.pants.len()),
+            personality: choices(true, self.personalities.len()),
+        })
+    }
+
+    pub fn combination_count(&self) -> u64 {
+        (0..self.genders.len())
+            .filter_map(|g| self.option_counts(g))
+            .map(|c| {
+                [
+                    c.body_color,
+                    c.alt,
+                    c.hair,
+                    c.head,
+                    c.shirt,
+                    c.pants,
+                    c.personality,
+                ]
+                .iter()
+                .map(|&n| n as u64)
+                .product::<u64>()
+            })
+            .sum()
+    }
+
+    /// Every valid set of creation options, gender by gender.
+    pub fn combinations(&self) -> Combinations<'_> {
+        Combinations {
+            species: self,
+            counts: self.option_counts(0),
+            next: Some(CharacterOptions::default()),
+        }
+    }
+
+    pub fn identity(
+        &self,
+        name: &str,
+        options: &CharacterOptions,
+    ) -> Result<Identity, SpeciesError> {
+        let pick =
+            |option: &'static str, list: &[String], idx: usize| -> Result<String, SpeciesError> {
+                match list.get(idx) {
+                    Some(s) => Ok(s.clone()),
+                    None if list.is_empty() && idx == 0 => Ok(String::new()),
+                    None => Err(SpeciesError::OptionOutOfRange(option, idx, list.len())),
+                }
+            };
+        // The alt and head options can pick from two lists at once, counting
+        // up to the longer one; the game wraps around the shorter
+        let wrap = |list: &[String], idx: usize| -> String {
+            match list.len() {
+                0 => String::new(),
+                len => list[idx % len].clone(),
+            }
+        };
+        let counts = self
+            .option_counts(options.gender)
+            .ok_or(SpeciesError::OptionOutOfRange(
+                "gender",
+                options.gender,
+                self.genders.len(),
+            ))?;
+        let check = |option: &'static str, idx: usize, count: usize| {
+            if idx < count {
+                Ok(())
+            } else {
+                Err(SpeciesError::OptionOutOfRange(option, idx, count))
+            }
+        };
+        check("alt", options.alt, counts.alt)?;
+        check("head", options.head, counts.head)?;
+        check("shirt", options.shirt, counts.shirt)?;
+        check("pants", options.pants, counts.pants)?;
+        check("personality", options.personality, counts.personality)?;
+
+        let gender = &self.genders[options.gender];
+        let personality = self
+            .personalities
+            .get(options.personality)
+            .cloned()
+            .unwrap_or_default();
+
+        let mut body_color = pick("body color", &self.body_color, options.body_color)?;
+        let alt_color = if self.alt_option_as_undy_color {
+            wrap(&self.undy_color, options.alt)
+        } else {
+            String::new()
+        };
+        let hair_type = pick("hair", &gender.hair, options.hair)?;
+
+        let mut hair_color = body_color.clone();
+        if self.head_option_as_hair_color {
+            hair_color = wrap(&self.hair_color, options.head);
+            if self.alt_option_as_hair_color {
+                hair_color.push_str(&alt_color);
+            }
+        }
+        if self.hair_color_as_body_sub_color {
+            body_color.push_str(&hair_color);
+        }
+
+        let (facial_hair_group, facial_hair_type) = if self.head_option_as_facial_hair {
+            (
+                gender.facial_hair_group.clone(),
+                wrap(&gender.facial_hair, options.head),
+            )
+        } else {
+            (String::new(), String::new())
+        };
+
+        let (facial_mask_group, facial_mask_type) = if self.alt_option_as_facial_mask {
+            (
+                gender.facial_mask_group.clone(),
+                wrap(&gender.facial_mask, options.alt),
+            )
+        } else {
+            (String::new(), String::new())
+        };
+        let mut facial_mask_directives = String::new();
+        if self.body_color_as_facial_mask_sub_color {
+            facial_mask_directives.push_str(&body_color);
+        }
+        if self.alt_color_as_facial_mask_sub_color {
+            facial_mask_directives.push_str(&alt_color);
+        }
+
+        Ok(Identity {
+            name: name.to_string(),
+            species: self.kind.clone(),
+            gender: gender.name.clone(),
+            hair_group: gender.hair_group.clone(),
+            hair_type,
+            hair_directives: hair_color.clone(),
+            body_directives: format!("{}{}", body_color, alt_color),
+            emote_directives: format!("{}{}", body_color, alt_color),
+            facial_hair_group,
+            facial_hair_type,
+            facial_hair_directives: hair_color,
+            facial_mask_group,
+            facial_mask_type,
+            facial_mask_directives,
+            personality_idle: personality.idle,
+            personality_arm_idle: personality.arm_idle,
+            personality_head_offset: personality.head_offset,
+            personality_arm_offset: personality.arm_offset,
+            color: (51, 117, 237),
+        })
+    }
+
+    /// The clothing picked during creation plus the species' default items.
+    pub fn starting_items(&self, options: &CharacterOptions) -> Vec<ItemDescriptor> {
+        let mut items = Vec::new();
+        if let Some(gender) = self.genders.get(options.gender) {
+            if let Some(shirt) = gender.shirt.get(options.shirt) {
+                items.push(ItemDescriptor::new(shirt, 1));
+            }
+            if let Some(pants) = gender.pants.get(options.pants) {
+                items.push(ItemDescriptor::new(pants, 1));
+            }
+        }
+        items.extend(self.default_items.iter().cloned());
+        items
+    }
+}
+
+pub struct Combinations<'a> {
+    species: &'a Species,
+    counts: Option<CharacterOptions>,
+    next: Option<CharacterOptions>,
+}
+
+impl<'a> Iterator for Combinations<'a> {
+    type Item = CharacterOptions;
+
+    fn next(&mut self) -> Option<CharacterOptions> {
+        let counts = self.counts?;
+        let current = self.next?;
+
+        let mut next = current;
+        let digits: [(&mut usize, usize); 7] = [
+            (&mut next.personality, counts.personality),
+            (&mut next.pants, counts.pants),
+            (&mut next.shirt, counts.shirt),
+            (&mut next.head, counts.head),
+            (&mut next.hair, counts.hair),
+            (&mut next.alt, counts.alt),
+            (&mut next.body_color, counts.body_color),
+        ];
+        let mut carried = true;
+        for (digit, count) in digits {
+            *digit += 1;
+            if *digit < count {
+                carried = false;
+                break;
+            }
+            *digit = 0;
+        }
+
+        if carried {
+            let gender = current.gender + 1;
+            self.counts = self.species.option_counts(gender);
+            self.next = self.counts.map(|_| CharacterOptions {
+                gender,
+                ..CharacterOptions::default()
+            });
+        } else {
+            self.next = Some(next);
+        }
+        Some(current)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SpeciesDatabase {
+    species: BTreeMap<String, Species>,
+}
+
+impl SpeciesDatabase {
+    pub fn new(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let personalities = if assets.contains(HUMANOID_CONFIG) {
+            Personality::list(assets.json(HUMANOID_CONFIG)?.get("personalities"))
+        } else {
+            Vec::new()
+        };
+
+        let mut db = Self::default();
+        for path in assets.assets_with_extension("species") {
+            let species = Species::from_json(&assets.json(path)?, &personalities)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            db.add(species);
+        }
+        Ok(db)
+    }
+
+    pub fn add(&mut self, species: Species) {
+        self.species.insert(species.kind.clone(), species);
+    }
+
+    pub fn species(&self) -> impl Iterator<Item = &Species> {
+        self.species.values()
+    }
+
+    pub fn get(&self, kind: &str) -> Option<&Species> {
+        self.species.get(kind)
+    }
+
+    pub fn identity(
+        &self,
+        kind: &str,
+        name: &str,
+        options: &CharacterOptions,
+    ) -> Result<Identity, SpeciesError> {
+        self.get(kind)
+            .ok_or_else(|| SpeciesError::UnknownSpecies(kind.to_string()))?
+            .identity(name, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn avian() -> Species {
+        Species::from_json(
+            &json!({
+                "kind": "avian",
+                "genders": [
+                    {
+                        "name": "male",
+                        "hairGroup": "hair",
+                        "hair": ["1", "2"],
+                        "facialHairGroup": "fluff",
+                        "facialHair": ["1", "2", "3"],
+                        "facialMaskGroup": "beaks",
+                        "facialMask": ["1", "2"],
+                        "shirt": ["aviantier0chest"],
+                        "pants": ["aviantier0pants"]
+                    },
+                    {
+                        "name": "female",
+                        "hairGroup": "hair",
+                        "hair": ["1"],
+                        "facialHairGroup": "fluff",
+                        "facialHair": ["1"],
+                        "facialMaskGroup": "beaks",
+                        "facialMask": ["1"]
+                    }
+                ],
+                "bodyColor": [{"ffca8a": "f0608b", "e0975c": "cd5061"}, {"ffca8a": "aaaaaa"}],
+                "undyColor": [""],
+                "hairColor": [{"735e3a": "977841"}],
+                "altOptionAsFacialMask": true,
+                "headOptionAsFacialhair": true,
+                "bodyColorAsFacialMaskSubColor": true
+            }),
+            &[Personality {
+                idle: "idle.1".to_string(),
+                arm_idle: "idle.1".to_string(),
+                head_offset: (0.0, 0.0),
+                arm_offset: (0.0, 0.0),
+            }],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_combinations() {
+        let species = avian();
+        // male: 2 body * 2 mask * 2 hair * 3 facial hair, female: 2 body
+        assert_eq!(species.combination_count(), 24 + 2);
+        let all = species.combinations().collect::<Vec<_>>();
+        assert_eq!(all.len() as u64, species.combination_count());
+        assert!(all.iter().all(|o| species.identity("x", o).is_ok()));
+        assert_eq!(all.last().unwrap().gender, 1);
+    }
+
+    #[test]
+    fn test_shared_options() {
+        // Both alt and both head flags, with lists of different lengths and
+        // no personalities
+        let species = Species::from_json(
+            &json!({
+                "kind": "novakid",
+                "genders": [{
+                    "name": "male",
+                    "hair": ["1"],
+                    "facialHair": ["1", "2", "3"],
+                    "facialMask": ["1", "2"]
+                }],
+                "bodyColor": [""],
+                "undyColor": ["?a", "?b", "?c"],
+                "hairColor": ["?h"],
+                "altOptionAsUndyColor": true,
+                "altOptionAsFacialMask": true,
+                "headOptionAsHairColor": true,
+                "headOptionAsFacialhair": true
+            }),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(species.combination_count(), 3 * 3);
+        for options in species.combinations() {
+            species.identity("x", &options).unwrap();
+        }
+        let options = CharacterOptions {
+            alt: 2,
+            head: 1,
+            ..Default::default()
+        };
+        let identity = species.identity("x", &options).unwrap();
+        assert_eq!(identity.facial_mask_type, "1");
+        assert_eq!(identity.body_directives, "?c");
+        assert_eq!(identity.hair_directives, "?h");
+        assert_eq!(identity.facial_hair_type, "2");
+        assert_eq!(identity.personality_idle, "idle.1");
+    }
+
+    #[test]
+    fn test_identity() {
+        let species = avian();
+        let options = CharacterOptions {
+            alt: 1,
+            head: 2,
+            ..Default::default()
+        };
+        let identity = species.identity("Ixtlin", &options).unwrap();
+        assert_eq!(
+            identity.body_directives,
+            "?replace;ffca8a=f0608b;e0975c=cd5061"
+        );
+        assert_eq!(identity.facial_mask_directives, identity.body_directives);
+        assert_eq!(identity.facial_hair_type, "3");
+        assert_eq!(identity.facial_mask_type, "2");
+        assert_eq!(
+            species.identity(
+                "x",
+                &CharacterOptions {
+                    hair: 2,
+                    ..Default::default()
+                }
+            ),
+            Err(SpeciesError::OptionOutOfRange("hair", 2, 2))
+        );
+
+        match identity.to_value() {
+            bson::Value::Object(o) => {
+                assert_eq!(o["species"], bson::Value::String("avian".to_string()));
+                assert_eq!(
+                    o["personalityArmOffset"],
+                    bson::Value::Array(vec![bson::Value::Float(0.0), bson::Value::Float(0.0)])
+                );
+            }
+            _ => panic!("identity is not an object"),
+        }
+    }
+}
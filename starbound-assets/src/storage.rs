@@ -0,0 +1,274 @@
+use crate::bson::Value;
+use crate::packed::Player;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Player,
+    Ship,
+    PlayerMetadata,
+    /// A rotated copy, `.bak1` being the newest.
+    Backup {
+        of: Box<FileKind>,
+        generation: u32,
+    },
+    World,
+    System,
+    UniverseChunks,
+    Universe,
+    Config,
+    Log,
+    Other,
+}
+
+impl FileKind {
+    /// Classifies `name`, a file directly inside `dir` relative to storage.
+    fn classify(dir: &Path, name: &str) -> Self {
+        if let Some((original, bak)) = name.rsplit_once(".bak") {
+            if let Ok(generation) = bak.parse() {
+                return FileKind::Backup {
+                    of: Box::new(FileKind::classify(dir, original)),
+                    generation,
+                };
+            }
+        }
+        let ext = Path::new(name).extension().and_then(|e| e.to_str());
+        let top = dir.components().next().and_then(|c| c.as_os_str().to_str());
+        match (top, ext) {
+            (Some("player"), Some("player")) => FileKind::Player,
+            (Some("player"), Some("shipworld")) => FileKind::Ship,
+            (Some("player"), Some("metadata")) => FileKind::PlayerMetadata,
+            (Some("universe"), _) if name == "universe.chunks" => FileKind::UniverseChunks,
+            (Some("universe"), Some("world")) => FileKind::World,
+            (Some("universe"), Some("system")) => FileKind::System,
+            (Some("universe"), _) => FileKind::Universe,
+            (None, Some("config")) => FileKind::Config,
+            (_, Some("log")) => FileKind::Log,
+            _ => FileKind::Other,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageFile {
+    /// Relative to the storage directory.
+    pub path: PathBuf,
+    pub kind: FileKind,
+}
+
+/// A character's files. Nothing is read until asked for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Character {
+    pub uuid: String,
+    pub player: Option<PathBuf>,
+    pub ship: Option<PathBuf>,
+    pub metadata: Option<PathBuf>,
+    /// Backups of any of the above, in file name order.
+    pub backups: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharacterSummary {
+    pub name: String,
+    pub species: String,
+    /// Seconds played.
+    pub play_time: f64,
+}
+
+impl Character {
+    pub fn load(&self) -> Result<Player, Box<dyn Error>> {
+        let path = self
+            .player
+            .as_ref()
+            .ok_or_else(|| format!("character {} has no player file", self.uuid))?;
+        Player::open(path)
+    }
+
+    pub fn summary(&self) -> Result<CharacterSummary, Box<dyn Error>> {
+        let player = self.load()?;
+        let content = &player.contents.content;
+        let text = |pointer| content.pointer(pointer).and_then(Value::as_str);
+        let name = text("/identity/name").unwrap_or_default().to_string();
+        let species = text("/identity/species").unwrap_or_default().to_string();
+        let play_time = content["log"]["playTime"].as_f64().unwrap_or(0.0);
+        Ok(CharacterSummary {
+            name,
+            species,
+            play_time,
+        })
+    }
+}
+
+/// A game `storage/` directory.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<StorageFile>,
+}
+
+fn scan(root: &Path, dir: &Path, files: &mut Vec<StorageFile>) -> Result<(), Box<dyn Error>> {
+    let mut entries = std::fs::read_dir(root.join(dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let path = dir.join(&name);
+        if entry.file_type()?.is_dir() {
+            scan(root, &path, files)?;
+        } else {
+            let kind = FileKind::classify(dir, &name.to_string_lossy());
+            files.push(StorageFile { path, kind });
+        }
+    }
+    Ok(())
+}
+
+impl Storage {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, Box<dyn Error>> {
+        let root = root.as_ref().to_path_buf();
+        let mut files = Vec::new();
+        scan(&root, Path::new(""), &mut files).map_err(|e| format!("{}: {}", root.display(), e))?;
+        Ok(Storage { root, files })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    pub fn path(&self, file: &StorageFile) -> PathBuf {
+        self.root.join(&file.path)
+    }
+
+    pub fn files_of_kind(&self, kind: &FileKind) -> impl Iterator<Item = &StorageFile> + '_ {
+        let kind = kind.clone();
+        self.files.iter().filter(move |f| f.kind == kind)
+    }
+
+    pub fn config(&self) -> Option<PathBuf> {
+        self.files_of_kind(&FileKind::Config)
+            .find(|f| f.path == Path::new("starbound.config"))
+            .map(|f| self.path(f))
+    }
+
+    /// Every character in `player/`, keyed by uuid, with absolute paths.
+    pub fn characters(&self) -> BTreeMap<String, Character> {
+        let mut characters = BTreeMap::new();
+        for file in &self.files {
+            let uuid = match file.path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.split('.').next().unwrap_or(name).to_string(),
+                None => continue,
+            };
+            let is_character_file = |kind: &FileKind| {
+                matches!(
+                    kind,
+                    FileKind::Player | FileKind::Ship | FileKind::PlayerMetadata
+                )
+            };
+            let is_backup = match &file.kind {
+                FileKind::Backup { of, .. } if is_character_file(of) => true,
+                kind if is_character_file(kind) => false,
+                _ => continue,
+            };
+            let c = characters.entry(uuid.clone()).or_insert_with(|| Character {
+                uuid,
+                ..Character::default()
+            });
+            let path = Some(self.path(file));
+            match file.kind {
+                _ if is_backup => c.backups.extend(path),
+                FileKind::Player => c.player = path,
+                FileKind::Ship => c.ship = path,
+                _ => c.metadata = path,
+            }
+        }
+        characters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packed::{save_versioned_json, VersionedJson};
+    use serde_json::json;
+
+    #[test]
+    fn test_scan() {
+        let root = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        let uuid = "c75356ebfb10a0111500b4985132688b";
+        std::fs::create_dir_all(root.join("player")).unwrap();
+        std::fs::create_dir_all(root.join("universe")).unwrap();
+        let mut player = VersionedJson {
+            identifier: "PlayerEntity".to_string(),
+            version: 30,
+            content: serde_json::from_value(json!({
+                "identity": {"name": "Nova", "species": "novakid"},
+                "log": {"playTime": 3600.5},
+            }))
+            .unwrap(),
+        };
+        let player = save_versioned_json(&mut player).unwrap();
+        for name in &[
+            format!("player/{}.player", uuid),
+            format!("player/{}.player.bak1", uuid),
+            format!("player/{}.shipworld", uuid),
+            format!("player/{}.metadata", uuid),
+            "player/0123.shipworld".to_string(),
+            "universe/universe.chunks".to_string(),
+            "universe/1_2_3.system".to_string(),
+            "universe/CelestialWorld_1_2_3_4.world".to_string(),
+            "starbound.config".to_string(),
+            "starbound.log".to_string(),
+        ] {
+            std::fs::write(root.join(name), &player).unwrap();
+        }
+
+        let storage = Storage::open(&root).unwrap();
+        let kinds = storage
+            .files()
+            .iter()
+            .map(|f| (f.path.to_str().unwrap().to_string(), f.kind.clone()))
+            .collect::<BTreeMap<_, _>>();
+        assert_eq!(kinds["universe/universe.chunks"], FileKind::UniverseChunks);
+        assert_eq!(kinds["universe/1_2_3.system"], FileKind::System);
+        assert_eq!(
+            kinds["universe/CelestialWorld_1_2_3_4.world"],
+            FileKind::World
+        );
+        assert_eq!(kinds["starbound.log"], FileKind::Log);
+        assert_eq!(
+            kinds[&format!("player/{}.player.bak1", uuid)],
+            FileKind::Backup {
+                of: Box::new(FileKind::Player),
+                generation: 1
+            }
+        );
+        assert_eq!(storage.config(), Some(root.join("starbound.config")));
+
+        let characters = storage.characters();
+        assert_eq!(characters.len(), 2);
+        let c = &characters[uuid];
+        assert_eq!(
+            c.ship,
+            Some(root.join(format!("player/{}.shipworld", uuid)))
+        );
+        assert!(c.metadata.is_some());
+        assert_eq!(c.backups.len(), 1);
+        assert_eq!(
+            c.summary().unwrap(),
+            CharacterSummary {
+                name: "Nova".to_string(),
+                species: "novakid".to_string(),
+                play_time: 3600.5,
+            }
+        );
+        // A ship left behind by a deleted character
+        assert!(characters["0123"].player.is_none());
+        assert!(characters["0123"].summary().is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use crate::bson::{Map, Value};
+use crate::packed::{PackedAssets, Player};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum TechSlot {
+    Head,
+    Body,
+    Legs,
+}
+
+impl TechSlot {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TechSlot::Head => "head",
+            TechSlot::Body => "body",
+            TechSlot::Legs => "legs",
+        }
+    }
+}
+
+impl std::fmt::Display for TechSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for TechSlot {
+    type Err = TechError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "head" => Ok(TechSlot::Head),
+            "body" => Ok(TechSlot::Body),
+            "legs" => Ok(TechSlot::Legs),
+            _ => Err(TechError::UnknownSlot(s.to_string())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tech {
+    pub name: String,
+    pub path: String,
+    pub slot: TechSlot,
+    pub short_description: String,
+    pub description: String,
+}
+
+impl Tech {
+    pub fn from_json(path: &str, v: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let text = |key: &str| {
+            v.get(key)
+                .and_then(|t| t.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let name = v
+            .get("name")
+            .and_then(|n| n.as_str())
+            .ok_or("tech has no name")?
+            .to_string();
+        let slot = v
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or("tech has no type")?
+            .parse()?;
+        Ok(Tech {
+            name,
+            path: path.to_string(),
+            slot,
+            short_description: text("shortDescription"),
+            description: text("description"),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TechError {
+    UnknownTech(String),
+    UnknownSlot(String),
+    WrongSlot {
+        tech: String,
+        slot: TechSlot,
+        expected: TechSlot,
+    },
+    MalformedPlayer(&'static str),
+}
+
+impl std::fmt::Display for TechError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TechError::UnknownTech(t) => write!(f, "unknown tech {}", t),
+            TechError::UnknownSlot(s) => write!(f, "unknown tech slot {}", s),
+            TechError::WrongSlot {
+                tech,
+                slot,
+                expected,
+            } => write!(
+                f,
+                "tech {} goes in the {} slot, not {}",
+                tech, expected, slot
+            ),
+            TechError::MalformedPlayer(what) => write!(f, "malformed player techs: {}", what),
+        }
+    }
+}
+
+impl Error for TechError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct TechDatabase {
+    techs: BTreeMap<String, Tech>,
+}
+
+impl TechDatabase {
+    pub fn new(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let mut db = Self::default();
+        for path in assets.assets_with_extension("tech") {
+            let tech = Tech::from_json(path, &assets.json(path)?)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            db.add(tech);
+        }
+        Ok(db)
+    }
+
+    pub fn add(&mut self, tech: Tech) {
+        self.techs.insert(tech.name.clone(), tech);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tech> {
+        self.techs.get(name)
+    }
+
+    pub fn techs(&self) -> impl Iterator<Item = &Tech> {
+        self.techs.values()
+    }
+
+    pub fn in_slot(&self, slot: TechSlot) -> impl Iterator<Item = &Tech> {
+        self.techs.values().filter(move |t| t.slot == slot)
+    }
+
+    fn lookup(&self, name: &str) -> Result<&Tech, TechError> {
+        self.get(name)
+            .ok_or_else(|| TechError::UnknownTech(name.to_string()))
+    }
+}
+
+fn names(list: Option<&Value>) -> Vec<String> {
+    match list {
+        Some(Value::Array(a)) => a
+            .iter()
+            .filter_map(|v| match v {
+                Value::String(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn add_name(techs: &mut Map, key: &str, name: &str) -> Result<(), TechError> {
+    match techs
+        .entry(key.to_string())
+        .or_insert_with(|| Value::Array(Vec::new()))
+    {
+        Value::Array(a) => {
+            if !a.iter().any(|v| *v == Value::String(name.to_string())) {
+                a.push(Value::String(name.to_string()));
+            }
+            Ok(())
+        }
+        _ => Err(TechError::MalformedPlayer("tech list is not an array")),
+    }
+}
+
+impl Player {
+    fn techs(&self) -> Option<&Map> {
+        match &self.contents.content {
+            Value::Object(o) => match o.get("techs") {
+                Some(Value::Object(techs)) => Some(techs),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn techs_mut(&mut self) -> Result<&mut Map, TechError> {
+        let content = match &mut self.contents.content {
+            Value::Object(o) => o,
+            _ => return Err(TechError::MalformedPlayer("player is not an object")),
+        };
+        match content
+            .entry("techs".to_string())
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(techs) => Ok(techs),
+            _ => Err(TechError::MalformedPlayer("techs is not an object")),
+        }
+    }
+
+    pub fn available_techs(&self) -> Vec<String> {
+        names(self.techs().and_then(|t| t.get("availableTechs")))
+    }
+
+    pub fn enabled_techs(&self) -> Vec<String> {
+        names(self.techs().and_then(|t| t.get("enabledTechs")))
+    }
+
+    pub fn equipped_tech(&self, slot: TechSlot) -> Option<String> {
+        match self.techs()?.get("equippedTechs")? {
+            Value::Object(equipped) => match equipped.get(slot.as_str())? {
+                Value::String(s) => Some(s.clone()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Makes a tech available to buy at the tech station.
+    pub fn unlock_tech(&mut self, db: &TechDatabase, name: &str) -> Result<(), TechError> {
+        db.lookup(name)?;
+        add_name(self.techs_mut()?, "availableTechs", name)
+    }
+
+    /// Enables a tech so it can be equipped, unlocking it if necessary.
+    pub fn enable_tech(&mut self, db: &TechDatabase, name: &str) -> Result<(), TechError> {
+        db.lookup(name)?;
+        let techs = self.techs_mut()?;
+        add_name(techs, "availableTechs", name)?;
+        add_name(techs, "enabledTechs", name)
+    }
+
+    /// Equips a tech in `slot`, enabling it if necessary.
+    pub fn equip_tech(
+        &mut self,
+        db: &TechDatabase,
+        slot: TechSlot,
+        name: &str,
+    ) -> Result<(), TechError> {
+        let tech = db.lookup(name)?;
+        if tech.slot != slot {
+            return Err(TechError::WrongSlot {
+                tech: name.to_string(),
+                slot,
+                expected: tech.slot,
+            });
+        }
+        self.enable_tech(db, name)?;
+        match self
+            .techs_mut()?
+            .entry("equippedTechs".to_string())
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(equipped) => {
+                equipped.insert(slot.as_str().to_string(), Value::String(name.to_string()));
+                Ok(())
+            }
+            _ => Err(TechError::MalformedPlayer("equippedTechs is not an object")),
+        }
+    }
+
+    pub fn unequip_tech(&mut self, slot: TechSlot) -> Result<Option<String>, TechError> {
+        match self.techs_mut()?.get_mut("equippedTechs") {
+            Some(Value::Object(equipped)) => Ok(match equipped.remove(slot.as_str()) {
+                Some(Value::String(s)) => Some(s),
+                _ => None,
+            }),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packed::VersionedJson;
+    use serde_json::json;
+
+    fn player() -> Player {
+        Player {
+            contents: VersionedJson {
+                identifier: "PlayerEntity".to_string(),
+                version: 30,
+                content: serde_json::from_value(json!({
+                    "techs": {"availableTechs": [], "enabledTechs": [], "equippedTechs": {}}
+                }))
+                .unwrap(),
+            },
+            source: None,
+        }
+    }
+
+    fn database() -> TechDatabase {
+        let mut db = TechDatabase::default();
+        for (name, slot) in &[("dash", "head"), ("doublejump", "legs")] {
+            let tech = Tech::from_json(
+                &format!("/tech/{}.tech", name),
+                &json!({"name": name, "type": slot}),
+            )
+            .unwrap();
+            db.add(tech);
+        }
+        db
+    }
+
+    #[test]
+    fn test_equip() {
+        let db = database();
+        let mut p = player();
+        p.equip_tech(&db, TechSlot::Head, "dash").unwrap();
+        assert_eq!(p.available_techs(), vec!["dash"]);
+        assert_eq!(p.enabled_techs(), vec!["dash"]);
+        assert_eq!(p.equipped_tech(TechSlot::Head), Some("dash".to_string()));
+        assert_eq!(p.unequip_tech(TechSlot::Head), Ok(Some("dash".to_string())));
+        assert_eq!(p.equipped_tech(TechSlot::Head), None);
+    }
+
+    #[test]
+    fn test_validation() {
+        let db = database();
+        let mut p = player();
+        assert_eq!(
+            p.unlock_tech(&db, "blink"),
+            Err(TechError::UnknownTech("blink".to_string()))
+        );
+        assert_eq!(
+            p.equip_tech(&db, TechSlot::Body, "doublejump"),
+            Err(TechError::WrongSlot {
+                tech: "doublejump".to_string(),
+                slot: TechSlot::Body,
+                expected: TechSlot::Legs,
+            })
+        );
+        p.unlock_tech(&db, "doublejump").unwrap();
+        p.unlock_tech(&db, "doublejump").unwrap();
+        assert_eq!(p.available_techs(), vec!["doublejump"]);
+        assert!(p.enabled_techs().is_empty());
+    }
+}
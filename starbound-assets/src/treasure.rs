@@ -0,0 +1,415 @@
+use crate::item::{json_u64, ItemDescriptor};
+use crate::packed::PackedAssets;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PoolEntry {
+    Item(ItemDescriptor),
+    Pool(String),
+}
+
+impl PoolEntry {
+    fn from_json(v: &Value) -> Option<Self> {
+        if let Some(pool) = v.get("pool").and_then(|p| p.as_str()) {
+            return Some(PoolEntry::Pool(pool.to_string()));
+        }
+        v.get("item")
+            .and_then(ItemDescriptor::from_json)
+            .map(PoolEntry::Item)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PoolLevel {
+    pub level: f64,
+    pub fill: Vec<PoolEntry>,
+    pub pool: Vec<(f64, PoolEntry)>,
+    pub pool_rounds: Vec<(f64, u64)>,
+    pub allow_duplication: bool,
+}
+
+impl PoolLevel {
+    fn from_json(level: f64, v: &Value) -> Result<Self, Box<dyn Error>> {
+        let entries = |key: &str| {
+            v.get(key)
+                .and_then(|e| e.as_array())
+                .map(|e| e.as_slice())
+                .unwrap_or_default()
+        };
+
+        let fill = entries("fill")
+            .iter()
+            .map(|e| PoolEntry::from_json(e).ok_or("invalid fill entry"))
+            .collect::<Result<_, _>>()?;
+        let pool = entries("pool")
+            .iter()
+            .map(|e| {
+                let weight = e.get("weight").and_then(|w| w.as_f64()).unwrap_or(1.0);
+                PoolEntry::from_json(e)
+                    .map(|e| (weight, e))
+                    .ok_or("invalid pool entry")
+            })
+            .collect::<Result<_, _>>()?;
+        let pool_rounds = match v.get("poolRounds") {
+            Some(Value::Array(rounds)) => rounds
+                .iter()
+                .map(|r| {
+                    let weight = r.get(0).and_then(|w| w.as_f64());
+                    let count = r.get(1).and_then(json_u64);
+                    weight.zip(count).ok_or("invalid poolRounds entry")
+                })
+                .collect::<Result<_, _>>()?,
+            Some(n) => vec![(1.0, json_u64(n).ok_or("invalid poolRounds")?)],
+            None => vec![(1.0, 1)],
+        };
+        let allow_duplication = v
+            .get("allowDuplication")
+            .and_then(|d| d.as_bool())
+            .unwrap_or(true);
+
+        Ok(PoolLevel {
+            level,
+            fill,
+            pool,
+            pool_rounds,
+            allow_duplication,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TreasurePool {
+    pub name: String,
+    /// Sorted by ascending level.
+    pub levels: Vec<PoolLevel>,
+}
+
+impl TreasurePool {
+    pub fn from_json(name: &str, v: &Value) -> Result<Self, Box<dyn Error>> {
+        let mut levels = v
+            .as_array()
+            .ok_or("treasure pool is not a list of levels")?
+            .iter()
+            .map(|l| {
+                let level = l
+                    .get(0)
+                    .and_then(|l| l.as_f64())
+                    .filter(|l| l.is_finite())
+                    .ok_or("invalid level")?;
+                let config = l.get(1).ok_or("missing level config")?;
+                PoolLevel::from_json(level, config)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        levels.sort_by(|a, b| a.level.total_cmp(&b.level));
+        Ok(TreasurePool {
+            name: name.to_string(),
+            levels,
+        })
+    }
+
+    /// The highest level config at or below `level`, or the lowest one when
+    /// `level` is below all of them.
+    pub fn at_level(&self, level: f64) -> Option<&PoolLevel> {
+        self.levels
+            .iter()
+            .rev()
+            .find(|l| l.level <= level)
+            .or_else(|| self.levels.first())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TreasureError {
+    UnknownPool(String),
+    TooDeep(Vec<String>),
+}
+
+impl std::fmt::Display for TreasureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreasureError::UnknownPool(pool) => write!(f, "unknown treasure pool {}", pool),
+            TreasureError::TooDeep(pools) => {
+                write!(f, "treasure pools nest too deeply: {}", pools.join(" -> "))
+            }
+        }
+    }
+}
+
+impl Error for TreasureError {}
+
+/// A small splitmix64 generator, so rolls are reproducible from a seed
+/// regardless of platform.
+#[derive(Clone, Debug)]
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn weighted<'a, T>(&mut self, choices: &'a [(f64, T)]) -> Option<&'a T> {
+        let total: f64 = choices.iter().map(|(w, _)| w).sum();
+        if choices.is_empty() || total <= 0.0 {
+            return None;
+        }
+        let mut target = self.next_f64() * total;
+        for (w, choice) in choices {
+            if target < *w {
+                return Some(choice);
+            }
+            target -= w;
+        }
+        choices.last().map(|(_, c)| c)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DropRate {
+    /// Fraction of rolls that dropped the item at all.
+    pub chance: f64,
+    /// Mean number of the item dropped per roll.
+    pub mean_count: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TreasureDatabase {
+    pools: BTreeMap<String, TreasurePool>,
+}
+
+impl TreasureDatabase {
+    pub fn new(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let mut db = Self::default();
+        for path in assets.assets_with_extension("treasurepools") {
+            db.add_file(&assets.json(path)?)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        Ok(db)
+    }
+
+    pub fn add_file(&mut self, v: &Value) -> Result<(), Box<dyn Error>> {
+        let pools = v.as_object().ok_or("treasure pool file is not an object")?;
+        for (name, pool) in pools {
+            let pool =
+                TreasurePool::from_json(name, pool).map_err(|e| format!("{}: {}", name, e))?;
+            self.pools.insert(name.clone(), pool);
+        }
+        Ok(())
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &TreasurePool> {
+        self.pools.values()
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&TreasurePool> {
+        self.pools.get(name)
+    }
+
+    pub fn roll(
+        &self,
+        pool: &str,
+        level: f64,
+        seed: u64,
+    ) -> Result<Vec<ItemDescriptor>, TreasureError> {
+        self.roll_with(pool, level, &mut Random::new(seed))
+    }
+
+    pub fn roll_with(
+        &self,
+        pool: &str,
+        level: f64,
+        rng: &mut Random,
+    ) -> Result<Vec<ItemDescriptor>, TreasureError> {
+        let mut out = Vec::new();
+        let mut stack = Vec::new();
+        self.roll_into(pool, level, rng, &mut stack, &mut out)?;
+        Ok(out)
+    }
+
+    fn roll_into(
+        &self,
+        pool: &str,
+        level: f64,
+        rng: &mut Random,
+        stack: &mut Vec<String>,
+        out: &mut Vec<ItemDescriptor>,
+    ) -> Result<(), TreasureError> {
+        stack.push(pool.to_string());
+        if stack.len() > MAX_DEPTH {
+            return Err(TreasureError::TooDeep(stack.clone()));
+        }
+        let config = self
+            .pools
+            .get(pool)
+            .and_then(|p| p.at_level(level))
+            .ok_or_else(|| TreasureError::UnknownPool(pool.to_string()))?;
+
+        for entry in &config.fill {
+            self.roll_entry(entry, level, rng, stack, out)?;
+        }
+
+        let rounds = rng.weighted(&config.pool_rounds).copied().unwrap_or(0);
+        let mut rolled = BTreeSet::new();
+        for _ in 0..rounds {
+            let entry = match rng.weighted(&config.pool) {
+                Some(entry) => entry,
+                None => break,
+            };
+            if !config.allow_duplication {
+                let key = match entry {
+                    PoolEntry::Item(item) => item.name.as_str(),
+                    PoolEntry::Pool(pool) => pool.as_str(),
+                };
+                if !rolled.insert(key) {
+                    continue;
+                }
+            }
+            self.roll_entry(entry, level, rng, stack, out)?;
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    fn roll_entry(
+        &self,
+        entry: &PoolEntry,
+        level: f64,
+        rng: &mut Random,
+        stack: &mut Vec<String>,
+        out: &mut Vec<ItemDescriptor>,
+    ) -> Result<(), TreasureError> {
+        match entry {
+            PoolEntry::Item(item) => {
+                out.push(item.clone());
+                Ok(())
+            }
+            PoolEntry::Pool(pool) => self.roll_into(pool, level, rng, stack, out),
+        }
+    }
+
+    /// Rolls `pool` `trials` times and reports how often each item dropped.
+    pub fn simulate(
+        &self,
+        pool: &str,
+        level: f64,
+        trials: u64,
+        seed: u64,
+    ) -> Result<BTreeMap<String, DropRate>, TreasureError> {
+        let mut rng = Random::new(seed);
+        let mut seen: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for _ in 0..trials {
+            let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+            for item in self.roll_with(pool, level, &mut rng)? {
+                *counts.entry(item.name).or_default() += item.count;
+            }
+            for (name, count) in counts {
+                let stat = seen.entry(name).or_default();
+                stat.0 += 1;
+                stat.1 += count;
+            }
+        }
+
+        let trials = std::cmp::max(trials, 1) as f64;
+        Ok(seen
+            .into_iter()
+            .map(|(name, (hits, total))| {
+                (
+                    name,
+                    DropRate {
+                        chance: hits as f64 / trials,
+                        mean_count: total as f64 / trials,
+                    },
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn database() -> TreasureDatabase {
+        let mut db = TreasureDatabase::default();
+        db.add_file(&json!({
+            "chest": [
+                [0, {
+                    "fill": [{"pool": "money"}],
+                    "pool": [
+                        {"weight": 0.75, "item": "torch"},
+                        {"weight": 0.25, "item": ["bandage", 2]}
+                    ],
+                    "poolRounds": [[0.5, 1], [0.5, 2]]
+                }],
+                [5, {
+                    "pool": [{"weight": 1.0, "item": "diamond"}],
+                    "poolRounds": [[1.0, 3]],
+                    "allowDuplication": false
+                }]
+            ],
+            "money": [[0, {"fill": [{"item": ["money", 10]}]}]],
+            "loop": [[0, {"fill": [{"pool": "loop"}]}]]
+        }))
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_roll_deterministic() {
+        let db = database();
+        let a = db.roll("chest", 1.0, 42).unwrap();
+        let b = db.roll("chest", 1.0, 42).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a[0], ItemDescriptor::new("money", 10));
+    }
+
+    #[test]
+    fn test_levels_and_duplication() {
+        let db = database();
+        let items = db.roll("chest", 6.0, 1).unwrap();
+        assert_eq!(items, vec![ItemDescriptor::new("diamond", 1)]);
+    }
+
+    #[test]
+    fn test_simulate() {
+        let db = database();
+        let rates = db.simulate("chest", 0.0, 10_000, 7).unwrap();
+        assert_eq!(rates["money"].chance, 1.0);
+        assert!((rates["money"].mean_count - 10.0).abs() < 1e-9);
+        let torch = &rates["torch"];
+        // 1.5 rounds on average, 3 in 4 of them torches
+        assert!((torch.mean_count - 1.125).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_errors() {
+        let db = database();
+        assert_eq!(
+            db.roll("nope", 0.0, 0),
+            Err(TreasureError::UnknownPool("nope".to_string()))
+        );
+        assert!(matches!(
+            db.roll("loop", 0.0, 0),
+            Err(TreasureError::TooDeep(_))
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+//! Checks a pak's structure without trusting any of it, for paks that may be
+//! truncated or corrupt.
+
+use crate::packed::{parse_metadata, render_nom_error, PackedAssets};
+use crate::vlq::read_vlqu64;
+use memmap::Mmap;
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::verify,
+    error::{context, ParseError},
+    number::complete::{be_i64, be_u64},
+    sequence::{pair, tuple},
+    IResult,
+};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+/// The header is the magic and the index offset.
+const HEADER_LEN: u64 = 16;
+/// The smallest directory entry: an empty path and two offsets.
+const MIN_ENTRY_LEN: u64 = 17;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The file doesn't start with `SBAsset6` and an index offset.
+    BadHeader,
+    IndexOutOfBounds {
+        offset: u64,
+        size: u64,
+    },
+    /// The index is there but couldn't be parsed.
+    BadIndex(String),
+    /// An asset path that isn't UTF-8, shown with the bad bytes replaced.
+    InvalidPath(String),
+    Duplicate(String),
+    /// An entry whose data isn't between the header and the index.
+    BadRange {
+        path: String,
+        offset: i64,
+        len: i64,
+    },
+    Overlap {
+        first: String,
+        second: String,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::BadHeader => write!(f, "not a pak: bad header"),
+            Problem::IndexOutOfBounds { offset, size } => write!(
+                f,
+                "index offset {} is outside the data of a {} byte file",
+                offset, size
+            ),
+            Problem::BadIndex(e) => write!(f, "could not parse the index: {}", e),
+            Problem::InvalidPath(p) => write!(f, "{} is not valid UTF-8", p),
+            Problem::Duplicate(p) => write!(f, "{} is listed more than once", p),
+            Problem::BadRange { path, offset, len } => write!(
+                f,
+                "{} has a bad range: {} bytes at offset {}",
+                path, len, offset
+            ),
+            Problem::Overlap { first, second } => write!(f, "{} overlaps {}", second, first),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// How many directory entries were read.
+    pub assets: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+type Entry<'a> = (&'a [u8], i64, i64);
+
+/// The directory with paths left as bytes.
+fn raw_directory<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], Vec<Entry<'a>>, E> {
+    // Don't believe a count the rest of the file can't hold
+    let (mut i, n) = context(
+        "directory size",
+        verify(read_vlqu64, |&n| n <= i.len() as u64 / MIN_ENTRY_LEN),
+    )(i)?;
+    let mut entries = Vec::new();
+    for _ in 0..n {
+        let path = |i| {
+            let (i, len) = read_vlqu64(i)?;
+            take(len)(i)
+        };
+        let (rest, (path, (offset, len))) = context("entry", pair(path, pair(be_i64, be_i64)))(i)?;
+        entries.push((path, offset, len));
+        i = rest;
+    }
+    Ok((i, entries))
+}
+
+/// Checks the header, the index and every directory entry of the pak in
+/// `data`.
+pub fn verify_packed(data: &[u8]) -> Report {
+    let mut report = Report::default();
+    let header: IResult<_, _, ()> = tuple((tag("SBAsset6"), be_u64))(data);
+    let index_offset = match header {
+        Ok((_, (_, offset))) => offset,
+        Err(_) => {
+            report.problems.push(Problem::BadHeader);
+            return report;
+        }
+    };
+    let size = data.len() as u64;
+    if index_offset < HEADER_LEN || index_offset > size {
+        report.problems.push(Problem::IndexOutOfBounds {
+            offset: index_offset,
+            size,
+        });
+        return report;
+    }
+
+    let index = &data[index_offset as usize..];
+    let parsed = (|| {
+        let (i, _) = render_nom_error(index, context("index", tag("INDEX"))(index))?;
+        let (i, _) = render_nom_error(index, parse_metadata(i))?;
+        render_nom_error(index, raw_directory(i))
+    })();
+    let entries = match parsed {
+        Ok((_, entries)) => entries,
+        Err(e) => {
+            report.problems.push(Problem::BadIndex(e.to_string()));
+            return report;
+        }
+    };
+    report.assets = entries.len();
+
+    let mut seen = BTreeSet::new();
+    let mut ranges = Vec::new();
+    for (path, offset, len) in entries {
+        let path = match std::str::from_utf8(path) {
+            Ok(p) => p.to_string(),
+            Err(_) => {
+                let p = String::from_utf8_lossy(path).into_owned();
+                report.problems.push(Problem::InvalidPath(p.clone()));
+                p
+            }
+        };
+        if !seen.insert(path.clone()) {
+            report.problems.push(Problem::Duplicate(path.clone()));
+        }
+        let end = u64::try_from(offset)
+            .ok()
+            .zip(u64::try_from(len).ok())
+            .and_then(|(offset, len)| offset.checked_add(len));
+        match end {
+            Some(end) if offset as u64 >= HEADER_LEN && end <= index_offset => {
+                if len > 0 {
+                    ranges.push((offset as u64, end, path));
+                }
+            }
+            _ => report
+                .problems
+                .push(Problem::BadRange { path, offset, len }),
+        }
+    }
+
+    ranges.sort();
+    let mut furthest: Option<(u64, String)> = None;
+    for (start, end, path) in ranges {
+        match &furthest {
+            Some((last_end, last)) if start < *last_end => {
+                report.problems.push(Problem::Overlap {
+                    first: last.clone(),
+                    second: path.clone(),
+                });
+                if end > *last_end {
+                    furthest = Some((end, path));
+                }
+            }
+            _ => furthest = Some((end, path)),
+        }
+    }
+    report
+}
+
+/// Verifies the pak at `path`, which needn't be readable as a pak at all.
+pub fn verify_file<P: AsRef<Path>>(path: P) -> Result<Report, Box<dyn Error>> {
+    let f = File::open(path)?;
+    if f.metadata()?.len() == 0 {
+        // Empty files can't be mapped
+        return Ok(verify_packed(&[]));
+    }
+    let map = unsafe { Mmap::map(&f)? };
+    Ok(verify_packed(&map))
+}
+
+impl PackedAssets {
+    pub fn verify(&self) -> Report {
+        verify_packed(&self.map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bson::{serializer, Map, Value};
+    use crate::vlq::write_vlqu64;
+    use crate::AssetError;
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    fn pak(data: &[u8], entries: &[(&[u8], i64, i64)]) -> Vec<u8> {
+        let mut out = b"SBAsset6".to_vec();
+        out.write_u64::<BigEndian>(16 + data.len() as u64).unwrap();
+        out.extend_from_slice(data);
+        out.extend_from_slice(b"INDEX");
+        let mut meta = Vec::new();
+        serializer::to_writer(&mut meta, &Value::Object(Map::new())).unwrap();
+        out.extend_from_slice(&meta[1..]);
+        write_vlqu64(&mut out, entries.len() as u64).unwrap();
+        for (path, offset, len) in entries {
+            write_vlqu64(&mut out, path.len() as u64).unwrap();
+            out.extend_from_slice(path);
+            out.write_i64::<BigEndian>(*offset).unwrap();
+            out.write_i64::<BigEndian>(*len).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn test_verify() {
+        let good = pak(
+            b"abcdef",
+            &[(b"/a", 16, 3), (b"/b", 19, 3), (b"/empty", 16, 0)],
+        );
+        assert_eq!(
+            verify_packed(&good),
+            Report {
+                assets: 3,
+                problems: vec![]
+            }
+        );
+
+        let bad = pak(
+            b"abcdef",
+            &[
+                (b"/a", 16, 4),
+                (b"/b", 19, 3),
+                (b"/neg", -8, 2),
+                (b"/long", 20, 100),
+                (b"/huge", 16, i64::MAX),
+                (b"/\xff", 16, 1),
+                (b"/a", 16, 1),
+            ],
+        );
+        let problems = verify_packed(&bad).problems;
+        assert_eq!(
+            problems,
+            vec![
+                Problem::BadRange {
+                    path: "/neg".to_string(),
+                    offset: -8,
+                    len: 2
+                },
+                Problem::BadRange {
+                    path: "/long".to_string(),
+                    offset: 20,
+                    len: 100
+                },
+                Problem::BadRange {
+                    path: "/huge".to_string(),
+                    offset: 16,
+                    len: i64::MAX
+                },
+                Problem::InvalidPath("/\u{fffd}".to_string()),
+                Problem::Duplicate("/a".to_string()),
+                Problem::Overlap {
+                    first: "/a".to_string(),
+                    second: "/\u{fffd}".to_string()
+                },
+                Problem::Overlap {
+                    first: "/a".to_string(),
+                    second: "/a".to_string()
+                },
+                Problem::Overlap {
+                    first: "/a".to_string(),
+                    second: "/b".to_string()
+                },
+            ]
+        );
+
+        assert_eq!(
+            verify_packed(b"SBAsset5").problems,
+            vec![Problem::BadHeader]
+        );
+        let mut truncated = good.clone();
+        truncated.truncate(20);
+        assert_eq!(
+            verify_packed(&truncated).problems,
+            vec![Problem::IndexOutOfBounds {
+                offset: 22,
+                size: 20
+            }]
+        );
+        let mut corrupt = good.clone();
+        corrupt[24] = 0xff;
+        assert!(matches!(
+            verify_packed(&corrupt).problems[..],
+            [Problem::BadIndex(_)]
+        ));
+
+        let path = std::env::temp_dir().join(format!("verify-{}.pak", std::process::id()));
+        std::fs::write(&path, pak(b"abc", &[(b"/ok", 16, 3), (b"/neg", -1, 3)])).unwrap();
+        let assets = PackedAssets::new(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(assets.file("/ok"), Ok(&b"abc"[..]));
+        assert_eq!(
+            assets.file("/neg"),
+            Err(AssetError::BadRange {
+                path: "/neg".to_string(),
+                offset: -1,
+                len: 3
+            })
+        );
+        assert_eq!(
+            assets.file("/missing"),
+            Err(AssetError::NotFound("/missing".to_string()))
+        );
+        assert_eq!(assets.verify().problems.len(), 1);
+        assert_eq!(verify_file(&path).unwrap().assets, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
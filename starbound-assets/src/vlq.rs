@@ -47,7 +47,8 @@ pub fn read_vlqi64<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8]
 }
 
 pub fn write_vlqi64<W: std::io::Write>(w: &mut W, n: i64) -> std::io::Result<()> {
-    write_vlqu64(w, ((n as u64) << 1) ^ ((n as u64) >> 63))
+    // Zigzag: the sign goes in the low bit, as read_vlqi64 expects
+    write_vlqu64(w, ((n << 1) ^ (n >> 63)) as u64)
 }
 
 pub fn write_vlqu64<W: std::io::Write>(w: &mut W, mut n: u64) -> std::io::Result<()> {
@@ -63,3 +64,34 @@ pub fn write_vlqu64<W: std::io::Write>(w: &mut W, mut n: u64) -> std::io::Result
     }
     w.write_all(&buf[std::cmp::min(i, buf.len() - 1)..])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vlqi64_round_trip() {
+        for &n in &[
+            0,
+            1,
+            -1,
+            63,
+            -64,
+            64,
+            -65,
+            1 << 40,
+            -(1 << 40),
+            i64::MIN,
+            i64::MAX,
+        ] {
+            let mut buf = Vec::new();
+            write_vlqi64(&mut buf, n).unwrap();
+            let (rest, read) = read_vlqi64::<()>(&buf).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(read, n);
+        }
+        let mut buf = Vec::new();
+        write_vlqi64(&mut buf, -1).unwrap();
+        assert_eq!(buf, vec![1]);
+    }
+}
@@ -0,0 +1,433 @@
+use super::{decompress, key, World};
+use crate::bson::{Map, Value};
+use crate::btreedb::writer::BTreeDbWriter;
+use crate::item::ItemDescriptor;
+use crate::packed::{parse_versioned_json, render_nom_error, write_versioned_json, VersionedJson};
+use crate::vlq::{read_vlqu64, write_vlqu64};
+use flate2::{write::ZlibEncoder, Compression};
+use nom::{
+    error::{context, ParseError},
+    multi::many_m_n,
+    IResult,
+};
+use std::error::Error;
+use std::io::Write;
+
+const ENTITY_SECTOR_KEY_TYPE: u8 = 2;
+
+/// Version of the `Item` wrappers the game writes into containers.
+const ITEM_VERSION: i64 = 1;
+
+pub fn entity_sector_key(x: u16, y: u16) -> [u8; 5] {
+    key(ENTITY_SECTOR_KEY_TYPE, x, y)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityKind {
+    Object,
+    Npc,
+    Monster,
+    ItemDrop,
+    Plant,
+    Stagehand,
+    Vehicle,
+    Other(String),
+}
+
+impl EntityKind {
+    fn from_identifier(id: &str) -> Self {
+        match id {
+            "ObjectEntity" => EntityKind::Object,
+            "NpcEntity" => EntityKind::Npc,
+            "MonsterEntity" => EntityKind::Monster,
+            "ItemDropEntity" => EntityKind::ItemDrop,
+            "PlantEntity" => EntityKind::Plant,
+            "StagehandEntity" => EntityKind::Stagehand,
+            "VehicleEntity" => EntityKind::Vehicle,
+            other => EntityKind::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntityError {
+    NotAContainer,
+    SlotOutOfRange { slot: usize, size: usize },
+    ContainerFull,
+    MalformedItem(usize),
+}
+
+impl std::fmt::Display for EntityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntityError::NotAContainer => f.write_str("entity is not a container"),
+            EntityError::SlotOutOfRange { slot, size } => {
+                write!(
+                    f,
+                    "slot {} is out of range for a container of {}",
+                    slot, size
+                )
+            }
+            EntityError::ContainerFull => f.write_str("container is full"),
+            EntityError::MalformedItem(slot) => write!(f, "malformed item in slot {}", slot),
+        }
+    }
+}
+
+impl Error for EntityError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub sector: (u16, u16),
+    pub kind: EntityKind,
+    pub store: VersionedJson,
+}
+
+fn field<'a>(v: &'a Value, key: &str) -> Option<&'a Value> {
+    match v {
+        Value::Object(o) => o.get(key),
+        _ => None,
+    }
+}
+
+fn number(v: &Value) -> Option<f64> {
+    match v {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn vec2(v: &Value) -> Option<(f64, f64)> {
+    match v {
+        Value::Array(a) if a.len() == 2 => Some((number(&a[0])?, number(&a[1])?)),
+        _ => None,
+    }
+}
+
+/// Container slots hold `{"id": "Item", "version", "content"}` wrappers,
+/// sometimes nested, or null when empty.
+fn item_from_value(v: &Value) -> Option<Option<ItemDescriptor>> {
+    match (field(v, "id"), field(v, "content")) {
+        (Some(Value::String(_)), Some(content)) => item_from_value(content),
+        _ => match v {
+            Value::Empty | Value::Unset => Some(None),
+            v => ItemDescriptor::from_json(&serde_json::to_value(v).ok()?).map(Some),
+        },
+    }
+}
+
+/// The `id` and `version` of each wrapper around the item in `v`,
+/// outermost first.
+fn wrappers(v: &Value) -> Vec<(Value, Value)> {
+    let mut out = Vec::new();
+    let mut v = v;
+    while let (Some(id @ Value::String(_)), Some(content)) = (field(v, "id"), field(v, "content")) {
+        let version = field(v, "version").cloned();
+        out.push((id.clone(), version.unwrap_or(Value::Integer(ITEM_VERSION))));
+        v = content;
+    }
+    out
+}
+
+fn item_to_value(item: &ItemDescriptor, wrappers: &[(Value, Value)]) -> Value {
+    let content = serde_json::from_value(item.to_json()).unwrap_or_default();
+    wrappers
+        .iter()
+        .rev()
+        .fold(content, |content, (id, version)| {
+            let mut wrapper = Map::new();
+            wrapper.insert("id".to_string(), id.clone());
+            wrapper.insert("version".to_string(), version.clone());
+            wrapper.insert("content".to_string(), content);
+            Value::Object(wrapper)
+        })
+}
+
+impl Entity {
+    pub fn name(&self) -> Option<&str> {
+        match field(&self.store.content, "name")? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Objects store the tile they're anchored to, everything else a
+    /// world position.
+    pub fn position(&self) -> Option<(f64, f64)> {
+        let store = &self.store.content;
+        field(store, "tilePosition")
+            .or_else(|| field(store, "position"))
+            .or_else(|| field(field(store, "movementController")?, "position"))
+            .and_then(vec2)
+    }
+
+    pub fn is_container(&self) -> bool {
+        self.kind == EntityKind::Object
+            && matches!(field(&self.store.content, "items"), Some(Value::Array(_)))
+    }
+
+    fn slots(&self) -> Result<&Vec<Value>, EntityError> {
+        match field(&self.store.content, "items") {
+            Some(Value::Array(a)) if self.kind == EntityKind::Object => Ok(a),
+            _ => Err(EntityError::NotAContainer),
+        }
+    }
+
+    fn slots_mut(&mut self) -> Result<&mut Vec<Value>, EntityError> {
+        if self.kind != EntityKind::Object {
+            return Err(EntityError::NotAContainer);
+        }
+        match &mut self.store.content {
+            Value::Object(o) => match o.get_mut("items") {
+                Some(Value::Array(a)) => Ok(a),
+                _ => Err(EntityError::NotAContainer),
+            },
+            _ => Err(EntityError::NotAContainer),
+        }
+    }
+
+    /// Every slot of a container, `None` for empty slots.
+    pub fn container_items(&self) -> Result<Vec<Option<ItemDescriptor>>, EntityError> {
+        self.slots()?
+            .iter()
+            .enumerate()
+            .map(|(i, v)| item_from_value(v).ok_or(EntityError::MalformedItem(i)))
+            .collect()
+    }
+
+    /// Replaces the item in `slot`, returning what was there. A slot that
+    /// doesn't hold an item is left alone.
+    pub fn set_container_item(
+        &mut self,
+        slot: usize,
+        item: Option<ItemDescriptor>,
+    ) -> Result<Option<ItemDescriptor>, EntityError> {
+        let slots = self.slots_mut()?;
+        if slot >= slots.len() {
+            return Err(EntityError::SlotOutOfRange {
+                slot,
+                size: slots.len(),
+            });
+        }
+        let old = item_from_value(&slots[slot]).ok_or(EntityError::MalformedItem(slot))?;
+        // Wrap the new item like the one it replaces, or like the other items
+        // when the slot is empty.
+        let shape = std::iter::once(&slots[slot])
+            .chain(slots.iter())
+            .map(wrappers)
+            .find(|w| !w.is_empty())
+            .unwrap_or_else(|| {
+                let id = Value::String("Item".to_string());
+                vec![(id, Value::Integer(ITEM_VERSION))]
+            });
+        slots[slot] = match item {
+            Some(item) => item_to_value(&item, &shape),
+            None => Value::Empty,
+        };
+        Ok(old)
+    }
+
+    /// Puts `item` in the first empty slot, returning the slot used.
+    pub fn add_container_item(&mut self, item: ItemDescriptor) -> Result<usize, EntityError> {
+        let slot = self
+            .container_items()?
+            .iter()
+            .position(|i| i.is_none())
+            .ok_or(EntityError::ContainerFull)?;
+        self.set_container_item(slot, Some(item))?;
+        Ok(slot)
+    }
+}
+
+pub(crate) fn parse_entity_stores<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], Vec<VersionedJson>, E> {
+    let (i, n) = read_vlqu64(i)?;
+    context(
+        "entity stores",
+        many_m_n(n as usize, n as usize, parse_versioned_json),
+    )(i)
+}
+
+impl World {
+    /// The sectors that have stored entities.
+    pub fn entity_sectors(&self) -> Result<Vec<(u16, u16)>, Box<dyn Error>> {
+        self.keys_of_type(ENTITY_SECTOR_KEY_TYPE)
+    }
+
+    pub fn entities_in(&self, x: u16, y: u16) -> Result<Vec<Entity>, Box<dyn Error>> {
+        let data = match self.db.get(&entity_sector_key(x, y))? {
+            Some(data) => decompress(&data)?,
+            None => return Ok(Vec::new()),
+        };
+        let (_, stores) = render_nom_error(&data, parse_entity_stores(&data))
+            .map_err(|e| format!("entity sector {}, {}: {}", x, y, e))?;
+        Ok(stores
+            .into_iter()
+            .map(|store| Entity {
+                sector: (x, y),
+                kind: EntityKind::from_identifier(&store.identifier),
+                store,
+            })
+            .collect())
+    }
+
+    pub fn entities(&self) -> Result<Vec<Entity>, Box<dyn Error>> {
+        let mut entities = Vec::new();
+        for (x, y) in self.entity_sectors()? {
+            entities.extend(self.entities_in(x, y)?);
+        }
+        Ok(entities)
+    }
+}
+
+/// Replaces every entity stored in sector `x`, `y`. The change is only
+/// written once `writer` commits.
+pub fn store_entities(
+    writer: &mut BTreeDbWriter,
+    x: u16,
+    y: u16,
+    entities: &[Entity],
+) -> Result<(), Box<dyn Error>> {
+    let key = entity_sector_key(x, y);
+    if entities.is_empty() {
+        writer.remove(&key)?;
+        return Ok(());
+    }
+    let mut data = Vec::new();
+    write_vlqu64(&mut data, entities.len() as u64)?;
+    for entity in entities {
+        write_versioned_json(&mut data, &entity.store)?;
+    }
+    let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+    e.write_all(&data)?;
+    writer.insert(&key, &e.finish()?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::world_file;
+    use super::*;
+    use serde_json::json;
+    use std::fs::{File, OpenOptions};
+
+    fn store(identifier: &str, content: serde_json::Value) -> Entity {
+        let store = VersionedJson {
+            identifier: identifier.to_string(),
+            version: 8,
+            content: serde_json::from_value(content).unwrap(),
+        };
+        Entity {
+            sector: (0, 0),
+            kind: EntityKind::from_identifier(identifier),
+            store,
+        }
+    }
+
+    fn writer(path: &std::path::Path) -> BTreeDbWriter {
+        let file = OpenOptions::new().read(true).write(true).open(path);
+        BTreeDbWriter::open(file.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_containers() {
+        let path = world_file("entities", false);
+        let chest = store(
+            "ObjectEntity",
+            json!({
+                "name": "shipchest",
+                "tilePosition": [10, 12],
+                "items": [
+                    {"id": "Item", "version": 3, "content": {
+                        "id": "Item", "version": 3,
+                        "content": {"name": "money", "count": 50, "parameters": {}},
+                    }},
+                    null,
+                    {"id": "Item", "version": 3, "content": {"count": "many"}},
+                ],
+            }),
+        );
+        let npc = store(
+            "NpcEntity",
+            json!({"movementController": {"position": [4.5, 6.0]}}),
+        );
+        let mut w = writer(&path);
+        store_entities(&mut w, 0, 0, &[chest, npc]).unwrap();
+        w.commit().unwrap();
+
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(world.entity_sectors().unwrap(), vec![(0, 0)]);
+        let mut entities = world.entities().unwrap();
+        assert_eq!(entities[1].kind, EntityKind::Npc);
+        assert_eq!(entities[1].position(), Some((4.5, 6.0)));
+        assert!(!entities[1].is_container());
+        assert_eq!(
+            entities[1].container_items(),
+            Err(EntityError::NotAContainer)
+        );
+
+        let chest = &mut entities[0];
+        assert_eq!(chest.name(), Some("shipchest"));
+        assert_eq!(chest.position(), Some((10.0, 12.0)));
+        assert_eq!(chest.container_items(), Err(EntityError::MalformedItem(2)));
+        assert_eq!(
+            chest.set_container_item(2, None),
+            Err(EntityError::MalformedItem(2))
+        );
+        // and is still there as it was
+        let malformed = json!({"id": "Item", "version": 3, "content": {"count": "many"}});
+        assert_eq!(
+            chest.slots_mut().unwrap().pop(),
+            Some(serde_json::from_value(malformed).unwrap())
+        );
+        assert_eq!(
+            chest.container_items().unwrap(),
+            vec![Some(ItemDescriptor::new("money", 50)), None]
+        );
+        assert_eq!(
+            chest.add_container_item(ItemDescriptor::new("torch", 5)),
+            Ok(1)
+        );
+        assert_eq!(
+            chest.add_container_item(ItemDescriptor::new("torch", 5)),
+            Err(EntityError::ContainerFull)
+        );
+        assert_eq!(
+            chest.set_container_item(0, None),
+            Ok(Some(ItemDescriptor::new("money", 50)))
+        );
+        assert_eq!(
+            chest.set_container_item(2, None),
+            Err(EntityError::SlotOutOfRange { slot: 2, size: 2 })
+        );
+
+        let mut w = writer(&path);
+        store_entities(&mut w, 0, 0, &entities).unwrap();
+        w.commit().unwrap();
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        let chest = &world.entities_in(0, 0).unwrap()[0];
+        assert_eq!(
+            chest.container_items().unwrap(),
+            vec![None, Some(ItemDescriptor::new("torch", 5))]
+        );
+        assert_eq!(
+            field(&chest.store.content, "items"),
+            Some(&Value::Array(vec![
+                Value::Empty,
+                item_to_value(
+                    &ItemDescriptor::new("torch", 5),
+                    &wrappers(
+                        &serde_json::from_value(json!({
+                            "id": "Item", "version": 3,
+                            "content": {"id": "Item", "version": 3, "content": {}},
+                        }))
+                        .unwrap()
+                    )
+                )
+            ]))
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
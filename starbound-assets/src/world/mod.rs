@@ -0,0 +1,593 @@
+use crate::bson::{Map, Value};
+use crate::btreedb::BTreeDb;
+use crate::packed::{parse_versioned_json, render_nom_error, VersionedJson};
+use crate::vlq::read_vlqu64;
+use flate2::read::ZlibDecoder;
+use nom::{
+    combinator::{map_opt, verify},
+    error::{context, ParseError},
+    multi::many_m_n,
+    number::complete::{be_f32, be_i32, be_u16, be_u32, be_u8},
+    IResult,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+pub mod entity;
+pub mod render;
+
+pub const SECTOR_SIZE: u32 = 32;
+/// The game's current tile serialization version.
+pub const TILE_VERSION: u64 = 418;
+/// The oldest tile version this reads. Version 416 tiles have no biome
+/// transition flag and 417 tiles no root source.
+pub const OLDEST_TILE_VERSION: u64 = 416;
+const SECTOR_TILES: usize = (SECTOR_SIZE * SECTOR_SIZE) as usize;
+
+pub const EMPTY_MATERIAL: u16 = 65535;
+pub const NULL_MATERIAL: u16 = 65534;
+pub const STRUCTURE_MATERIAL: u16 = 65533;
+pub const NO_MOD: u16 = 65535;
+pub const NO_LIQUID: u8 = 0;
+
+pub const METADATA_KEY: [u8; 5] = [0; 5];
+const SECTOR_KEY_TYPE: u8 = 1;
+
+fn key(key_type: u8, x: u16, y: u16) -> [u8; 5] {
+    let (x, y) = (x.to_be_bytes(), y.to_be_bytes());
+    [key_type, x[0], x[1], y[0], y[1]]
+}
+
+pub fn sector_key(x: u16, y: u16) -> [u8; 5] {
+    key(SECTOR_KEY_TYPE, x, y)
+}
+
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Vec::new();
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Collision {
+    Null,
+    None,
+    Platform,
+    Dynamic,
+    Slippery,
+    Block,
+}
+
+impl Collision {
+    fn from_u8(n: u8) -> Option<Self> {
+        Some(match n {
+            0 => Collision::Null,
+            1 => Collision::None,
+            2 => Collision::Platform,
+            3 => Collision::Dynamic,
+            4 => Collision::Slippery,
+            5 => Collision::Block,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TileLayer {
+    pub material: u16,
+    pub hue_shift: u8,
+    pub color_variant: u8,
+    pub modifier: u16,
+    pub mod_hue_shift: u8,
+}
+
+impl TileLayer {
+    pub fn is_empty(&self) -> bool {
+        self.material == EMPTY_MATERIAL || self.material == NULL_MATERIAL
+    }
+
+    /// Hue shifts are stored as a byte covering a full turn.
+    pub fn hue_shift_degrees(&self) -> f32 {
+        self.hue_shift as f32 * 360.0 / 255.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Liquid {
+    pub id: u8,
+    pub level: f32,
+    pub pressure: f32,
+    pub source: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tile {
+    pub foreground: TileLayer,
+    pub background: TileLayer,
+    pub liquid: Liquid,
+    pub collision: Collision,
+    pub dungeon_id: u16,
+    pub block_biome: u8,
+    pub environment_biome: u8,
+    pub biome_transition: bool,
+    /// The tile this one's material is rooted to, e.g. for tree roots.
+    pub root_source: Option<(i32, i32)>,
+}
+
+fn parse_layer<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], TileLayer, E> {
+    let (i, material) = be_u16(i)?;
+    let (i, hue_shift) = be_u8(i)?;
+    let (i, color_variant) = be_u8(i)?;
+    let (i, modifier) = be_u16(i)?;
+    let (i, mod_hue_shift) = be_u8(i)?;
+    Ok((
+        i,
+        TileLayer {
+            material,
+            hue_shift,
+            color_variant,
+            modifier,
+            mod_hue_shift,
+        },
+    ))
+}
+
+/// A tile as written by tile `version`.
+pub(crate) fn parse_tile<'a, E: ParseError<&'a [u8]>>(
+    version: u64,
+) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], Tile, E> {
+    move |i| parse_versioned_tile(i, version)
+}
+
+fn parse_versioned_tile<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+    version: u64,
+) -> IResult<&'a [u8], Tile, E> {
+    let (i, foreground) = context("foreground", parse_layer)(i)?;
+    let (i, background) = context("background", parse_layer)(i)?;
+    let (i, id) = be_u8(i)?;
+    let (i, level) = be_f32(i)?;
+    let (i, pressure) = be_f32(i)?;
+    let (i, source) = be_u8(i)?;
+    let (i, collision) = context("collision", map_opt(be_u8, Collision::from_u8))(i)?;
+    let (i, dungeon_id) = be_u16(i)?;
+    let (i, block_biome) = be_u8(i)?;
+    let (i, environment_biome) = be_u8(i)?;
+    let (i, biome_transition) = if version >= 417 { be_u8(i)? } else { (i, 0) };
+    let (i, has_root_source) = if version >= 418 { be_u8(i)? } else { (i, 0) };
+    let (i, root_source) = if has_root_source != 0 {
+        let (i, x) = be_i32(i)?;
+        let (i, y) = be_i32(i)?;
+        (i, Some((x, y)))
+    } else {
+        (i, None)
+    };
+    Ok((
+        i,
+        Tile {
+            foreground,
+            background,
+            liquid: Liquid {
+                id,
+                level,
+                pressure,
+                source: source != 0,
+            },
+            collision,
+            dungeon_id,
+            block_biome,
+            environment_biome,
+            biome_transition: biome_transition != 0,
+            root_source,
+        },
+    ))
+}
+
+/// A 32x32 block of tiles, stored row by row from the bottom left.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sector {
+    pub generation_level: u64,
+    pub version: u64,
+    pub tiles: Vec<Tile>,
+}
+
+impl Sector {
+    pub fn tile(&self, x: u32, y: u32) -> &Tile {
+        &self.tiles[(y * SECTOR_SIZE + x) as usize]
+    }
+}
+
+fn supported(version: u64) -> bool {
+    (OLDEST_TILE_VERSION..=TILE_VERSION).contains(&version)
+}
+
+/// The generation level and tile version a sector starts with.
+fn parse_sector_header<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], (u64, u64), E> {
+    let (i, generation_level) = read_vlqu64(i)?;
+    let (i, version) = read_vlqu64(i)?;
+    Ok((i, (generation_level, version)))
+}
+
+pub(crate) fn parse_sector<'a, E: ParseError<&'a [u8]>>(
+    i: &'a [u8],
+) -> IResult<&'a [u8], Sector, E> {
+    let (i, (generation_level, version)) = context(
+        "tile version",
+        verify(parse_sector_header, |&(_, v)| supported(v)),
+    )(i)?;
+    let tile = parse_tile(version);
+    let (i, tiles) = context("tiles", many_m_n(SECTOR_TILES, SECTOR_TILES, tile))(i)?;
+    Ok((
+        i,
+        Sector {
+            generation_level,
+            version,
+            tiles,
+        },
+    ))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub contents: VersionedJson,
+}
+
+impl WorldMetadata {
+    fn field(&self, key: &str) -> Option<&Value> {
+        match &self.contents.content {
+            Value::Object(o) => o.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn template(&self) -> Option<&Value> {
+        self.field("worldTemplate")
+    }
+
+    pub fn properties(&self) -> Option<&Map> {
+        match self.field("worldProperties")? {
+            Value::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+}
+
+fn parse_metadata<'a, E: ParseError<&'a [u8]>>(i: &'a [u8]) -> IResult<&'a [u8], WorldMetadata, E> {
+    let (i, width) = be_u32(i)?;
+    let (i, height) = be_u32(i)?;
+    let (i, contents) = context("world metadata", parse_versioned_json)(i)?;
+    Ok((
+        i,
+        WorldMetadata {
+            width,
+            height,
+            contents,
+        },
+    ))
+}
+
+/// A `.world` or `.shipworld` file.
+pub struct World {
+    db: BTreeDb,
+    metadata: WorldMetadata,
+}
+
+impl World {
+    pub fn new(f: &File) -> Result<Self, Box<dyn Error>> {
+        let db = BTreeDb::new(f)?;
+        if !db.identifier().starts_with("World") || db.key_size() != METADATA_KEY.len() {
+            return Err(format!("not a world database: {}", db.identifier()).into());
+        }
+        let data = db.get(&METADATA_KEY)?.ok_or("world has no metadata")?;
+        let data = decompress(&data)?;
+        let (_, metadata) = render_nom_error(&data, parse_metadata(&data))?;
+        Ok(World { db, metadata })
+    }
+
+    pub fn db(&self) -> &BTreeDb {
+        &self.db
+    }
+
+    pub fn metadata(&self) -> &WorldMetadata {
+        &self.metadata
+    }
+
+    pub fn width(&self) -> u32 {
+        self.metadata.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.metadata.height
+    }
+
+    /// The number of sectors across and up the world.
+    pub fn sector_counts(&self) -> (u16, u16) {
+        (
+            self.width().div_ceil(SECTOR_SIZE) as u16,
+            self.height().div_ceil(SECTOR_SIZE) as u16,
+        )
+    }
+
+    /// The sectors that have been generated and stored.
+    pub fn stored_sectors(&self) -> Result<Vec<(u16, u16)>, Box<dyn Error>> {
+        self.keys_of_type(SECTOR_KEY_TYPE)
+    }
+
+    fn keys_of_type(&self, key_type: u8) -> Result<Vec<(u16, u16)>, Box<dyn Error>> {
+        Ok(self
+            .db
+            .keys()?
+            .into_iter()
+            .filter(|k| k[0] == key_type)
+            .map(|k| {
+                (
+                    u16::from_be_bytes([k[1], k[2]]),
+                    u16::from_be_bytes([k[3], k[4]]),
+                )
+            })
+            .collect())
+    }
+
+    pub fn sector(&self, x: u16, y: u16) -> Result<Option<Sector>, Box<dyn Error>> {
+        let data = match self.db.get(&sector_key(x, y))? {
+            Some(data) => decompress(&data)?,
+            None => return Ok(None),
+        };
+        let (_, (_, version)) = render_nom_error(&data, parse_sector_header(&data))
+            .map_err(|e| format!("sector {}, {}: {}", x, y, e))?;
+        if !supported(version) {
+            return Err(format!(
+                "sector {}, {} has unsupported tile version {}",
+                x, y, version
+            )
+            .into());
+        }
+        let (rest, sector) = render_nom_error(&data, parse_sector(&data))
+            .map_err(|e| format!("sector {}, {}: {}", x, y, e))?;
+        if !rest.is_empty() {
+            return Err(format!("sector {}, {} has {} bytes left over", x, y, rest.len()).into());
+        }
+        Ok(Some(sector))
+    }
+
+    /// The tile at world coordinates `x`, `y`, wrapping around horizontally.
+    /// Returns `None` outside the world or in sectors that were never stored.
+    pub fn tile(&self, x: u32, y: u32) -> Result<Option<Tile>, Box<dyn Error>> {
+        if y >= self.height() || self.width() == 0 {
+            return Ok(None);
+        }
+        let x = x % self.width();
+        let sector = self.sector((x / SECTOR_SIZE) as u16, (y / SECTOR_SIZE) as u16)?;
+        Ok(sector.map(|s| *s.tile(x % SECTOR_SIZE, y % SECTOR_SIZE)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btreedb::writer::BTreeDbWriter;
+    use crate::packed::save_versioned_json;
+    use flate2::{write::ZlibEncoder, Compression};
+    use serde_json::json;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    pub(super) fn compress(data: &[u8]) -> Vec<u8> {
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn tile(
+        version: u64,
+        material: u16,
+        collision: u8,
+        root_source: Option<(i32, i32)>,
+    ) -> Vec<u8> {
+        let mut t = Vec::new();
+        t.extend_from_slice(&material.to_be_bytes());
+        t.extend_from_slice(&[128, 2]);
+        t.extend_from_slice(&NO_MOD.to_be_bytes());
+        t.push(0);
+        t.extend_from_slice(&EMPTY_MATERIAL.to_be_bytes());
+        t.extend_from_slice(&[0, 0]);
+        t.extend_from_slice(&NO_MOD.to_be_bytes());
+        t.push(0);
+        t.push(1);
+        t.extend_from_slice(&0.5f32.to_be_bytes());
+        t.extend_from_slice(&1.0f32.to_be_bytes());
+        t.push(0);
+        t.push(collision);
+        t.extend_from_slice(&65535u16.to_be_bytes());
+        t.extend_from_slice(&[3, 4]);
+        if version >= 417 {
+            t.push(1);
+        }
+        match root_source {
+            _ if version < 418 => {}
+            Some((x, y)) => {
+                t.push(1);
+                t.extend_from_slice(&x.to_be_bytes());
+                t.extend_from_slice(&y.to_be_bytes());
+            }
+            None => t.push(0),
+        }
+        t
+    }
+
+    /// A 64x64 world with one sector stored, and with `old_sectors` two
+    /// more from older versions of the game.
+    pub(super) fn world_file(name: &str, old_sectors: bool) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("world-{}-{}", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut w = BTreeDbWriter::create(file, "World4", 5, 2048).unwrap();
+
+        let mut contents = VersionedJson {
+            identifier: "WorldMetadata".to_string(),
+            version: 24,
+            content: serde_json::from_value(json!({
+                "worldTemplate": {"size": [64, 64]},
+                "worldProperties": {"ship.level": 3},
+            }))
+            .unwrap(),
+        };
+        let mut metadata = Vec::new();
+        metadata.extend_from_slice(&64u32.to_be_bytes());
+        metadata.extend_from_slice(&64u32.to_be_bytes());
+        metadata.extend_from_slice(&save_versioned_json(&mut contents).unwrap()[6..]);
+        w.insert(&METADATA_KEY, &compress(&metadata)).unwrap();
+
+        let mut sector = vec![1, 0x83, 0x22];
+        for i in 0..SECTOR_TILES {
+            match i {
+                0 => sector.extend(tile(418, 5, 5, Some((33, 1)))),
+                n if n == SECTOR_TILES - 1 => sector.extend(tile(418, 5, 5, None)),
+                _ => sector.extend(tile(418, EMPTY_MATERIAL, 1, None)),
+            }
+        }
+        w.insert(&sector_key(1, 0), &compress(&sector)).unwrap();
+        if old_sectors {
+            for (x, version) in [(0, 417), (1, 416)] {
+                let mut old = vec![1, 0x83, version as u8 - 0x80];
+                old.extend((0..SECTOR_TILES).flat_map(|_| tile(version, 6, 5, None)));
+                w.insert(&sector_key(x, 1), &compress(&old)).unwrap();
+            }
+        }
+        w.commit().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_world() {
+        let path = world_file("read", true);
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((world.width(), world.height()), (64, 64));
+        assert_eq!(world.sector_counts(), (2, 2));
+        assert_eq!(world.metadata().contents.version, 25);
+        assert_eq!(
+            world.metadata().properties().unwrap().get("ship.level"),
+            Some(&Value::Integer(3))
+        );
+        assert_eq!(
+            world.stored_sectors().unwrap(),
+            vec![(0, 1), (1, 0), (1, 1)]
+        );
+        assert_eq!(world.sector(0, 0).unwrap(), None);
+
+        let sector = world.sector(1, 0).unwrap().unwrap();
+        assert_eq!((sector.generation_level, sector.version), (1, 418));
+        let t = world.tile(32 + 64, 0).unwrap().unwrap();
+        assert_eq!(t.foreground.material, 5);
+        assert_eq!(t.foreground.color_variant, 2);
+        assert!((t.foreground.hue_shift_degrees() - 180.7).abs() < 0.1);
+        assert!(t.background.is_empty());
+        assert_eq!(t.collision, Collision::Block);
+        assert_eq!(t.liquid.level, 0.5);
+        assert!(t.biome_transition);
+        assert_eq!(t.root_source, Some((33, 1)));
+        let t = world.tile(33, 0).unwrap().unwrap();
+        assert_eq!(t.collision, Collision::None);
+        assert_eq!(t.root_source, None);
+        assert_eq!(world.tile(63, 31).unwrap().unwrap().foreground.material, 5);
+
+        let sector = world.sector(0, 1).unwrap().unwrap();
+        assert_eq!(sector.version, 417);
+        assert!(sector.tiles.iter().all(|t| t.biome_transition));
+        let t = world.tile(0, 32).unwrap().unwrap();
+        assert_eq!((t.foreground.material, t.collision), (6, Collision::Block));
+        assert_eq!(t.root_source, None);
+        let sector = world.sector(1, 1).unwrap().unwrap();
+        assert_eq!(sector.version, 416);
+        let t = world.tile(63, 63).unwrap().unwrap();
+        assert_eq!((t.foreground.material, t.liquid.level), (6, 0.5));
+        assert!(!t.biome_transition);
+
+        assert_eq!(world.tile(0, 64).unwrap(), None);
+    }
+
+    #[test]
+    fn test_tile_layout() {
+        // Two version 418 tiles as the game writes them: a stone block
+        // rooted elsewhere, then water over an empty foreground
+        #[rustfmt::skip]
+        let data: &[u8] = &[
+            // foreground: material, hue shift, color variant, mod, mod hue shift
+            0x00, 0x01, 0x00, 0x00, 0xff, 0xff, 0x00,
+            // background
+            0x00, 0x02, 0x20, 0x01, 0x00, 0x07, 0x10,
+            // liquid: id, level, pressure, source
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // collision, dungeon id, block biome, environment biome, biome transition
+            0x05, 0xff, 0xfe, 0x02, 0x02, 0x00,
+            // root source: present, x, y
+            0x01, 0x00, 0x00, 0x01, 0x02, 0xff, 0xff, 0xff, 0xfe,
+
+            0xff, 0xff, 0x00, 0x00, 0xff, 0xff, 0x00,
+            0x00, 0x02, 0x20, 0x01, 0xff, 0xff, 0x00,
+            0x01, 0x3f, 0x80, 0x00, 0x00, 0x3f, 0x00, 0x00, 0x00, 0x01,
+            0x01, 0xff, 0xfe, 0x02, 0x03, 0x01,
+            0x00,
+        ];
+        let (rest, stone) = parse_tile::<()>(418)(data).unwrap();
+        let (rest, water) = parse_tile::<()>(418)(rest).unwrap();
+        assert!(rest.is_empty());
+
+        assert_eq!(stone.foreground.material, 1);
+        assert_eq!(stone.background.modifier, 7);
+        assert_eq!(stone.collision, Collision::Block);
+        assert_eq!(stone.dungeon_id, 65534);
+        assert!(!stone.biome_transition);
+        assert_eq!(stone.root_source, Some((258, -2)));
+
+        assert!(water.foreground.is_empty());
+        assert_eq!(water.liquid.id, 1);
+        assert_eq!(water.liquid.level, 1.0);
+        assert_eq!(water.liquid.pressure, 0.5);
+        assert!(water.liquid.source);
+        assert_eq!(water.environment_biome, 3);
+        assert!(water.biome_transition);
+        assert_eq!(water.root_source, None);
+
+        // Version 417 stops before the root source and 416 before the biome
+        // transition flag
+        let (stone_417, water_417) = (&data[..30], &data[39..69]);
+        assert_eq!(
+            parse_tile::<()>(417)(stone_417),
+            Ok((
+                &[][..],
+                Tile {
+                    root_source: None,
+                    ..stone
+                }
+            ))
+        );
+        assert_eq!(parse_tile::<()>(417)(water_417), Ok((&[][..], water)));
+        let water_416 = &data[39..68];
+        assert_eq!(
+            parse_tile::<()>(416)(water_416),
+            Ok((
+                &[][..],
+                Tile {
+                    biome_transition: false,
+                    ..water
+                }
+            ))
+        );
+        assert!(parse_sector::<()>(&[1, 0x83, 0x1f]).is_err());
+        assert!(parse_sector::<()>(&[1, 0x83, 0x23]).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use super::{Tile, TileLayer, World, NO_LIQUID, SECTOR_SIZE};
+use crate::packed::PackedAssets;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+pub type Color = [u8; 4];
+
+const DEFAULT_MATERIAL: Color = [128, 128, 128, 255];
+const DEFAULT_LIQUID: Color = [64, 96, 224, 160];
+
+fn color(v: &Value) -> Option<Color> {
+    let a = v.as_array()?;
+    if a.len() < 3 {
+        return None;
+    }
+    let mut c = [0, 0, 0, 255];
+    for (i, n) in a.iter().take(4).enumerate() {
+        c[i] = n.as_u64()?.min(255) as u8;
+    }
+    Some(c)
+}
+
+fn ids<K: std::str::FromStr + Eq + std::hash::Hash>(
+    v: Option<&Value>,
+) -> Result<HashMap<K, Color>, Box<dyn Error>> {
+    let mut map = HashMap::new();
+    for (id, c) in v.and_then(|v| v.as_object()).into_iter().flatten() {
+        let id = id.parse().map_err(|_| format!("invalid id {}", id))?;
+        map.insert(id, color(c).ok_or_else(|| format!("invalid color {}", c))?);
+    }
+    Ok(map)
+}
+
+/// The average colour of the opaque pixels in a PNG.
+fn average_color(data: &[u8]) -> Result<Option<Color>, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+    let channels = info.color_type.samples();
+
+    let mut sum = [0u64; 3];
+    let mut count = 0;
+    for px in buf.chunks_exact(channels) {
+        let (rgb, alpha) = match channels {
+            1 => ([px[0]; 3], 255),
+            2 => ([px[0]; 3], px[1]),
+            3 => ([px[0], px[1], px[2]], 255),
+            _ => ([px[0], px[1], px[2]], px[3]),
+        };
+        if alpha < 128 {
+            continue;
+        }
+        for (s, c) in sum.iter_mut().zip(&rgb) {
+            *s += *c as u64;
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(None);
+    }
+    let avg = |s: u64| (s / count) as u8;
+    Ok(Some([avg(sum[0]), avg(sum[1]), avg(sum[2]), 255]))
+}
+
+/// Resolves an asset reference relative to the asset that made it, dropping
+/// any frame or directives.
+fn asset_path(base: &str, reference: &str) -> String {
+    let reference = reference.split(['?', ':']).next().unwrap();
+    if reference.starts_with('/') {
+        return reference.to_string();
+    }
+    let dir = base.rsplit_once('/').map_or("", |(dir, _)| dir);
+    format!("{}/{}", dir, reference)
+}
+
+/// The colours used to draw materials and liquids.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub materials: HashMap<u16, Color>,
+    pub liquids: HashMap<u8, Color>,
+    pub default_material: Color,
+    pub default_liquid: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            materials: HashMap::new(),
+            liquids: HashMap::new(),
+            default_material: DEFAULT_MATERIAL,
+            default_liquid: DEFAULT_LIQUID,
+        }
+    }
+}
+
+impl Palette {
+    /// Colours every material by its particle colour, or failing that the
+    /// average colour of its texture, and every liquid by its colour.
+    pub fn from_assets(assets: &PackedAssets) -> Result<Self, Box<dyn Error>> {
+        let mut palette = Palette::default();
+        for path in assets.assets_with_extension("material") {
+            let material = assets.json(path)?;
+            let id = match material.get("materialId").and_then(|i| i.as_u64()) {
+                Some(id) => id as u16,
+                None => continue,
+            };
+            let mut c = material.get("particleColor").and_then(color);
+            if c.is_none() {
+                let texture = material
+                    .pointer("/renderParameters/texture")
+                    .and_then(|t| t.as_str())
+                    .map(|t| asset_path(path, t))
+                    .filter(|t| assets.contains(t));
+                if let Some(texture) = texture {
+                    c = average_color(assets.file(&texture)?)
+                        .map_err(|e| format!("{}: {}", path, e))?;
+                }
+            }
+            if let Some(c) = c {
+                palette.materials.insert(id, c);
+            }
+        }
+        for path in assets.assets_with_extension("liquid") {
+            let liquid = assets.json(path)?;
+            let id = liquid.get("liquidId").and_then(|i| i.as_u64());
+            let c = liquid.get("color").and_then(color);
+            if let (Some(id), Some(c)) = (id, c) {
+                palette.liquids.insert(id as u8, c);
+            }
+        }
+        Ok(palette)
+    }
+
+    /// Reads a flat palette of the form
+    /// `{"materials": {"<id>": [r, g, b, a?]}, "liquids": {..}, "default": [..]}`.
+    pub fn from_json(v: &Value) -> Result<Self, Box<dyn Error>> {
+        let mut palette = Palette {
+            materials: ids(v.get("materials"))?,
+            liquids: ids(v.get("liquids"))?,
+            ..Palette::default()
+        };
+        if let Some(c) = v.get("default") {
+            palette.default_material = color(c).ok_or("invalid default color")?;
+        }
+        if let Some(c) = v.get("defaultLiquid") {
+            palette.default_liquid = color(c).ok_or("invalid default liquid color")?;
+        }
+        Ok(palette)
+    }
+
+    /// Adds the colours from `other`, replacing any already set.
+    pub fn merge(&mut self, other: Palette) {
+        self.materials.extend(other.materials);
+        self.liquids.extend(other.liquids);
+        self.default_material = other.default_material;
+        self.default_liquid = other.default_liquid;
+    }
+
+    fn layer(&self, layer: &TileLayer) -> Option<Color> {
+        if layer.is_empty() {
+            return None;
+        }
+        Some(
+            self.materials
+                .get(&layer.material)
+                .copied()
+                .unwrap_or(self.default_material),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderOptions {
+    /// How much of a background tile's colour is kept, from 0 to 1.
+    pub background_brightness: f32,
+    /// Drawn wherever there is no tile, or the sector was never stored.
+    pub sky: Color,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            background_brightness: 0.5,
+            sky: [0, 0, 0, 0],
+        }
+    }
+}
+
+/// Draws `over` on top of `under`, scaling its alpha by `amount`.
+fn blend(under: Color, over: Color, amount: f32) -> Color {
+    let a = (over[3] as f32 / 255.0 * amount).clamp(0.0, 1.0);
+    let ua = under[3] as f32 / 255.0 * (1.0 - a);
+    let out = a + ua;
+    if out <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+    let mix = |u: u8, o: u8| ((o as f32 * a + u as f32 * ua) / out).round() as u8;
+    [
+        mix(under[0], over[0]),
+        mix(under[1], over[1]),
+        mix(under[2], over[2]),
+        (out * 255.0).round() as u8,
+    ]
+}
+
+fn tile_color(tile: &Tile, palette: &Palette, options: &RenderOptions) -> Color {
+    let mut c = match (
+        palette.layer(&tile.foreground),
+        palette.layer(&tile.background),
+    ) {
+        (Some(fg), _) => fg,
+        (None, Some(bg)) => {
+            let dim = |c: u8| (c as f32 * options.background_brightness) as u8;
+            [dim(bg[0]), dim(bg[1]), dim(bg[2]), bg[3]]
+        }
+        (None, None) => options.sky,
+    };
+    if tile.liquid.id != NO_LIQUID && tile.liquid.level > 0.0 {
+        let liquid = palette
+            .liquids
+            .get(&tile.liquid.id)
+            .copied()
+            .unwrap_or(palette.default_liquid);
+        c = blend(c, liquid, tile.liquid.level.min(1.0));
+    }
+    c
+}
+
+/// An RGBA image, one pixel per tile, with the top of the world first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn pixel(&self, x: u32, y: u32) -> Color {
+        let i = (y * self.width + x) as usize * 4;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    pub fn write_png<W: Write>(&self, w: W) -> Result<(), Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let f = std::fs::File::create(path)?;
+        self.write_png(std::io::BufWriter::new(f))
+    }
+}
+
+/// A rendered world, along with the sectors that couldn't be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendered {
+    pub image: Image,
+    /// Each unreadable sector and why. They are drawn in the palette's
+    /// default material colour.
+    pub errors: Vec<((u16, u16), String)>,
+}
+
+impl World {
+    pub fn render(
+        &self,
+        palette: &Palette,
+        options: &RenderOptions,
+    ) -> Result<Rendered, Box<dyn Error>> {
+        let (width, height) = (self.width(), self.height());
+        let mut pixels = options
+            .sky
+            .iter()
+            .copied()
+            .cycle()
+            .take((width * height) as usize * 4)
+            .collect::<Vec<_>>();
+
+        let mut errors = Vec::new();
+        for (sx, sy) in self.stored_sectors()? {
+            let sector = match self.sector(sx, sy) {
+                Ok(Some(sector)) => Some(sector),
+                Ok(None) => continue,
+                Err(e) => {
+                    errors.push(((sx, sy), e.to_string()));
+                    None
+                }
+            };
+            for ty in 0..SECTOR_SIZE {
+                for tx in 0..SECTOR_SIZE {
+                    let (x, y) = (sx as u32 * SECTOR_SIZE + tx, sy as u32 * SECTOR_SIZE + ty);
+                    if x >= width || y >= height {
+                        continue;
+                    }
+                    let c = match &sector {
+                        Some(sector) => tile_color(sector.tile(tx, ty), palette, options),
+                        None => palette.default_material,
+                    };
+                    let i = ((height - 1 - y) * width + x) as usize * 4;
+                    pixels[i..i + 4].copy_from_slice(&c);
+                }
+            }
+        }
+
+        Ok(Rendered {
+            image: Image {
+                width,
+                height,
+                pixels,
+            },
+            errors,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sector_key;
+    use super::super::tests::{compress, world_file};
+    use super::*;
+    use crate::btreedb::writer::BTreeDbWriter;
+    use serde_json::json;
+    use std::fs::File;
+
+    #[test]
+    fn test_render() {
+        let path = world_file("render", false);
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let palette = Palette::from_json(&json!({
+            "materials": {"5": [200, 100, 0], "6": [0, 200, 0]},
+            "liquids": {"1": [0, 0, 255, 255]},
+        }))
+        .unwrap();
+        let rendered = world.render(&palette, &RenderOptions::default()).unwrap();
+        assert!(rendered.errors.is_empty());
+        let image = rendered.image;
+        assert_eq!((image.width, image.height), (64, 64));
+        // Half a level of liquid over the material
+        assert_eq!(image.pixel(32, 63), [100, 50, 128, 255]);
+        // Liquid over nothing
+        assert_eq!(image.pixel(33, 63), [0, 0, 255, 128]);
+        // Never generated
+        assert_eq!(image.pixel(0, 63), [0, 0, 0, 0]);
+        // The last tile of the sector, after one with a root source, which
+        // makes tiles vary in length
+        assert_eq!(image.pixel(63, 32), [100, 50, 128, 255]);
+        assert_eq!(image.pixel(62, 32), [0, 0, 255, 128]);
+
+        // Sectors from older versions of the game, with shorter tiles
+        let path = world_file("render-old", true);
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let old = world.render(&palette, &RenderOptions::default()).unwrap();
+        assert!(old.errors.is_empty());
+        assert_eq!(old.image.pixel(0, 31), [0, 100, 128, 255]);
+        assert_eq!(old.image.pixel(63, 0), [0, 100, 128, 255]);
+
+        // A sector this can't read is drawn in the default colour and
+        // reported, without stopping the rest
+        let path = world_file("render-unknown", false);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path);
+        let mut w = BTreeDbWriter::open(file.unwrap()).unwrap();
+        w.insert(&sector_key(0, 0), &compress(&[1, 0x83, 0x23]))
+            .unwrap();
+        w.commit().unwrap();
+        let world = World::new(&File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let rendered = world.render(&palette, &RenderOptions::default()).unwrap();
+        assert_eq!(
+            rendered.errors,
+            vec![(
+                (0, 0),
+                "sector 0, 0 has unsupported tile version 419".to_string()
+            )]
+        );
+        assert_eq!(rendered.image.pixel(0, 63), palette.default_material);
+        assert_eq!(rendered.image.pixel(32, 63), [100, 50, 128, 255]);
+
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        let (info, _) = png::Decoder::new(&png[..]).read_info().unwrap();
+        assert_eq!((info.width, info.height), (64, 64));
+    }
+
+    #[test]
+    fn test_palette() {
+        let mut png = Vec::new();
+        let image = Image {
+            width: 2,
+            height: 1,
+            pixels: vec![10, 20, 30, 255, 90, 100, 110, 0],
+        };
+        image.write_png(&mut png).unwrap();
+        assert_eq!(average_color(&png).unwrap(), Some([10, 20, 30, 255]));
+        assert_eq!(
+            asset_path("/tiles/materials/dirt.material", "dirt.png?hueshift=10"),
+            "/tiles/materials/dirt.png"
+        );
+        assert!(Palette::from_json(&json!({"materials": {"x": [1, 2, 3]}})).is_err());
+    }
+}